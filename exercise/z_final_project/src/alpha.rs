@@ -0,0 +1,195 @@
+// Transparency.
+//
+// Filters that average neighboring pixels (blurring, resizing) have to weight each color by its
+// alpha, or fully transparent pixels -- whose color is invisible and usually black -- bleed into
+// the visible ones and leave dark fringes around icons.  The usual trick is to multiply the color
+// channels by alpha before filtering and divide it back out afterwards ("premultiplied alpha").
+//
+// There are also a few explicit operations: flattening onto a solid background, pulling the alpha
+// channel out as a grayscale mask, and building it back from one.
+
+use crate::{depth, gamma, parallel};
+use image::{DynamicImage, GenericImageView, Rgba, Rgba32FImage};
+
+pub fn premultiply_pixel(pixel: &mut Rgba<f32>) {
+    let a = pixel[3];
+    for c in 0..3 {
+        pixel[c] *= a;
+    }
+}
+
+pub fn unpremultiply_pixel(pixel: &mut Rgba<f32>) {
+    let a = pixel[3];
+    for c in 0..3 {
+        pixel[c] = if a > 0.0 { pixel[c] / a } else { 0.0 };
+    }
+}
+
+pub fn premultiply(img: &mut Rgba32FImage) {
+    let width = img.width() as usize;
+    parallel::for_each_pixel(img, width, premultiply_pixel);
+}
+
+pub fn unpremultiply(img: &mut Rgba32FImage) {
+    let width = img.width() as usize;
+    parallel::for_each_pixel(img, width, unpremultiply_pixel);
+}
+
+// Run a filter on the premultiplied image, and hand back the result in the color type the filter
+// produced.  Images without alpha are passed through as they are.
+pub fn premultiplied(
+    img: &DynamicImage,
+    op: impl FnOnce(&DynamicImage) -> DynamicImage,
+) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return op(img);
+    }
+    let mut input = img.to_rgba32f();
+    premultiply(&mut input);
+    let result = op(&DynamicImage::ImageRgba32F(input));
+    let color = result.color();
+    let mut output = result.into_rgba32f();
+    unpremultiply(&mut output);
+    depth::convert(DynamicImage::ImageRgba32F(output), color)
+}
+
+// Composite the image over a solid color and drop the alpha channel.
+pub fn flatten(img: &DynamicImage, background: Rgba<u8>, linear: bool) -> DynamicImage {
+    let color = depth::color_type(
+        depth::bits(img.color()),
+        img.color().has_color() || background[0] != background[1] || background[1] != background[2],
+        false,
+    );
+    if !img.color().has_alpha() {
+        return depth::convert(img.clone(), color);
+    }
+    let mut back = Rgba(background.0.map(|c| c as f32 / 255.0));
+    if linear {
+        back = gamma::decode_pixel(back);
+    }
+    let mut result = img.to_rgba32f();
+    for pixel in result.pixels_mut() {
+        let mut p = if linear {
+            gamma::decode_pixel(*pixel)
+        } else {
+            *pixel
+        };
+        let a = p[3].clamp(0.0, 1.0);
+        for c in 0..3 {
+            p[c] = p[c] * a + back[c] * (1.0 - a);
+        }
+        p[3] = 1.0;
+        *pixel = if linear { gamma::encode_pixel(p) } else { p };
+    }
+    depth::convert(DynamicImage::ImageRgba32F(result), color)
+}
+
+// The alpha channel as a grayscale image (white is opaque).  Images without alpha give all white.
+pub fn extract_alpha(img: &DynamicImage) -> DynamicImage {
+    let alpha = img.to_rgba32f();
+    let mask = Rgba32FImage::from_fn(img.width(), img.height(), |x, y| {
+        let a = alpha.get_pixel(x, y)[3];
+        Rgba([a, a, a, 1.0])
+    });
+    // Gray floats don't exist, so a float alpha channel comes out as 16 bits.
+    let bits = depth::bits(img.color()).min(16);
+    depth::convert(
+        DynamicImage::ImageRgba32F(mask),
+        depth::color_type(bits, false, false),
+    )
+}
+
+// Replace the alpha channel with the brightness of `mask`, which must be the same size.
+pub fn set_alpha(img: &DynamicImage, mask: &DynamicImage) -> Result<DynamicImage, String> {
+    if img.dimensions() != mask.dimensions() {
+        return Err(format!(
+            "The mask is {}x{} but the image is {}x{}",
+            mask.width(),
+            mask.height(),
+            img.width(),
+            img.height()
+        ));
+    }
+    let gray = mask.to_luma32f();
+    let mut result = img.to_rgba32f();
+    for (x, y, pixel) in result.enumerate_pixels_mut() {
+        pixel[3] = gray.get_pixel(x, y)[0].clamp(0.0, 1.0);
+    }
+    let bits = depth::bits(img.color()).max(depth::bits(mask.color()));
+    let color = depth::color_type(bits, img.color().has_color(), true);
+    Ok(depth::convert(DynamicImage::ImageRgba32F(result), color))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayAlphaImage, GrayImage, Luma, LumaA, RgbaImage};
+
+    #[test]
+    fn premultiplying_round_trips() {
+        let original = Rgba([0.8, 0.4, 0.2, 0.5]);
+        let mut pixel = original;
+        premultiply_pixel(&mut pixel);
+        assert_eq!(pixel, Rgba([0.4, 0.2, 0.1, 0.5]));
+        unpremultiply_pixel(&mut pixel);
+        assert_eq!(pixel, original);
+
+        // Fully transparent colors come back black instead of dividing by zero
+        let mut pixel = Rgba([0.3, 0.6, 0.9, 0.0]);
+        premultiply_pixel(&mut pixel);
+        unpremultiply_pixel(&mut pixel);
+        assert_eq!(pixel, Rgba([0.0, 0.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn transparent_pixels_dont_bleed() {
+        // An opaque red pixel next to a transparent black one: averaging them should stay red.
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        }));
+        let averaged = premultiplied(&img, |img| {
+            let img = img.to_rgba32f();
+            let (a, b) = (img.get_pixel(0, 0), img.get_pixel(1, 0));
+            let mean = Rgba([0, 1, 2, 3].map(|c| (a[c] + b[c]) / 2.0));
+            DynamicImage::ImageRgba32F(Rgba32FImage::from_pixel(1, 1, mean))
+        });
+        assert_eq!(averaged.to_rgba8().get_pixel(0, 0), &Rgba([255, 0, 0, 128]));
+    }
+
+    #[test]
+    fn flatten_blends_onto_the_background() {
+        let img = DynamicImage::ImageLumaA8(GrayAlphaImage::from_pixel(1, 1, LumaA([255, 128])));
+        let flat = flatten(&img, Rgba([0, 0, 0, 255]), false);
+        assert_eq!(flat.color(), image::ColorType::L8);
+        assert_eq!(flat.to_luma8().get_pixel(0, 0), &Luma([128]));
+
+        // Half-covered white over black is 50% light, which is 188 in sRGB
+        let flat = flatten(&img, Rgba([0, 0, 0, 255]), true);
+        assert_eq!(flat.to_luma8().get_pixel(0, 0), &Luma([188]));
+
+        // A colored background makes a gray image colored
+        let flat = flatten(&img, Rgba([255, 0, 0, 255]), false);
+        assert_eq!(flat.color(), image::ColorType::Rgb8);
+        assert_eq!(flat.to_rgb8().get_pixel(0, 0).0, [255, 128, 128]);
+    }
+
+    #[test]
+    fn alpha_comes_out_and_goes_back_in() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(3, 1, |x, _| {
+            Rgba([10, 20, 30, x as u8 * 100])
+        }));
+        let mask = extract_alpha(&img);
+        assert_eq!(mask.color(), image::ColorType::L8);
+        assert_eq!(mask.to_luma8().into_raw(), vec![0, 100, 200]);
+
+        let opaque = DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 1, Rgba([10, 20, 30, 255])));
+        assert_eq!(set_alpha(&opaque, &mask).unwrap(), img);
+
+        let small = DynamicImage::ImageLuma8(GrayImage::new(2, 1));
+        assert!(set_alpha(&opaque, &small).is_err());
+    }
+}
@@ -0,0 +1,536 @@
+// Finding edges, straight lines and circles.
+//
+// Canny edge detection (Canny, 1986):
+// 1. Blur the brightness with a Gaussian of `sigma`, so noise doesn't show up as edges.
+// 2. Take the gradient with the Sobel operator.
+// 3. Keep only pixels whose gradient is the strongest across the edge (non-maximum suppression),
+//    which thins edges to one pixel.
+// 4. Hysteresis: pixels above the high threshold are edges, and so are pixels above the low
+//    threshold that connect to them.  Thresholds are fractions of the strongest gradient in the
+//    image, so the defaults work whatever the contrast.
+//
+// Hough transforms find shapes from the edge pixels by voting:
+// - Lines: every edge pixel votes for each line through it, written as
+//   x cos(theta) + y sin(theta) = rho with theta in whole degrees.  Lines with many votes that beat
+//   their neighbors win.
+// - Circles: for each radius, every edge pixel votes for the two centers that lie that far along
+//   its gradient direction (an edge on a circle points at the center).  A circle's score is its
+//   votes over its circumference, so big and small circles compare fairly.
+
+use crate::blur;
+use crate::draw::{self, Command, Shape, Style};
+use image::{DynamicImage, Rgba};
+use serde_json::{json, Value};
+use std::f32::consts::PI;
+
+pub struct CannyOptions {
+    pub sigma: f32,
+    // Fractions of the strongest gradient
+    pub low: f32,
+    pub high: f32,
+}
+
+impl Default for CannyOptions {
+    fn default() -> Self {
+        CannyOptions {
+            sigma: 1.4,
+            low: 0.1,
+            high: 0.2,
+        }
+    }
+}
+
+pub struct Edges {
+    pub width: usize,
+    pub height: usize,
+    pub edge: Vec<bool>,
+    // Gradient direction at each pixel, in radians
+    pub direction: Vec<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub rho: f32,
+    // In degrees, 0 to 179
+    pub theta: u32,
+    pub votes: u32,
+    // Where the line enters and leaves the image
+    pub from: (f32, f32),
+    pub to: (f32, f32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Circle {
+    pub x: u32,
+    pub y: u32,
+    pub radius: u32,
+    pub votes: u32,
+    // Votes over circumference
+    pub score: f32,
+}
+
+pub fn canny(img: &DynamicImage, options: &CannyOptions) -> Edges {
+    let gray = img.to_luma32f();
+    let (width, height) = (gray.width() as usize, gray.height() as usize);
+    let smooth = blur_gray(gray.as_raw(), width, height, options.sigma);
+    let at = |x: isize, y: isize| {
+        let x = x.clamp(0, width as isize - 1) as usize;
+        let y = y.clamp(0, height as isize - 1) as usize;
+        smooth[y * width + x]
+    };
+
+    let mut magnitude = vec![0.0f32; width * height];
+    let mut direction = vec![0.0f32; width * height];
+    for y in 0..height as isize {
+        for x in 0..width as isize {
+            let gx = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x - 1, y)
+                - at(x - 1, y + 1);
+            let gy = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x, y - 1)
+                - at(x + 1, y - 1);
+            let i = y as usize * width + x as usize;
+            magnitude[i] = gx.hypot(gy);
+            direction[i] = gy.atan2(gx);
+        }
+    }
+
+    // Thin the edges: compare each pixel with its two neighbors across the edge.
+    let mut thin = vec![0.0f32; width * height];
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let i = y * width + x;
+            let angle = direction[i].to_degrees().rem_euclid(180.0);
+            let (dx, dy): (isize, isize) = if !(22.5..157.5).contains(&angle) {
+                (1, 0)
+            } else if angle < 67.5 {
+                (1, 1)
+            } else if angle < 112.5 {
+                (0, 1)
+            } else {
+                (-1, 1)
+            };
+            let neighbor = |sign: isize| {
+                let nx = (x as isize + sign * dx) as usize;
+                let ny = (y as isize + sign * dy) as usize;
+                magnitude[ny * width + nx]
+            };
+            if magnitude[i] >= neighbor(1) && magnitude[i] > neighbor(-1) {
+                thin[i] = magnitude[i];
+            }
+        }
+    }
+
+    let strongest = thin.iter().cloned().fold(0.0, f32::max);
+    let (low, high) = (options.low * strongest, options.high * strongest);
+    let mut edge = vec![false; width * height];
+    let mut stack: Vec<usize> = (0..thin.len())
+        .filter(|&i| strongest > 0.0 && thin[i] >= high)
+        .collect();
+    while let Some(i) = stack.pop() {
+        if edge[i] {
+            continue;
+        }
+        edge[i] = true;
+        let (x, y) = ((i % width) as isize, (i / width) as isize);
+        for (dx, dy) in NEIGHBORS {
+            let (nx, ny) = (x + dx, y + dy);
+            if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                continue;
+            }
+            let n = ny as usize * width + nx as usize;
+            if !edge[n] && thin[n] >= low && thin[n] > 0.0 {
+                stack.push(n);
+            }
+        }
+    }
+
+    Edges {
+        width,
+        height,
+        edge,
+        direction,
+    }
+}
+
+const NEIGHBORS: [(isize, isize); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+// Separable Gaussian blur of one channel, with the edges repeated.
+fn blur_gray(data: &[f32], width: usize, height: usize, sigma: f32) -> Vec<f32> {
+    if sigma <= 0.0 {
+        return data.to_vec();
+    }
+    let kernel = blur::kernel(sigma);
+    let radius = (kernel.len() / 2) as isize;
+    let pass = |source: &[f32], step: (usize, usize)| {
+        let mut out = vec![0.0; source.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = 0.0;
+                for (k, weight) in kernel.iter().enumerate() {
+                    let d = k as isize - radius;
+                    let sx = (x as isize + d * step.0 as isize).clamp(0, width as isize - 1);
+                    let sy = (y as isize + d * step.1 as isize).clamp(0, height as isize - 1);
+                    sum += weight * source[sy as usize * width + sx as usize];
+                }
+                out[y * width + x] = sum;
+            }
+        }
+        out
+    };
+    pass(&pass(data, (1, 0)), (0, 1))
+}
+
+// The edges as a white-on-black image.
+pub fn edge_image(edges: &Edges) -> DynamicImage {
+    let pixels = edges
+        .edge
+        .iter()
+        .map(|&e| if e { 255 } else { 0 })
+        .collect();
+    let img = image::GrayImage::from_raw(edges.width as u32, edges.height as u32, pixels);
+    DynamicImage::ImageLuma8(img.unwrap())
+}
+
+// Lines with at least `min_votes` votes, the strongest first.
+pub fn hough_lines(edges: &Edges, min_votes: u32, max_lines: usize) -> Vec<Line> {
+    let (width, height) = (edges.width as f32, edges.height as f32);
+    let diagonal = width.hypot(height).ceil() as i64;
+    let rhos = (2 * diagonal + 1) as usize;
+    let trig: Vec<(f32, f32)> = (0..180)
+        .map(|t| ((t as f32).to_radians().cos(), (t as f32).to_radians().sin()))
+        .collect();
+
+    let mut votes = vec![0u32; 180 * rhos];
+    for (i, _) in edges.edge.iter().enumerate().filter(|(_, &e)| e) {
+        let (x, y) = ((i % edges.width) as f32, (i / edges.width) as f32);
+        for (t, (cos, sin)) in trig.iter().enumerate() {
+            let rho = (x * cos + y * sin).round() as i64 + diagonal;
+            votes[t * rhos + rho as usize] += 1;
+        }
+    }
+
+    // Local maxima, so that one thick line doesn't come out as several.  Theta wraps around, with
+    // rho changing sign.
+    let mut lines = Vec::new();
+    for t in 0..180i64 {
+        for r in 0..rhos as i64 {
+            let v = votes[t as usize * rhos + r as usize];
+            if v < min_votes.max(1) {
+                continue;
+            }
+            let beaten = (-3..=3).any(|dt: i64| {
+                (-3..=3).any(|dr: i64| {
+                    let (mut nt, mut nr) = (t + dt, r + dr);
+                    if !(0..180).contains(&nt) {
+                        nt = nt.rem_euclid(180);
+                        nr = 2 * diagonal - nr;
+                    }
+                    if (dt, dr) == (0, 0) || nr < 0 || nr >= rhos as i64 {
+                        return false;
+                    }
+                    let n = votes[nt as usize * rhos + nr as usize];
+                    // Ties go to the first one.
+                    n > v || (n == v && (nt, nr) < (t, r))
+                })
+            });
+            if !beaten {
+                let rho = (r - diagonal) as f32;
+                let (from, to) = clip_line(rho, t as u32, width, height);
+                lines.push(Line {
+                    rho,
+                    theta: t as u32,
+                    votes: v,
+                    from,
+                    to,
+                });
+            }
+        }
+    }
+    lines.sort_by_key(|line| std::cmp::Reverse(line.votes));
+    lines.truncate(max_lines);
+    lines
+}
+
+// The ends of x cos(theta) + y sin(theta) = rho inside the image.
+fn clip_line(rho: f32, theta: u32, width: f32, height: f32) -> ((f32, f32), (f32, f32)) {
+    let (sin, cos) = (theta as f32).to_radians().sin_cos();
+    let (right, bottom) = (width - 1.0, height - 1.0);
+    let mut points = Vec::new();
+    if sin.abs() > 1e-6 {
+        // Crossing the left and right edges
+        for x in [0.0, right] {
+            let y = (rho - x * cos) / sin;
+            if (0.0..=bottom).contains(&y) {
+                points.push((x, y));
+            }
+        }
+    }
+    if cos.abs() > 1e-6 {
+        // Crossing the top and bottom edges
+        for y in [0.0, bottom] {
+            let x = (rho - y * sin) / cos;
+            if (0.0..=right).contains(&x) {
+                points.push((x, y));
+            }
+        }
+    }
+    let round = |(x, y): (f32, f32)| ((x * 10.0).round() / 10.0, (y * 10.0).round() / 10.0);
+    match points.as_slice() {
+        [first, .., last] => (round(*first), round(*last)),
+        [only] => (round(*only), round(*only)),
+        [] => ((0.0, 0.0), (0.0, 0.0)),
+    }
+}
+
+// Circles with radii from `min_radius` to `max_radius` that score at least `min_score`, the
+// best first.  Circles closer together than `min_radius` are taken to be the same one.
+pub fn hough_circles(
+    edges: &Edges,
+    (min_radius, max_radius): (u32, u32),
+    min_score: f32,
+    max_circles: usize,
+) -> Vec<Circle> {
+    let (width, height) = (edges.width, edges.height);
+    let points: Vec<(f32, f32, f32, f32)> = (0..edges.edge.len())
+        .filter(|&i| edges.edge[i])
+        .map(|i| {
+            let (sin, cos) = edges.direction[i].sin_cos();
+            ((i % width) as f32, (i / width) as f32, cos, sin)
+        })
+        .collect();
+
+    let mut candidates = Vec::new();
+    let mut votes = vec![0u32; width * height];
+    for radius in min_radius.max(1)..=max_radius {
+        votes.fill(0);
+        let r = radius as f32;
+        for &(x, y, cos, sin) in &points {
+            for sign in [-1.0, 1.0] {
+                let cx = (x + sign * r * cos).round();
+                let cy = (y + sign * r * sin).round();
+                if cx >= 0.0 && cy >= 0.0 && (cx as usize) < width && (cy as usize) < height {
+                    votes[cy as usize * width + cx as usize] += 1;
+                }
+            }
+        }
+        // Gradient directions are a little off, so count the votes around each center.
+        let circumference = 2.0 * PI * r;
+        for cy in 1..height.saturating_sub(1) {
+            for cx in 1..width.saturating_sub(1) {
+                let mut total = 0;
+                for dy in 0..3 {
+                    let row = (cy + dy - 1) * width + cx;
+                    total += votes[row - 1] + votes[row] + votes[row + 1];
+                }
+                let score = total as f32 / circumference;
+                if score >= min_score {
+                    candidates.push(Circle {
+                        x: cx as u32,
+                        y: cy as u32,
+                        radius,
+                        votes: total,
+                        score,
+                    });
+                }
+            }
+        }
+    }
+
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let mut circles: Vec<Circle> = Vec::new();
+    let apart = min_radius.max(1) as f32;
+    for candidate in candidates {
+        let close = circles.iter().any(|c| {
+            (c.x as f32 - candidate.x as f32).hypot(c.y as f32 - candidate.y as f32) < apart
+        });
+        if !close {
+            circles.push(candidate);
+            if circles.len() == max_circles {
+                break;
+            }
+        }
+    }
+    circles
+}
+
+pub fn lines_json(lines: &[Line]) -> Value {
+    Value::Array(
+        lines
+            .iter()
+            .map(|l| {
+                json!({
+                    "rho": l.rho,
+                    "theta": l.theta,
+                    "votes": l.votes,
+                    "x1": l.from.0,
+                    "y1": l.from.1,
+                    "x2": l.to.0,
+                    "y2": l.to.1,
+                })
+            })
+            .collect(),
+    )
+}
+
+pub fn circles_json(circles: &[Circle]) -> Value {
+    Value::Array(
+        circles
+            .iter()
+            .map(|c| {
+                json!({
+                    "x": c.x,
+                    "y": c.y,
+                    "radius": c.radius,
+                    "votes": c.votes,
+                    "score": (c.score * 1000.0).round() / 1000.0,
+                })
+            })
+            .collect(),
+    )
+}
+
+// The image with the lines and circles drawn on it.
+pub fn draw_shapes(
+    img: &DynamicImage,
+    lines: &[Line],
+    circles: &[Circle],
+    color: Rgba<u8>,
+    linear: bool,
+) -> DynamicImage {
+    let style = Style {
+        fill: None,
+        stroke: Some(color),
+        width: 2.0,
+    };
+    let lines = lines.iter().map(|l| Shape::Line {
+        from: l.from,
+        to: l.to,
+    });
+    let circles = circles.iter().map(|c| Shape::Ellipse {
+        center: (c.x as f32, c.y as f32),
+        radii: (c.radius as f32, c.radius as f32),
+    });
+    let commands: Vec<Command> = lines
+        .chain(circles)
+        .map(|shape| Command {
+            shape,
+            style: style.clone(),
+        })
+        .collect();
+    draw::draw(img, &commands, linear)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    fn edges_at(
+        width: usize,
+        height: usize,
+        points: impl Iterator<Item = (usize, usize)>,
+    ) -> Edges {
+        let mut edge = vec![false; width * height];
+        for (x, y) in points {
+            edge[y * width + x] = true;
+        }
+        Edges {
+            width,
+            height,
+            edge,
+            direction: vec![0.0; width * height],
+        }
+    }
+
+    #[test]
+    fn step_gives_a_thin_edge() {
+        let img = GrayImage::from_fn(32, 16, |x, _| Luma([if x < 16 { 20 } else { 220 }]));
+        let edges = canny(&DynamicImage::ImageLuma8(img), &CannyOptions::default());
+        for y in 2..14 {
+            let row: Vec<usize> = (0..32).filter(|&x| edges.edge[y * 32 + x]).collect();
+            assert_eq!(row.len(), 1, "row {}: {:?}", y, row);
+            assert!((15..=16).contains(&row[0]), "row {}: {:?}", y, row);
+            // Across the step, from dark to bright
+            assert!(edges.direction[y * 32 + row[0]].abs() < 0.1);
+        }
+        let img = edge_image(&edges);
+        assert_eq!((img.width(), img.height()), (32, 16));
+    }
+
+    #[test]
+    fn finds_a_line() {
+        // x + y = 50, which is 50 / sqrt(2) = 35.4 from the corner at 45 degrees
+        let edges = edges_at(64, 64, (0..=50).map(|x| (x, 50 - x)));
+        let lines = hough_lines(&edges, 20, 5);
+        assert_eq!(lines.len(), 1);
+        let line = &lines[0];
+        assert_eq!((line.theta, line.rho, line.votes), (45, 35.0, 51));
+        // Its ends are on the edges of the image, close to where the pixels start and stop
+        for (end, expected) in [(line.from, (0.0, 49.5)), (line.to, (49.5, 0.0))] {
+            assert!((end.0 - expected.0).abs() < 0.1 && (end.1 - expected.1).abs() < 0.1);
+        }
+
+        // A vertical line has theta 0, and too few votes find nothing
+        let edges = edges_at(40, 30, (0..30).map(|y| (12, y)));
+        let lines = hough_lines(&edges, 20, 5);
+        assert_eq!((lines[0].theta, lines[0].rho), (0, 12.0));
+        assert!(hough_lines(&edges, 31, 5).is_empty());
+    }
+
+    #[test]
+    fn finds_a_circle() {
+        let img = GrayImage::from_fn(64, 64, |x, y| {
+            let inside = (x as f32 - 30.0).hypot(y as f32 - 34.0) <= 12.0;
+            Luma([if inside { 230 } else { 30 }])
+        });
+        let edges = canny(&DynamicImage::ImageLuma8(img), &CannyOptions::default());
+        let circles = hough_circles(&edges, (6, 20), 0.5, 3);
+        let best = &circles[0];
+        assert!(
+            best.x.abs_diff(30) <= 1 && best.y.abs_diff(34) <= 1,
+            "{:?}",
+            best
+        );
+        assert!(best.radius.abs_diff(12) <= 1, "{:?}", best);
+    }
+
+    #[test]
+    fn json_shapes() {
+        let line = Line {
+            rho: 12.0,
+            theta: 0,
+            votes: 30,
+            from: (12.0, 0.0),
+            to: (12.0, 29.0),
+        };
+        assert_eq!(
+            lines_json(&[line]),
+            json!([{"rho": 12.0, "theta": 0, "votes": 30,
+                    "x1": 12.0, "y1": 0.0, "x2": 12.0, "y2": 29.0}])
+        );
+        let circle = Circle {
+            x: 30,
+            y: 34,
+            radius: 12,
+            votes: 70,
+            score: 0.928_47,
+        };
+        assert_eq!(
+            circles_json(&[circle]),
+            json!([{"x": 30, "y": 34, "radius": 12, "votes": 70, "score": 0.928_f32}])
+        );
+        assert_eq!(lines_json(&[]), json!([]));
+    }
+}
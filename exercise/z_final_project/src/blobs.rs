@@ -0,0 +1,341 @@
+// Finding and measuring blobs: the connected areas of a thresholded image, such as particles or
+// cells in a microscope image.
+//
+// Pixels at or above the threshold brightness are foreground (or below it, for dark objects on a
+// light background).  Foreground pixels that touch belong to the same blob: sideways only with
+// 4-connectivity, or diagonally too with 8-connectivity.  Blobs are numbered from 1 in the order
+// their first pixel comes in reading order (top to bottom, left to right).
+//
+// For each blob:
+// - area: its number of pixels.
+// - bounding box: the smallest rectangle holding it.
+// - centroid: the mean position of its pixel centers, so a single pixel at 0,0 is at 0.5,0.5.
+// - perimeter: the length of its outline along pixel edges, counting holes.  This runs about 27%
+//   long on diagonal and round outlines, as the staircase is measured rather than the curve.
+
+use crate::morphology;
+use image::{DynamicImage, Rgb, RgbImage};
+use serde_json::{json, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Connectivity {
+    Four,
+    Eight,
+}
+
+pub struct BlobOptions {
+    // In 8-bit levels
+    pub threshold: u8,
+    // Objects are darker than the threshold instead of brighter
+    pub dark: bool,
+    pub connectivity: Connectivity,
+    // Smaller blobs are left out, as noise
+    pub min_area: u64,
+}
+
+impl Default for BlobOptions {
+    fn default() -> Self {
+        BlobOptions {
+            threshold: 128,
+            dark: false,
+            connectivity: Connectivity::Eight,
+            min_area: 1,
+        }
+    }
+}
+
+pub struct Blob {
+    pub label: u32,
+    pub area: u64,
+    // x, y, width, height
+    pub bounds: (u32, u32, u32, u32),
+    pub centroid: (f64, f64),
+    pub perimeter: u64,
+}
+
+pub struct Labels {
+    pub width: u32,
+    pub height: u32,
+    // The blob each pixel belongs to, or 0 for the background
+    pub labels: Vec<u32>,
+    pub blobs: Vec<Blob>,
+}
+
+pub fn find_blobs(img: &DynamicImage, options: &BlobOptions) -> Labels {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let binary = morphology::threshold(img, options.threshold).into_luma8();
+    let foreground: Vec<bool> = binary
+        .pixels()
+        .map(|p| (p[0] > 0) != options.dark)
+        .collect();
+
+    let neighbors: &[(isize, isize)] = match options.connectivity {
+        Connectivity::Four => &[(0, -1), (-1, 0), (1, 0), (0, 1)],
+        Connectivity::Eight => &[
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (-1, 0),
+            (1, 0),
+            (-1, 1),
+            (0, 1),
+            (1, 1),
+        ],
+    };
+    let mut labels = vec![0u32; width * height];
+    let mut blobs = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..labels.len() {
+        if !foreground[start] || labels[start] != 0 {
+            continue;
+        }
+        // Flood the blob from its first pixel, noting the pixels as they're labeled.
+        let label = blobs.len() as u32 + 1;
+        let mut pixels = Vec::new();
+        labels[start] = label;
+        stack.push(start);
+        while let Some(i) = stack.pop() {
+            pixels.push(i);
+            let (x, y) = ((i % width) as isize, (i / width) as isize);
+            for &(dx, dy) in neighbors {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                    continue;
+                }
+                let n = ny as usize * width + nx as usize;
+                if foreground[n] && labels[n] == 0 {
+                    labels[n] = label;
+                    stack.push(n);
+                }
+            }
+        }
+        blobs.push(measure(&pixels, &labels, label, width, height));
+    }
+
+    // Drop the small ones, and number the rest without gaps.
+    let mut renumber = vec![0u32; blobs.len() + 1];
+    blobs.retain(|blob| blob.area >= options.min_area);
+    for (i, blob) in blobs.iter_mut().enumerate() {
+        renumber[blob.label as usize] = i as u32 + 1;
+        blob.label = i as u32 + 1;
+    }
+    for label in labels.iter_mut() {
+        *label = renumber[*label as usize];
+    }
+    Labels {
+        width: width as u32,
+        height: height as u32,
+        labels,
+        blobs,
+    }
+}
+
+fn measure(pixels: &[usize], labels: &[u32], label: u32, width: usize, height: usize) -> Blob {
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+    let (mut sum_x, mut sum_y) = (0.0, 0.0);
+    let mut perimeter = 0;
+    for &i in pixels {
+        let (x, y) = (i % width, i / width);
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+        sum_x += x as f64 + 0.5;
+        sum_y += y as f64 + 0.5;
+        // Each side that faces something else is part of the outline.
+        let outside = |nx: usize, ny: usize| labels[ny * width + nx] != label;
+        perimeter += [
+            x == 0 || outside(x - 1, y),
+            x + 1 == width || outside(x + 1, y),
+            y == 0 || outside(x, y - 1),
+            y + 1 == height || outside(x, y + 1),
+        ]
+        .iter()
+        .filter(|&&side| side)
+        .count() as u64;
+    }
+    let area = pixels.len() as u64;
+    Blob {
+        label,
+        area,
+        bounds: (
+            min_x as u32,
+            min_y as u32,
+            (max_x - min_x + 1) as u32,
+            (max_y - min_y + 1) as u32,
+        ),
+        centroid: (sum_x / area as f64, sum_y / area as f64),
+        perimeter,
+    }
+}
+
+pub fn blobs_json(blobs: &[Blob]) -> Value {
+    json!({
+        "count": blobs.len(),
+        "blobs": blobs
+            .iter()
+            .map(|b| {
+                json!({
+                    "label": b.label,
+                    "area": b.area,
+                    "x": b.bounds.0,
+                    "y": b.bounds.1,
+                    "width": b.bounds.2,
+                    "height": b.bounds.3,
+                    "centroid_x": b.centroid.0,
+                    "centroid_y": b.centroid.1,
+                    "perimeter": b.perimeter,
+                })
+            })
+            .collect::<Vec<Value>>(),
+    })
+}
+
+pub fn blobs_csv(blobs: &[Blob]) -> String {
+    let mut csv = "label,area,x,y,width,height,centroid_x,centroid_y,perimeter\n".to_string();
+    for b in blobs {
+        let (x, y, width, height) = b.bounds;
+        csv += &format!(
+            "{},{},{},{},{},{},{:.3},{:.3},{}\n",
+            b.label, b.area, x, y, width, height, b.centroid.0, b.centroid.1, b.perimeter
+        );
+    }
+    csv
+}
+
+// The blobs in different colors on black, so that blobs close together can be told apart.
+pub fn label_image(labels: &Labels) -> DynamicImage {
+    let colors: Vec<Rgb<u8>> = (0..=labels.blobs.len() as u32).map(label_color).collect();
+    let img = RgbImage::from_fn(labels.width, labels.height, |x, y| {
+        colors[labels.labels[(y * labels.width + x) as usize] as usize]
+    });
+    DynamicImage::ImageRgb8(img)
+}
+
+// Black for the background, and bright colors around the color wheel for blobs, stepping by the
+// golden angle so that neighboring labels get very different hues.
+fn label_color(label: u32) -> Rgb<u8> {
+    if label == 0 {
+        return Rgb([0, 0, 0]);
+    }
+    let hue = (label as f64 * 137.507_764) % 360.0 / 60.0;
+    // Alternate the brightness too, for when hues come round close together.
+    let value = if label.is_multiple_of(2) { 1.0 } else { 0.75 };
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    let (r, g, b) = match hue as u32 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    let level = |c: f64| (c * value * 255.0).round() as u8;
+    Rgb([level(r), level(g), level(b)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    // '#' is white, anything else black
+    fn picture(rows: &[&str]) -> DynamicImage {
+        let (width, height) = (rows[0].len() as u32, rows.len() as u32);
+        DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| {
+            Luma([if rows[y as usize].as_bytes()[x as usize] == b'#' {
+                255
+            } else {
+                0
+            }])
+        }))
+    }
+
+    fn options(connectivity: Connectivity) -> BlobOptions {
+        BlobOptions {
+            connectivity,
+            ..BlobOptions::default()
+        }
+    }
+
+    const SHAPES: [&str; 4] = ["##...", "##..#", "..#..", "#..##"];
+
+    #[test]
+    fn connectivity_decides_what_touches() {
+        let img = picture(&SHAPES);
+        let eight = find_blobs(&img, &options(Connectivity::Eight));
+        assert_eq!(
+            eight.labels,
+            vec![1, 1, 0, 0, 0, 1, 1, 0, 0, 2, 0, 0, 1, 0, 0, 3, 0, 0, 1, 1]
+        );
+        let four = find_blobs(&img, &options(Connectivity::Four));
+        assert_eq!(
+            four.labels,
+            vec![1, 1, 0, 0, 0, 1, 1, 0, 0, 2, 0, 0, 3, 0, 0, 4, 0, 0, 5, 5]
+        );
+        let areas: Vec<u64> = four.blobs.iter().map(|blob| blob.area).collect();
+        assert_eq!(areas, vec![4, 1, 1, 1, 2]);
+    }
+
+    #[test]
+    fn small_blobs_are_dropped_and_the_rest_renumbered() {
+        let img = picture(&SHAPES);
+        let labels = find_blobs(
+            &img,
+            &BlobOptions {
+                min_area: 2,
+                ..options(Connectivity::Four)
+            },
+        );
+        assert_eq!(
+            labels.labels,
+            vec![1, 1, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 2]
+        );
+        let found: Vec<u32> = labels.blobs.iter().map(|blob| blob.label).collect();
+        assert_eq!(found, vec![1, 2]);
+    }
+
+    #[test]
+    fn measurements() {
+        let square = &find_blobs(&picture(&SHAPES), &options(Connectivity::Four)).blobs[0];
+        assert_eq!(square.bounds, (0, 0, 2, 2));
+        assert_eq!(square.centroid, (1.0, 1.0));
+        assert_eq!(square.perimeter, 8);
+
+        // The hole's edges count too
+        let ring = &find_blobs(&picture(&["###", "#.#", "###"]), &BlobOptions::default()).blobs;
+        assert_eq!(ring.len(), 1);
+        assert_eq!(ring[0].area, 8);
+        assert_eq!(ring[0].centroid, (1.5, 1.5));
+        assert_eq!(ring[0].perimeter, 16);
+    }
+
+    #[test]
+    fn dark_objects() {
+        let options = BlobOptions {
+            dark: true,
+            ..options(Connectivity::Four)
+        };
+        let holes = find_blobs(&picture(&["###", "#.#", "###"]), &options);
+        assert_eq!(holes.blobs.len(), 1);
+        assert_eq!(holes.blobs[0].bounds, (1, 1, 1, 1));
+        assert!(find_blobs(&picture(&["##", "##"]), &options)
+            .blobs
+            .is_empty());
+    }
+
+    #[test]
+    fn output() {
+        let labels = find_blobs(&picture(&["#.#"]), &BlobOptions::default());
+        assert_eq!(
+            blobs_csv(&labels.blobs),
+            "label,area,x,y,width,height,centroid_x,centroid_y,perimeter\n\
+             1,1,0,0,1,1,0.500,0.500,4\n\
+             2,1,2,0,1,1,2.500,0.500,4\n"
+        );
+        assert_eq!(blobs_json(&labels.blobs)["count"], 2);
+        let colors = label_image(&labels).into_rgb8();
+        assert_eq!(colors.get_pixel(1, 0), &Rgb([0, 0, 0]));
+        assert_ne!(colors.get_pixel(0, 0), colors.get_pixel(2, 0));
+    }
+}
@@ -0,0 +1,283 @@
+// Gaussian blur.
+//
+// A 2D Gaussian is the product of two 1D ones, so the blur runs as a horizontal pass followed by
+// a vertical one, each in parallel bands of rows.  Pixels past the edges repeat the edge pixels.
+// Works on RGBA floats; callers take care of premultiplying alpha and of linear light.
+//
+// The exact kernel takes time in proportion to sigma, which gets slow for big blurs.  `Box(n)`
+// instead runs n box blurs in a row, each a running sum that costs the same per pixel however wide
+// the box is.  Repeated box blurs quickly approach a Gaussian (the central limit theorem), and the
+// box widths are picked so that the overall spread matches sigma as closely as odd widths allow.
+// Measured against the exact blur across a hard black/white edge (the worst case; photos come out
+// much closer), for whole sigmas from 4 to 100, the largest difference in any channel and the
+// average within 3 sigma of the edge are at most these fractions of full scale:
+//
+//     passes    1      2      3      4      5      6
+//     largest   6.5%   2.3%   1.1%   1.1%   0.9%   0.9%
+//     average   2.9%   1.1%   0.5%   0.5%   0.4%   0.4%
+//
+// The exception is within 3 sigma of the image's edges, where each pass repeats the already blurred
+// edge pixels: there a big blur on an image only a few sigma across can be off by up to 18%.
+
+use crate::parallel;
+use image::Rgba32FImage;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    Gaussian,
+    // This many box blurs
+    Box(u32),
+}
+
+impl Method {
+    // The exact kernel while it's small and cheap, three box blurs past that.
+    pub fn default_for(sigma: f32) -> Method {
+        if sigma <= 3.0 {
+            Method::Gaussian
+        } else {
+            Method::Box(3)
+        }
+    }
+}
+
+pub fn blur(img: &Rgba32FImage, sigma: f32, method: Method) -> Rgba32FImage {
+    match method {
+        Method::Gaussian => gaussian(img, sigma),
+        Method::Box(passes) => box_blur(img, sigma, passes),
+    }
+}
+
+pub fn gaussian_kernel(sigma: f32, radius: usize) -> Vec<f32> {
+    let weights: Vec<f32> = (0..=2 * radius)
+        .map(|i| {
+            let d = i as f32 - radius as f32;
+            (-d * d / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let sum: f32 = weights.iter().sum();
+    weights.iter().map(|w| w / sum).collect()
+}
+
+// The kernel for `sigma`, out to three standard deviations (which covers 99.7% of the weight).
+pub fn kernel(sigma: f32) -> Vec<f32> {
+    let radius = (sigma * 3.0).ceil().max(1.0) as usize;
+    gaussian_kernel(sigma.max(f32::EPSILON), radius)
+}
+
+pub fn gaussian(img: &Rgba32FImage, sigma: f32) -> Rgba32FImage {
+    let kernel = kernel(sigma);
+    let radius = (kernel.len() / 2) as i64;
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let source = img.as_raw();
+
+    let mut horizontal = vec![0.0; source.len()];
+    parallel::for_each_row(&mut horizontal, width * 4, |y, row| {
+        blur_row(&source[y * width * 4..(y + 1) * width * 4], &kernel, row);
+    });
+
+    let mut result = vec![0.0; source.len()];
+    let last = height as i64 - 1;
+    parallel::for_each_row(&mut result, width * 4, |y, row| {
+        for (k, weight) in kernel.iter().enumerate() {
+            let sy = (y as i64 + k as i64 - radius).clamp(0, last) as usize;
+            let above = &horizontal[sy * width * 4..(sy + 1) * width * 4];
+            for (o, s) in row.iter_mut().zip(above) {
+                *o += weight * s;
+            }
+        }
+    });
+    Rgba32FImage::from_raw(img.width(), img.height(), result).unwrap()
+}
+
+// Blur one row of RGBA floats horizontally into `out`, which must start out zeroed.
+pub fn blur_row(row: &[f32], kernel: &[f32], out: &mut [f32]) {
+    let radius = (kernel.len() / 2) as i64;
+    let last = (row.len() / 4) as i64 - 1;
+    for (x, pixel) in out.chunks_exact_mut(4).enumerate() {
+        for (k, weight) in kernel.iter().enumerate() {
+            let source = (x as i64 + k as i64 - radius).clamp(0, last) as usize * 4;
+            for c in 0..4 {
+                pixel[c] += weight * row[source + c];
+            }
+        }
+    }
+}
+
+// The radii of `passes` box blurs that together spread about as much as a Gaussian of `sigma`.
+// A box of width w has variance (w² - 1) / 12, and variances add up.  Widths must be odd to keep
+// the box centered, so some boxes get the next odd width up (from Kovesi, "Fast Almost-Gaussian
+// Filtering", 2010).
+pub fn box_radii(sigma: f32, passes: u32) -> Vec<usize> {
+    let n = passes.max(1) as f64;
+    let variance = (sigma as f64).powi(2);
+    let ideal = (12.0 * variance / n + 1.0).sqrt();
+    let mut lower = ideal.floor() as i64;
+    if lower % 2 == 0 {
+        lower -= 1;
+    }
+    let lower = lower.max(1) as f64;
+    let wider = ((12.0 * variance - n * lower * lower - 4.0 * n * lower - 3.0 * n)
+        / (-4.0 * lower - 4.0))
+        .round()
+        .clamp(0.0, n) as u32;
+    (0..passes.max(1))
+        .map(|i| {
+            let width = if i < wider { lower } else { lower + 2.0 };
+            (width as usize - 1) / 2
+        })
+        .collect()
+}
+
+pub fn box_blur(img: &Rgba32FImage, sigma: f32, passes: u32) -> Rgba32FImage {
+    let radii = box_radii(sigma, passes);
+    let (width, height) = (img.width() as usize, img.height() as usize);
+
+    // The vertical passes are horizontal passes over the transposed image, which keeps every pass
+    // a simple run along contiguous memory.
+    let rows = box_rows(img.as_raw().clone(), width, &radii);
+    let columns = box_rows(transpose(&rows, width, height), height, &radii);
+    let result = transpose(&columns, height, width);
+    Rgba32FImage::from_raw(img.width(), img.height(), result).unwrap()
+}
+
+// Box blur every row of `data` with each radius in turn.
+fn box_rows(mut data: Vec<f32>, width: usize, radii: &[usize]) -> Vec<f32> {
+    parallel::for_each_row(&mut data, width * 4, |_, row| {
+        let mut scratch = vec![0.0; row.len()];
+        for &radius in radii {
+            box_row(row, radius, &mut scratch);
+            row.copy_from_slice(&scratch);
+        }
+    });
+    data
+}
+
+// Box blur one row of RGBA floats horizontally into `out`.
+pub fn box_row(row: &[f32], radius: usize, out: &mut [f32]) {
+    let pixels = row.len() / 4;
+    if pixels == 0 {
+        return;
+    }
+    let last = pixels as i64 - 1;
+    let radius = radius as i64;
+    let at = |x: i64, c: usize| row[x.clamp(0, last) as usize * 4 + c] as f64;
+    let scale = 1.0 / (2 * radius + 1) as f64;
+
+    // Sums in f64, or rounding errors would pile up along long rows.
+    let mut sums = [0.0f64; 4];
+    for (c, sum) in sums.iter_mut().enumerate() {
+        for x in -radius..=radius {
+            *sum += at(x, c);
+        }
+    }
+    for (x, pixel) in out.chunks_exact_mut(4).enumerate() {
+        let x = x as i64;
+        for c in 0..4 {
+            pixel[c] = (sums[c] * scale) as f32;
+            sums[c] += at(x + radius + 1, c) - at(x - radius, c);
+        }
+    }
+}
+
+// Swap the rows and columns of `width` × `height` RGBA pixels.
+fn transpose(data: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let mut result = vec![0.0; data.len()];
+    parallel::for_each_row(&mut result, height * 4, |x, row| {
+        for (y, pixel) in row.chunks_exact_mut(4).enumerate() {
+            let source = (y * width + x) * 4;
+            pixel.copy_from_slice(&data[source..source + 4]);
+        }
+    });
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    // Black on the left half, white on the right
+    fn edge(width: u32) -> Rgba32FImage {
+        Rgba32FImage::from_fn(width, 1, |x, _| {
+            let v = if x < width / 2 { 0.0 } else { 1.0 };
+            Rgba([v, v, v, 1.0])
+        })
+    }
+
+    // The largest and the average (within 3 sigma of the edge) difference from the exact blur
+    fn errors(img: &Rgba32FImage, sigma: f32, passes: u32) -> (f32, f32) {
+        let exact = gaussian(img, sigma);
+        let boxed = box_blur(img, sigma, passes);
+        let middle = img.width() as f32 / 2.0;
+        let (mut largest, mut sum, mut count) = (0.0f32, 0.0, 0);
+        for (x, (a, b)) in exact.pixels().zip(boxed.pixels()).enumerate() {
+            let difference = (0..4).map(|c| (a[c] - b[c]).abs()).fold(0.0, f32::max);
+            largest = largest.max(difference);
+            if (x as f32 + 0.5 - middle).abs() < 3.0 * sigma {
+                sum += difference;
+                count += 1;
+            }
+        }
+        (largest, sum / count as f32)
+    }
+
+    #[test]
+    fn box_blur_stays_within_the_documented_error() {
+        let largest = [0.065, 0.023, 0.011, 0.011, 0.009, 0.009];
+        let average = [0.029, 0.011, 0.005, 0.005, 0.004, 0.004];
+        for sigma in (4..=20).chain([25, 40, 60, 100]) {
+            let sigma = sigma as f32;
+            // Wide enough that the image's own edges are out of reach
+            let img = edge((16.0 * sigma) as u32 + 1);
+            for passes in 1..=6 {
+                let (most, mean) = errors(&img, sigma, passes);
+                let i = passes as usize - 1;
+                assert!(
+                    most <= largest[i],
+                    "sigma {} passes {}: {}",
+                    sigma,
+                    passes,
+                    most
+                );
+                assert!(
+                    mean <= average[i],
+                    "sigma {} passes {}: {}",
+                    sigma,
+                    passes,
+                    mean
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn box_blur_near_the_image_edges() {
+        for sigma in [4.0, 20.0, 60.0] {
+            for width in [sigma as u32, 3 * sigma as u32] {
+                for passes in 1..=6 {
+                    let (most, _) = errors(&edge(width), sigma, passes);
+                    assert!(most <= 0.18, "sigma {} width {}: {}", sigma, width, most);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn box_radii_match_the_variance() {
+        for sigma in [1.0f32, 4.0, 10.0, 33.3] {
+            for passes in 1..=6 {
+                let variance: f32 = box_radii(sigma, passes)
+                    .iter()
+                    .map(|&r| ((2 * r + 1).pow(2) - 1) as f32 / 12.0)
+                    .sum();
+                // Odd widths only come in steps of 2
+                assert!(
+                    (variance.sqrt() - sigma).abs() < 1.0,
+                    "{} {}",
+                    sigma,
+                    passes
+                );
+            }
+        }
+    }
+}
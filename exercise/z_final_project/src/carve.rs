@@ -0,0 +1,399 @@
+// Content-aware resizing by seam carving (Avidan and Shamir, 2007).
+//
+// A seam is a path of pixels from the top of the image to the bottom, one per row, each within one
+// column of the last.  Removing the seam that crosses the least "energy" (detail) narrows the image
+// by a pixel while leaving faces, text and edges alone; repeating that gets to any width.  Widening
+// works the other way round: find the seams that would be removed first, and duplicate them.  Only
+// up to half the width is added per round, or the same seam would be stretched over and over.
+// Heights are changed the same way on the transposed image, after the width.
+//
+// Energy functions:
+// - gradient: how much the brightness changes around each pixel (the original paper's).
+// - forward: how much brightness difference removing the pixel would bring together, which makes
+//   fewer jagged artifacts (Rubinstein, Shamir and Avidan, 2008).
+//
+// A protection mask (white = keep) makes seams avoid areas such as faces; a removal mask (white =
+// remove) makes them go through an object until it's gone.
+
+use crate::depth;
+use image::DynamicImage;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Energy {
+    Gradient,
+    Forward,
+}
+
+impl Energy {
+    pub fn from_name(name: &str) -> Option<Energy> {
+        match name {
+            "gradient" => Some(Energy::Gradient),
+            "forward" => Some(Energy::Forward),
+            _ => None,
+        }
+    }
+}
+
+pub struct CarveOptions {
+    pub energy: Energy,
+    // White areas are kept
+    pub protect: Option<DynamicImage>,
+    // White areas are carved away
+    pub remove: Option<DynamicImage>,
+}
+
+// Added to the energy of masked pixels: far more than any amount of real detail.
+const PROTECT: f64 = 1e6;
+const REMOVE: f64 = -1e6;
+
+// The image being carved, as rows of pixels.
+#[derive(Clone)]
+struct Grid {
+    width: usize,
+    height: usize,
+    pixels: Vec<[f32; 4]>,
+    // Brightness, which the energy is computed from
+    luma: Vec<f32>,
+    // Extra energy from the masks
+    bias: Vec<f64>,
+    // Which column of the grid each pixel started out in, for widening
+    origin: Vec<usize>,
+}
+
+// Carve to `size` (a 0 keeps that dimension), after removing what the removal mask covers.
+pub fn carve(
+    img: &DynamicImage,
+    size: (u32, u32),
+    options: &CarveOptions,
+) -> Result<DynamicImage, String> {
+    let mut grid = Grid::new(img);
+    for (mask, bias) in [(&options.protect, PROTECT), (&options.remove, REMOVE)] {
+        if let Some(mask) = mask {
+            if mask.width() != img.width() || mask.height() != img.height() {
+                return Err(format!(
+                    "The mask is {}x{} but the image is {}x{}",
+                    mask.width(),
+                    mask.height(),
+                    img.width(),
+                    img.height()
+                ));
+            }
+            for (b, m) in grid.bias.iter_mut().zip(mask.to_luma32f().pixels()) {
+                if m[0] > 0.5 {
+                    *b += bias;
+                }
+            }
+        }
+    }
+
+    if options.remove.is_some() {
+        grid = remove_object(grid, options.energy);
+    }
+    let (width, height) = match size {
+        (0, 0) => (grid.width, grid.height),
+        (0, h) => (grid.width, h as usize),
+        (w, 0) => (w as usize, grid.height),
+        (w, h) => (w as usize, h as usize),
+    };
+    grid = resize_width(grid, width, options.energy);
+    grid = resize_width(grid.transposed(), height, options.energy).transposed();
+    Ok(depth::convert(grid.into_image(), img.color()))
+}
+
+// Take out seams through the removal mask until none of it is left: vertical seams if the object
+// is taller than it is wide, horizontal ones otherwise.
+fn remove_object(grid: Grid, energy: Energy) -> Grid {
+    let masked: Vec<(usize, usize)> = (0..grid.pixels.len())
+        .filter(|&i| grid.bias[i] < 0.0)
+        .map(|i| (i % grid.width, i / grid.width))
+        .collect();
+    let extent = |coordinate: fn(&(usize, usize)) -> usize| {
+        let values = masked.iter().map(coordinate);
+        values.clone().max().unwrap_or(0) - values.min().unwrap_or(0)
+    };
+    let vertical = extent(|p| p.0) <= extent(|p| p.1);
+
+    let mut grid = if vertical { grid } else { grid.transposed() };
+    while grid.width > 1 && grid.bias.iter().any(|&b| b < 0.0) {
+        let seam = grid.find_seam(energy);
+        grid.remove_seam(&seam);
+    }
+    if vertical {
+        grid
+    } else {
+        grid.transposed()
+    }
+}
+
+fn resize_width(mut grid: Grid, width: usize, energy: Energy) -> Grid {
+    let width = width.max(1);
+    while grid.width > width {
+        let seam = grid.find_seam(energy);
+        grid.remove_seam(&seam);
+    }
+    while grid.width < width {
+        let count = (width - grid.width).min(grid.width / 2).max(1);
+        // Find the seams on a copy, and note where they were in the original.
+        let mut work = grid.clone();
+        for y in 0..work.height {
+            for x in 0..work.width {
+                work.origin[y * work.width + x] = x;
+            }
+        }
+        let mut seams = vec![Vec::new(); grid.height];
+        for _ in 0..count {
+            let seam = work.find_seam(energy);
+            for (y, &x) in seam.iter().enumerate() {
+                seams[y].push(work.origin[y * work.width + x]);
+            }
+            work.remove_seam(&seam);
+        }
+        grid.insert_seams(&mut seams);
+    }
+    grid
+}
+
+impl Grid {
+    fn new(img: &DynamicImage) -> Grid {
+        let rgba = img.to_rgba32f();
+        let pixels: Vec<[f32; 4]> = rgba.pixels().map(|p| p.0).collect();
+        let count = pixels.len();
+        Grid {
+            width: img.width() as usize,
+            height: img.height() as usize,
+            luma: pixels.iter().map(luma).collect(),
+            pixels,
+            bias: vec![0.0; count],
+            origin: vec![0; count],
+        }
+    }
+
+    fn into_image(self) -> DynamicImage {
+        let data = self.pixels.into_iter().flatten().collect();
+        let img = image::Rgba32FImage::from_raw(self.width as u32, self.height as u32, data);
+        DynamicImage::ImageRgba32F(img.unwrap())
+    }
+
+    fn transposed(&self) -> Grid {
+        let (width, height) = (self.height, self.width);
+        let index = |i: usize| (i % width) * self.width + i / width;
+        Grid {
+            width,
+            height,
+            pixels: (0..self.pixels.len())
+                .map(|i| self.pixels[index(i)])
+                .collect(),
+            luma: (0..self.luma.len()).map(|i| self.luma[index(i)]).collect(),
+            bias: (0..self.bias.len()).map(|i| self.bias[index(i)]).collect(),
+            origin: (0..self.origin.len())
+                .map(|i| self.origin[index(i)])
+                .collect(),
+        }
+    }
+
+    fn luma_at(&self, x: isize, y: isize) -> f32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.luma[y * self.width + x]
+    }
+
+    // The column of the cheapest seam in each row, by dynamic programming: the cheapest way to
+    // reach each pixel is its own cost plus the cheapest way to reach one of the three above it.
+    fn find_seam(&self, energy: Energy) -> Vec<usize> {
+        let (width, height) = (self.width, self.height);
+        let mut cost = vec![0.0f64; width * height];
+        // Where each pixel's cheapest seam came from, as -1, 0 or +1 columns
+        let mut step = vec![0i8; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let (xi, yi) = (x as isize, y as isize);
+                let left = self.luma_at(xi - 1, yi);
+                let right = self.luma_at(xi + 1, yi);
+                let above = self.luma_at(xi, yi - 1);
+                let (own, extra) = match energy {
+                    Energy::Gradient => {
+                        let below = self.luma_at(xi, yi + 1);
+                        ((right - left).abs() + (below - above).abs(), [0.0; 3])
+                    }
+                    Energy::Forward => {
+                        // The new neighbors that removing this pixel (coming from the upper
+                        // left, above or upper right) would make.
+                        let up = (right - left).abs();
+                        let extra = [up + (above - left).abs(), up, up + (above - right).abs()];
+                        (0.0, extra)
+                    }
+                };
+                let own = own as f64 + self.bias[i];
+                if y == 0 {
+                    cost[i] = own + extra[1] as f64;
+                    continue;
+                }
+                let mut best = (f64::INFINITY, 0);
+                for d in -1..=1 {
+                    let from = x as isize + d;
+                    if from < 0 || from >= width as isize {
+                        continue;
+                    }
+                    let total =
+                        cost[(from as usize) + (y - 1) * width] + extra[(d + 1) as usize] as f64;
+                    if total < best.0 {
+                        best = (total, d as i8);
+                    }
+                }
+                cost[i] = own + best.0;
+                step[i] = best.1;
+            }
+        }
+
+        let last = &cost[(height - 1) * width..];
+        let mut x = (0..width)
+            .min_by(|&a, &b| last[a].total_cmp(&last[b]))
+            .unwrap();
+        let mut seam = vec![0; height];
+        for y in (0..height).rev() {
+            seam[y] = x;
+            x = (x as isize + step[y * width + x] as isize) as usize;
+        }
+        seam
+    }
+
+    fn remove_seam(&mut self, seam: &[usize]) {
+        let width = self.width;
+        let keep = |i: &usize| i % width != seam[i / width];
+        let indexes: Vec<usize> = (0..self.pixels.len()).filter(keep).collect();
+        self.pixels = indexes.iter().map(|&i| self.pixels[i]).collect();
+        self.luma = indexes.iter().map(|&i| self.luma[i]).collect();
+        self.bias = indexes.iter().map(|&i| self.bias[i]).collect();
+        self.origin = indexes.iter().map(|&i| self.origin[i]).collect();
+        self.width -= 1;
+    }
+
+    // Duplicate the given columns of each row, as the average of the pixel and its right neighbor.
+    fn insert_seams(&mut self, seams: &mut [Vec<usize>]) {
+        let width = self.width + seams[0].len();
+        let mut pixels = Vec::with_capacity(width * self.height);
+        let mut bias = Vec::with_capacity(width * self.height);
+        for (y, seam) in seams.iter_mut().enumerate() {
+            seam.sort_unstable();
+            let row = y * self.width;
+            let mut next = seam.iter().peekable();
+            for x in 0..self.width {
+                pixels.push(self.pixels[row + x]);
+                bias.push(self.bias[row + x]);
+                while next.next_if(|&&s| s == x).is_some() {
+                    let (a, b) = (
+                        self.pixels[row + x],
+                        self.pixels[row + (x + 1).min(self.width - 1)],
+                    );
+                    pixels.push([0, 1, 2, 3].map(|c| (a[c] + b[c]) / 2.0));
+                    bias.push(self.bias[row + x]);
+                }
+            }
+        }
+        self.width = width;
+        self.luma = pixels.iter().map(luma).collect();
+        self.origin = vec![0; pixels.len()];
+        self.pixels = pixels;
+        self.bias = bias;
+    }
+}
+
+// Rec. 709 luma, counting transparent pixels as dark
+fn luma(p: &[f32; 4]) -> f32 {
+    (0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]) * p[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma, Rgb, RgbImage};
+
+    // Detail everywhere, so that no seam is free
+    fn noise(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let v = (x * 7919 + y * 104729) ^ (x * y * 31);
+            Rgb([(v % 251) as u8, (v / 3 % 241) as u8, (v / 7 % 239) as u8])
+        })
+    }
+
+    fn options(energy: Energy) -> CarveOptions {
+        CarveOptions {
+            energy,
+            protect: None,
+            remove: None,
+        }
+    }
+
+    #[test]
+    fn carves_to_the_exact_size() {
+        let img = DynamicImage::ImageRgb8(noise(12, 10));
+        for energy in [Energy::Gradient, Energy::Forward] {
+            for (size, expected) in [
+                ((8, 7), (8, 7)),
+                ((20, 13), (20, 13)),
+                ((5, 0), (5, 10)),
+                ((0, 16), (12, 16)),
+                ((0, 0), (12, 10)),
+            ] {
+                let carved = carve(&img, size, &options(energy)).unwrap();
+                assert_eq!((carved.width(), carved.height()), expected, "{:?}", energy);
+                assert_eq!(carved.color(), img.color());
+            }
+        }
+    }
+
+    #[test]
+    fn protected_columns_are_kept() {
+        let img = noise(12, 8);
+        let mask = GrayImage::from_fn(12, 8, |x, _| Luma([if x == 4 { 255 } else { 0 }]));
+        let options = CarveOptions {
+            protect: Some(DynamicImage::ImageLuma8(mask)),
+            ..options(Energy::Gradient)
+        };
+        let carved = carve(&DynamicImage::ImageRgb8(img.clone()), (5, 0), &options)
+            .unwrap()
+            .into_rgb8();
+        let column = |img: &RgbImage, x| (0..8).map(|y| *img.get_pixel(x, y)).collect::<Vec<_>>();
+        let protected = column(&img, 4);
+        assert!((0..5).any(|x| column(&carved, x) == protected));
+    }
+
+    #[test]
+    fn removal_takes_out_the_masked_object() {
+        let green = Rgb([0, 255, 0]);
+        let object = |x, y| (5..7).contains(&x) && (2..6).contains(&y);
+        let mut img = noise(12, 8);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            if object(x, y) {
+                *pixel = green;
+            }
+        }
+        let mask = GrayImage::from_fn(12, 8, |x, y| Luma([if object(x, y) { 255 } else { 0 }]));
+        for energy in [Energy::Gradient, Energy::Forward] {
+            let options = CarveOptions {
+                remove: Some(DynamicImage::ImageLuma8(mask.clone())),
+                ..options(energy)
+            };
+            let carved = carve(&DynamicImage::ImageRgb8(img.clone()), (0, 0), &options)
+                .unwrap()
+                .into_rgb8();
+            // The object is taller than it's wide, so it goes with two vertical seams
+            assert_eq!(carved.dimensions(), (10, 8));
+            assert!(carved.pixels().all(|p| *p != green));
+        }
+    }
+
+    #[test]
+    fn masks_must_match_the_image() {
+        let options = CarveOptions {
+            protect: Some(DynamicImage::ImageLuma8(GrayImage::new(3, 3))),
+            ..options(Energy::Gradient)
+        };
+        let img = DynamicImage::ImageRgb8(noise(4, 3));
+        assert_eq!(
+            carve(&img, (2, 0), &options).err().as_deref(),
+            Some("The mask is 3x3 but the image is 4x3")
+        );
+    }
+}
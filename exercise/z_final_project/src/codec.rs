@@ -0,0 +1,421 @@
+// Reading and writing image files, with control over the format and the encoder settings.
+//
+// Files are recognized by their contents (magic bytes) rather than by their extension, and "-"
+// means stdin or stdout, so mirage can sit in the middle of a Unix pipeline:
+//
+//     curl -s https://example.com/cat.jpg | mirage blur - - --format png | ...
+//
+// Nothing tells us what format stdout should be in, so writing to "-" needs --format.
+//
+// `DynamicImage::save()` picks the encoder from the file extension and always uses its defaults.
+// Here the format can be chosen explicitly (so `out.dat` can hold a PNG), and the encoders we
+// care most about get their knobs:
+//
+// - JPEG: quality 1-100 and chroma subsampling (4:4:4, 4:2:2, 4:2:0), via jpeg-encoder
+// - PNG: compression (fast, default, best) and row filter
+// - WebP: lossless (the only kind of WebP the image crate can write without libwebp)
+// - TIFF: compression (none, lzw, deflate, packbits), via the tiff crate
+//
+// Every other format the image crate knows is written with its default settings.  Formats that
+// can't hold the image's bit depth get the closest one they can.
+
+use crate::depth;
+use image::codecs::png::{CompressionType, FilterType};
+use image::codecs::webp::WebPEncoder;
+use image::io::Reader;
+use image::{ColorType, DynamicImage, ImageEncoder, ImageFormat};
+use jpeg_encoder::SamplingFactor;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use tiff::encoder::{colortype, compression, TiffEncoder, TiffValue};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TiffCompression {
+    None,
+    Lzw,
+    Deflate,
+    Packbits,
+}
+
+#[derive(Clone)]
+pub struct SaveOptions {
+    // Use this format instead of guessing from the file extension
+    pub format: Option<ImageFormat>,
+    // Bits per channel to write (8, 16 or 32), instead of the image's own
+    pub depth: Option<u8>,
+    pub jpeg_quality: u8,
+    pub jpeg_subsampling: SamplingFactor,
+    pub png_compression: CompressionType,
+    pub png_filter: FilterType,
+    pub tiff_compression: TiffCompression,
+}
+
+impl Default for SaveOptions {
+    fn default() -> Self {
+        SaveOptions {
+            format: None,
+            depth: None,
+            jpeg_quality: 90,
+            jpeg_subsampling: SamplingFactor::R_4_2_0,
+            png_compression: CompressionType::Default,
+            png_filter: FilterType::Adaptive,
+            tiff_compression: TiffCompression::Lzw,
+        }
+    }
+}
+
+pub fn parse_format(name: &str) -> Option<ImageFormat> {
+    ImageFormat::from_extension(name.to_ascii_lowercase())
+}
+
+pub fn parse_subsampling(name: &str) -> Option<SamplingFactor> {
+    match name {
+        "444" | "4:4:4" => Some(SamplingFactor::R_4_4_4),
+        "422" | "4:2:2" => Some(SamplingFactor::R_4_2_2),
+        "420" | "4:2:0" => Some(SamplingFactor::R_4_2_0),
+        _ => None,
+    }
+}
+
+pub fn parse_png_compression(name: &str) -> Option<CompressionType> {
+    match name {
+        "fast" => Some(CompressionType::Fast),
+        "default" => Some(CompressionType::Default),
+        "best" => Some(CompressionType::Best),
+        _ => None,
+    }
+}
+
+pub fn parse_png_filter(name: &str) -> Option<FilterType> {
+    match name {
+        "none" => Some(FilterType::NoFilter),
+        "sub" => Some(FilterType::Sub),
+        "up" => Some(FilterType::Up),
+        "avg" => Some(FilterType::Avg),
+        "paeth" => Some(FilterType::Paeth),
+        "adaptive" => Some(FilterType::Adaptive),
+        _ => None,
+    }
+}
+
+pub fn parse_tiff_compression(name: &str) -> Option<TiffCompression> {
+    match name {
+        "none" => Some(TiffCompression::None),
+        "lzw" => Some(TiffCompression::Lzw),
+        "deflate" => Some(TiffCompression::Deflate),
+        "packbits" => Some(TiffCompression::Packbits),
+        _ => None,
+    }
+}
+
+pub fn open(path: impl AsRef<Path>) -> Result<DynamicImage, String> {
+    let path = path.as_ref();
+    let img = if path == Path::new("-") {
+        let mut bytes = Vec::new();
+        std::io::stdin()
+            .lock()
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("stdin: {}", e))?;
+        decode_sniffed(bytes)
+    } else {
+        // The extension is only a fallback for formats without magic bytes (like TGA).
+        Reader::open(path)
+            .and_then(Reader::with_guessed_format)
+            .map_err(|e| format!("{}: {}", path.display(), e))?
+            .decode()
+    };
+    img.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Decode an image held in memory, telling the format from its magic bytes alone.
+fn decode_sniffed(bytes: Vec<u8>) -> image::ImageResult<DynamicImage> {
+    Reader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .decode()
+}
+
+pub fn save(img: &DynamicImage, path: &str, options: &SaveOptions) -> Result<(), String> {
+    let format = match options.format {
+        Some(format) => format,
+        None if path == "-" => return Err("Writing to stdout (-) needs --format".to_string()),
+        None => ImageFormat::from_path(path).map_err(|e| e.to_string())?,
+    };
+    let bytes = encode(img, format, options)?;
+    if path == "-" {
+        let mut stdout = std::io::stdout().lock();
+        stdout
+            .write_all(&bytes)
+            .and_then(|_| stdout.flush())
+            .map_err(|e| format!("stdout: {}", e))
+    } else {
+        std::fs::write(path, bytes).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+pub fn encode(
+    img: &DynamicImage,
+    format: ImageFormat,
+    options: &SaveOptions,
+) -> Result<Vec<u8>, String> {
+    let forced;
+    let img = match options.depth {
+        Some(bits) => {
+            forced = depth::with_bits(img.clone(), bits);
+            &forced
+        }
+        None => img,
+    };
+    let mut out = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => encode_jpeg(img, out.get_mut(), options)?,
+        ImageFormat::Png => encode_png(img, out.get_mut(), options).map_err(|e| e.to_string())?,
+        ImageFormat::WebP => {
+            let img = match (img.color().has_color(), img.color().has_alpha()) {
+                (false, false) => DynamicImage::ImageLuma8(img.to_luma8()),
+                (false, true) => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+                (true, false) => DynamicImage::ImageRgb8(img.to_rgb8()),
+                (true, true) => DynamicImage::ImageRgba8(img.to_rgba8()),
+            };
+            WebPEncoder::new_lossless(&mut out)
+                .write_image(img.as_bytes(), img.width(), img.height(), img.color())
+                .map_err(|e| e.to_string())?;
+        }
+        ImageFormat::Tiff => {
+            encode_tiff(img, &mut out, options.tiff_compression).map_err(|e| e.to_string())?
+        }
+        _ => {
+            // Try the image as it is, and fall back to 8 bits for formats that can't take more.
+            if let Err(e) = img.write_to(&mut out, format) {
+                if depth::bits(img.color()) == 8 {
+                    return Err(e.to_string());
+                }
+                out = Cursor::new(Vec::new());
+                depth::with_bits(img.clone(), 8)
+                    .write_to(&mut out, format)
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+    }
+    Ok(out.into_inner())
+}
+
+// A PNG encoder for an image of this size and color type, with the compression and filter from
+// `options`.  PNG holds up to 16 bits per channel, so floats become 16-bit rather than 8-bit.
+pub fn png_encoder<W: Write>(
+    out: W,
+    width: u32,
+    height: u32,
+    color: ColorType,
+    options: &SaveOptions,
+) -> png::Encoder<'static, W> {
+    let mut encoder = png::Encoder::new(out, width, height);
+    encoder.set_color(match (color.has_color(), color.has_alpha()) {
+        (false, false) => png::ColorType::Grayscale,
+        (false, true) => png::ColorType::GrayscaleAlpha,
+        (true, false) => png::ColorType::Rgb,
+        (true, true) => png::ColorType::Rgba,
+    });
+    encoder.set_depth(if depth::bits(color) > 8 {
+        png::BitDepth::Sixteen
+    } else {
+        png::BitDepth::Eight
+    });
+    encoder.set_compression(match options.png_compression {
+        CompressionType::Fast => png::Compression::Fast,
+        CompressionType::Best => png::Compression::Best,
+        _ => png::Compression::Default,
+    });
+    match options.png_filter {
+        FilterType::Adaptive => encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive),
+        filter => encoder.set_filter(match filter {
+            FilterType::Sub => png::FilterType::Sub,
+            FilterType::Up => png::FilterType::Up,
+            FilterType::Avg => png::FilterType::Avg,
+            FilterType::Paeth => png::FilterType::Paeth,
+            _ => png::FilterType::NoFilter,
+        }),
+    }
+    encoder
+}
+
+fn encode_png(
+    img: &DynamicImage,
+    out: &mut Vec<u8>,
+    options: &SaveOptions,
+) -> Result<(), png::EncodingError> {
+    let encoder = png_encoder(out, img.width(), img.height(), img.color(), options);
+    // PNG stores 16-bit samples big-endian, whatever the byte order of this machine.
+    let bytes = if depth::bits(img.color()) == 8 {
+        img.as_bytes().to_vec()
+    } else {
+        match depth::with_bits(img.clone(), 16) {
+            DynamicImage::ImageLuma16(img) => big_endian(img.as_raw()),
+            DynamicImage::ImageLumaA16(img) => big_endian(img.as_raw()),
+            DynamicImage::ImageRgb16(img) => big_endian(img.as_raw()),
+            DynamicImage::ImageRgba16(img) => big_endian(img.as_raw()),
+            _ => unreachable!(),
+        }
+    };
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&bytes)?;
+    writer.finish()
+}
+
+fn big_endian(samples: &[u16]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_be_bytes()).collect()
+}
+
+fn encode_jpeg(img: &DynamicImage, out: &mut Vec<u8>, options: &SaveOptions) -> Result<(), String> {
+    let (width, height) = (img.width(), img.height());
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(format!(
+            "JPEG can't store images bigger than {0}x{0}",
+            u16::MAX
+        ));
+    }
+    let mut encoder = jpeg_encoder::Encoder::new(out, options.jpeg_quality.clamp(1, 100));
+    encoder.set_sampling_factor(options.jpeg_subsampling);
+    // JPEG has no alpha channel and only 8 bits per channel.
+    let result = if img.color().has_color() {
+        let rgb = img.to_rgb8();
+        encoder.encode(
+            &rgb,
+            width as u16,
+            height as u16,
+            jpeg_encoder::ColorType::Rgb,
+        )
+    } else {
+        let gray = img.to_luma8();
+        encoder.encode(
+            &gray,
+            width as u16,
+            height as u16,
+            jpeg_encoder::ColorType::Luma,
+        )
+    };
+    result.map_err(|e| e.to_string())
+}
+
+fn encode_tiff(
+    img: &DynamicImage,
+    out: &mut Cursor<Vec<u8>>,
+    compression: TiffCompression,
+) -> tiff::TiffResult<()> {
+    let (w, h) = (img.width(), img.height());
+    match img.color() {
+        ColorType::L8 => write_tiff::<colortype::Gray8>(out, w, h, &img.to_luma8(), compression),
+        ColorType::L16 => write_tiff::<colortype::Gray16>(out, w, h, &img.to_luma16(), compression),
+        ColorType::Rgb8 => write_tiff::<colortype::RGB8>(out, w, h, &img.to_rgb8(), compression),
+        ColorType::Rgb16 => write_tiff::<colortype::RGB16>(out, w, h, &img.to_rgb16(), compression),
+        ColorType::Rgba16 | ColorType::La16 => {
+            write_tiff::<colortype::RGBA16>(out, w, h, &img.to_rgba16(), compression)
+        }
+        ColorType::Rgb32F => {
+            write_tiff::<colortype::RGB32Float>(out, w, h, &img.to_rgb32f(), compression)
+        }
+        ColorType::Rgba32F => {
+            write_tiff::<colortype::RGBA32Float>(out, w, h, &img.to_rgba32f(), compression)
+        }
+        _ => write_tiff::<colortype::RGBA8>(out, w, h, &img.to_rgba8(), compression),
+    }
+}
+
+fn write_tiff<C: colortype::ColorType>(
+    out: &mut Cursor<Vec<u8>>,
+    width: u32,
+    height: u32,
+    data: &[C::Inner],
+    method: TiffCompression,
+) -> tiff::TiffResult<()>
+where
+    [C::Inner]: TiffValue,
+{
+    let mut encoder = TiffEncoder::new(out)?;
+    match method {
+        TiffCompression::None => encoder.write_image_with_compression::<C, _>(
+            width,
+            height,
+            compression::Uncompressed,
+            data,
+        ),
+        TiffCompression::Lzw => {
+            encoder.write_image_with_compression::<C, _>(width, height, compression::Lzw, data)
+        }
+        TiffCompression::Deflate => encoder.write_image_with_compression::<C, _>(
+            width,
+            height,
+            compression::Deflate::default(),
+            data,
+        ),
+        TiffCompression::Packbits => {
+            encoder.write_image_with_compression::<C, _>(width, height, compression::Packbits, data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Luma, Rgb, Rgba};
+
+    // Samples whose two bytes differ, so a byte-order mix-up can't go unnoticed
+    fn sample(x: u32, y: u32, c: u32) -> u16 {
+        (0x0102 + x * 0x1111 + y * 0x0301 + c * 0x2000) as u16
+    }
+
+    fn round_trip(img: &DynamicImage, format: ImageFormat) -> DynamicImage {
+        let bytes = encode(img, format, &SaveOptions::default()).unwrap();
+        image::load_from_memory_with_format(&bytes, format).unwrap()
+    }
+
+    #[test]
+    fn sixteen_bit_png_round_trips() {
+        let gray =
+            DynamicImage::ImageLuma16(ImageBuffer::from_fn(5, 3, |x, y| Luma([sample(x, y, 0)])));
+        let rgba = DynamicImage::ImageRgba16(ImageBuffer::from_fn(5, 3, |x, y| {
+            Rgba([0, 1, 2, 3].map(|c| sample(x, y, c)))
+        }));
+        for img in [gray, rgba] {
+            assert_eq!(round_trip(&img, ImageFormat::Png), img);
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_tiff_round_trips() {
+        let rgb = DynamicImage::ImageRgb16(ImageBuffer::from_fn(5, 3, |x, y| {
+            Rgb([0, 1, 2].map(|c| sample(x, y, c)))
+        }));
+        assert_eq!(round_trip(&rgb, ImageFormat::Tiff), rgb);
+    }
+
+    #[test]
+    fn float_png_becomes_sixteen_bit() {
+        let img = DynamicImage::ImageRgb32F(ImageBuffer::from_fn(4, 2, |x, y| {
+            Rgb([x as f32 / 3.0, y as f32, 0.5])
+        }));
+        let decoded = round_trip(&img, ImageFormat::Png);
+        assert_eq!(decoded.color(), ColorType::Rgb16);
+        assert_eq!(decoded, DynamicImage::ImageRgb16(img.to_rgb16()));
+    }
+
+    #[test]
+    fn stdin_format_comes_from_magic_bytes() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(3, 2, |x, y| {
+            Rgb([x as u8 * 80, y as u8 * 200, 7])
+        }));
+        for format in [ImageFormat::Png, ImageFormat::Bmp, ImageFormat::Tiff] {
+            let bytes = encode(&img, format, &SaveOptions::default()).unwrap();
+            assert_eq!(decode_sniffed(bytes).unwrap().to_rgb8(), img.to_rgb8());
+        }
+        assert!(decode_sniffed(b"not an image".to_vec()).is_err());
+    }
+
+    #[test]
+    fn stdout_needs_a_format() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::new(1, 1));
+        assert_eq!(
+            save(&img, "-", &SaveOptions::default()),
+            Err("Writing to stdout (-) needs --format".to_string())
+        );
+    }
+}
@@ -0,0 +1,86 @@
+// Colors on the command line.  Any of these work:
+//
+//     #rgb  #rrggbb  #rrggbbaa  255,128,0  255,128,0,128  white  transparent
+//
+use image::Rgba;
+
+pub fn parse_color(text: &str) -> Option<Rgba<u8>> {
+    let named = match text {
+        "black" => Some([0, 0, 0, 255]),
+        "white" => Some([255, 255, 255, 255]),
+        "gray" | "grey" => Some([128, 128, 128, 255]),
+        "red" => Some([255, 0, 0, 255]),
+        "green" => Some([0, 255, 0, 255]),
+        "blue" => Some([0, 0, 255, 255]),
+        "yellow" => Some([255, 255, 0, 255]),
+        "cyan" => Some([0, 255, 255, 255]),
+        "magenta" => Some([255, 0, 255, 255]),
+        "transparent" => Some([0, 0, 0, 0]),
+        _ => None,
+    };
+    if let Some(rgba) = named {
+        return Some(Rgba(rgba));
+    }
+
+    if let Some(hex) = text.strip_prefix('#') {
+        let digits: Vec<u8> = hex
+            .chars()
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<_>>()?;
+        let rgba = match digits.len() {
+            3 => [digits[0] * 17, digits[1] * 17, digits[2] * 17, 255],
+            6 | 8 => {
+                let mut rgba = [255; 4];
+                for (channel, pair) in rgba.iter_mut().zip(digits.chunks(2)) {
+                    *channel = pair[0] * 16 + pair[1];
+                }
+                rgba
+            }
+            _ => return None,
+        };
+        return Some(Rgba(rgba));
+    }
+
+    let values: Vec<u8> = text
+        .split(',')
+        .map(|v| v.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    match values[..] {
+        [r, g, b] => Some(Rgba([r, g, b, 255])),
+        [r, g, b, a] => Some(Rgba([r, g, b, a])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_form() {
+        assert_eq!(parse_color("white"), Some(Rgba([255, 255, 255, 255])));
+        assert_eq!(parse_color("transparent"), Some(Rgba([0, 0, 0, 0])));
+        assert_eq!(parse_color("#f80"), Some(Rgba([255, 136, 0, 255])));
+        assert_eq!(parse_color("#ff8800"), Some(Rgba([255, 136, 0, 255])));
+        assert_eq!(parse_color("#FF880080"), Some(Rgba([255, 136, 0, 128])));
+        assert_eq!(parse_color("255, 128,0"), Some(Rgba([255, 128, 0, 255])));
+        assert_eq!(parse_color("255,128,0,64"), Some(Rgba([255, 128, 0, 64])));
+    }
+
+    #[test]
+    fn rejects_malformed_colors() {
+        for text in [
+            "",
+            "purple",
+            "#",
+            "#ff",
+            "#ff88",
+            "#ggg",
+            "1,2",
+            "1,2,3,4,5",
+            "256,0,0",
+        ] {
+            assert_eq!(parse_color(text), None, "{:?}", text);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// Image comparison for visual regression tests.
+//
+// All metrics work on channel values scaled to 0.0..=1.0, so they mean the same thing for 8-bit,
+// 16-bit and floating-point images:
+//
+// - MSE is the mean squared difference.
+// - PSNR is 10 * log10(1 / MSE) in decibels; higher is closer, identical images give infinity.
+//   (Scaling doesn't change PSNR, so it matches the usual 8-bit numbers.)
+// - SSIM is the structural similarity index of Wang et al. (2004) with the standard 11x11
+//   Gaussian window (sigma 1.5); 1.0 means identical.
+
+use crate::blur::gaussian_kernel;
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage, Rgba32FImage};
+
+pub struct ChannelScores {
+    pub name: &'static str,
+    pub mse: f64,
+    pub psnr: f64,
+    pub ssim: f64,
+}
+
+pub struct Comparison {
+    pub channels: Vec<ChannelScores>,
+    pub overall: ChannelScores,
+}
+
+// Compare two images of the same size.  Gray images are compared on one channel, color images on
+// R, G and B, plus alpha if either image has it.
+pub fn compare(a: &DynamicImage, b: &DynamicImage) -> Result<Comparison, String> {
+    if a.dimensions() != b.dimensions() {
+        return Err(format!(
+            "Images have different sizes: {}x{} and {}x{}",
+            a.width(),
+            a.height(),
+            b.width(),
+            b.height()
+        ));
+    }
+    let color = a.color().has_color() || b.color().has_color();
+    let alpha = a.color().has_alpha() || b.color().has_alpha();
+    let mut channels: Vec<(&'static str, usize)> = if color {
+        vec![("R", 0), ("G", 1), ("B", 2)]
+    } else {
+        vec![("L", 0)]
+    };
+    if alpha {
+        channels.push(("A", 3));
+    }
+
+    let (a, b) = (a.to_rgba32f(), b.to_rgba32f());
+    let scores: Vec<ChannelScores> = channels
+        .into_iter()
+        .map(|(name, c)| {
+            let x = plane(&a, c);
+            let y = plane(&b, c);
+            let mse = x
+                .iter()
+                .zip(&y)
+                .map(|(p, q)| (*p as f64 - *q as f64).powi(2))
+                .sum::<f64>()
+                / x.len().max(1) as f64;
+            ChannelScores {
+                name,
+                mse,
+                psnr: psnr(mse),
+                ssim: ssim(&x, &y, a.width() as usize, a.height() as usize),
+            }
+        })
+        .collect();
+
+    let n = scores.len() as f64;
+    let mse = scores.iter().map(|s| s.mse).sum::<f64>() / n;
+    let overall = ChannelScores {
+        name: "all",
+        mse,
+        psnr: psnr(mse),
+        ssim: scores.iter().map(|s| s.ssim).sum::<f64>() / n,
+    };
+    Ok(Comparison {
+        channels: scores,
+        overall,
+    })
+}
+
+fn psnr(mse: f64) -> f64 {
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (1.0 / mse).log10()
+    }
+}
+
+fn plane(img: &Rgba32FImage, channel: usize) -> Vec<f32> {
+    img.pixels().map(|p| p[channel].clamp(0.0, 1.0)).collect()
+}
+
+fn ssim(x: &[f32], y: &[f32], width: usize, height: usize) -> f64 {
+    const C1: f32 = 0.01 * 0.01;
+    const C2: f32 = 0.03 * 0.03;
+    let kernel = gaussian_kernel(1.5, 5);
+    let product = |p: &[f32], q: &[f32]| p.iter().zip(q).map(|(a, b)| a * b).collect::<Vec<_>>();
+
+    let mu_x = gaussian_blur(x, width, height, &kernel);
+    let mu_y = gaussian_blur(y, width, height, &kernel);
+    let xx = gaussian_blur(&product(x, x), width, height, &kernel);
+    let yy = gaussian_blur(&product(y, y), width, height, &kernel);
+    let xy = gaussian_blur(&product(x, y), width, height, &kernel);
+
+    let mut total = 0.0f64;
+    for i in 0..x.len() {
+        let (mx, my) = (mu_x[i], mu_y[i]);
+        let var_x = xx[i] - mx * mx;
+        let var_y = yy[i] - my * my;
+        let cov = xy[i] - mx * my;
+        let score = ((2.0 * mx * my + C1) * (2.0 * cov + C2))
+            / ((mx * mx + my * my + C1) * (var_x + var_y + C2));
+        total += score as f64;
+    }
+    total / x.len().max(1) as f64
+}
+
+// Separable blur of a single-channel plane, repeating the edge pixels past the borders.
+fn gaussian_blur(plane: &[f32], width: usize, height: usize, kernel: &[f32]) -> Vec<f32> {
+    let radius = (kernel.len() / 2) as isize;
+    let mut horizontal = vec![0.0; plane.len()];
+    for y in 0..height {
+        let row = &plane[y * width..(y + 1) * width];
+        for x in 0..width {
+            horizontal[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| {
+                    let sx = (x as isize + k as isize - radius).clamp(0, width as isize - 1);
+                    w * row[sx as usize]
+                })
+                .sum();
+        }
+    }
+    let mut result = vec![0.0; plane.len()];
+    for y in 0..height {
+        for x in 0..width {
+            result[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| {
+                    let sy = (y as isize + k as isize - radius).clamp(0, height as isize - 1);
+                    w * horizontal[sy as usize * width + x]
+                })
+                .sum();
+        }
+    }
+    result
+}
+
+// A picture of where the images differ: image A washed out to a pale gray, with differing pixels
+// painted red.  Differences are amplified so that even a change of one 8-bit level shows up.
+pub fn diff_image(a: &DynamicImage, b: &DynamicImage) -> RgbImage {
+    let (pa, pb) = (a.to_rgba32f(), b.to_rgba32f());
+    let gray = a.to_luma32f();
+    RgbImage::from_fn(a.width(), a.height(), |x, y| {
+        let (p, q) = (pa.get_pixel(x, y), pb.get_pixel(x, y));
+        let difference = (0..4).map(|c| (p[c] - q[c]).abs()).fold(0.0, f32::max);
+        let faded = 0.75 + 0.25 * gray.get_pixel(x, y)[0].clamp(0.0, 1.0);
+        let strength = if difference > 0.0 {
+            (0.5 + difference * 4.0).min(1.0)
+        } else {
+            0.0
+        };
+        let mix = |to: f32| ((faded + (to - faded) * strength) * 255.0).round() as u8;
+        Rgb([mix(1.0), mix(0.0), mix(0.0)])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma, RgbaImage};
+
+    fn gray(level: u8) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_pixel(16, 16, Luma([level])))
+    }
+
+    #[test]
+    fn identical_images_score_perfectly() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 16, |x, y| {
+            image::Rgba([(x * 16) as u8, (y * 16) as u8, 7, 200])
+        }));
+        let result = compare(&img, &img).unwrap();
+        assert_eq!(result.channels.len(), 4);
+        assert_eq!(result.overall.mse, 0.0);
+        assert_eq!(result.overall.psnr, f64::INFINITY);
+        assert!((result.overall.ssim - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn scores_a_known_difference() {
+        // 51 is 0.2 of full scale, so the MSE is 0.04 and the PSNR 10 * log10(25).
+        let result = compare(&gray(0), &gray(51)).unwrap();
+        assert_eq!(result.channels.len(), 1);
+        assert!((result.overall.mse - 0.04).abs() < 1e-6);
+        assert!((result.overall.psnr - 13.9794).abs() < 1e-3);
+        let result = compare(&gray(0), &gray(255)).unwrap();
+        assert!(result.overall.psnr.abs() < 1e-9);
+        assert!(result.overall.ssim < 0.001);
+    }
+
+    #[test]
+    fn rejects_different_sizes() {
+        let small = DynamicImage::ImageLuma8(GrayImage::new(4, 4));
+        assert!(compare(&gray(0), &small).is_err());
+    }
+}
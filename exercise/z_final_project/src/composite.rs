@@ -0,0 +1,364 @@
+// Layer compositing: place an OVERLAY image on top of a BASE image.
+//
+// Everything happens in floating-point RGBA, so the two inputs can have different color types
+// (gray, RGB, RGBA, 8 or 16 bits) and the math doesn't lose precision along the way.  Colors are
+// kept straight (not premultiplied) in the buffers, and the formulas below follow the W3C
+// "Compositing and Blending" spec: https://www.w3.org/TR/compositing-1/
+
+use crate::{depth, gamma};
+use image::{ColorType, DynamicImage, GenericImageView, Rgba, Rgba32FImage};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlendMode {
+    // Porter-Duff operators
+    Clear,
+    Src,
+    Dst,
+    SrcOver,
+    DstOver,
+    SrcIn,
+    DstIn,
+    SrcOut,
+    DstOut,
+    SrcAtop,
+    DstAtop,
+    Xor,
+    // Separable blend modes, always composited with source-over
+    Multiply,
+    Screen,
+    Overlay,
+    SoftLight,
+    Difference,
+    Darken,
+    Lighten,
+}
+
+impl BlendMode {
+    pub const NAMES: &'static str = "normal, clear, src, dst, src-over, dst-over, src-in, dst-in, \
+        src-out, dst-out, src-atop, dst-atop, xor, multiply, screen, overlay, soft-light, \
+        difference, darken, lighten";
+
+    pub fn from_name(name: &str) -> Option<BlendMode> {
+        let mode = match name {
+            "normal" | "src-over" => BlendMode::SrcOver,
+            "clear" => BlendMode::Clear,
+            "src" => BlendMode::Src,
+            "dst" => BlendMode::Dst,
+            "dst-over" => BlendMode::DstOver,
+            "src-in" => BlendMode::SrcIn,
+            "dst-in" => BlendMode::DstIn,
+            "src-out" => BlendMode::SrcOut,
+            "dst-out" => BlendMode::DstOut,
+            "src-atop" => BlendMode::SrcAtop,
+            "dst-atop" => BlendMode::DstAtop,
+            "xor" => BlendMode::Xor,
+            "multiply" => BlendMode::Multiply,
+            "screen" => BlendMode::Screen,
+            "overlay" => BlendMode::Overlay,
+            "soft-light" => BlendMode::SoftLight,
+            "difference" => BlendMode::Difference,
+            "darken" => BlendMode::Darken,
+            "lighten" => BlendMode::Lighten,
+            _ => return None,
+        };
+        Some(mode)
+    }
+
+    // The Porter-Duff fractions (Fa, Fb) of source and backdrop that end up in the result.
+    fn fractions(self, alpha_s: f32, alpha_b: f32) -> (f32, f32) {
+        match self {
+            BlendMode::Clear => (0.0, 0.0),
+            BlendMode::Src => (1.0, 0.0),
+            BlendMode::Dst => (0.0, 1.0),
+            BlendMode::DstOver => (1.0 - alpha_b, 1.0),
+            BlendMode::SrcIn => (alpha_b, 0.0),
+            BlendMode::DstIn => (0.0, alpha_s),
+            BlendMode::SrcOut => (1.0 - alpha_b, 0.0),
+            BlendMode::DstOut => (0.0, 1.0 - alpha_s),
+            BlendMode::SrcAtop => (alpha_b, 1.0 - alpha_s),
+            BlendMode::DstAtop => (1.0 - alpha_b, alpha_s),
+            BlendMode::Xor => (1.0 - alpha_b, 1.0 - alpha_s),
+            _ => (1.0, 1.0 - alpha_s),
+        }
+    }
+
+    // B(Cb, Cs) for the separable blend modes.  Porter-Duff operators just take the source color.
+    fn blend(self, cb: f32, cs: f32) -> f32 {
+        match self {
+            BlendMode::Multiply => cb * cs,
+            BlendMode::Screen => cb + cs - cb * cs,
+            BlendMode::Overlay => hard_light(cs, cb),
+            BlendMode::SoftLight => soft_light(cb, cs),
+            BlendMode::Difference => (cb - cs).abs(),
+            BlendMode::Darken => cb.min(cs),
+            BlendMode::Lighten => cb.max(cs),
+            _ => cs,
+        }
+    }
+}
+
+fn hard_light(cb: f32, cs: f32) -> f32 {
+    if cs <= 0.5 {
+        cb * 2.0 * cs
+    } else {
+        let cs = 2.0 * cs - 1.0;
+        cb + cs - cb * cs
+    }
+}
+
+fn soft_light(cb: f32, cs: f32) -> f32 {
+    if cs <= 0.5 {
+        cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb)
+    } else {
+        let d = if cb <= 0.25 {
+            ((16.0 * cb - 12.0) * cb + 4.0) * cb
+        } else {
+            cb.sqrt()
+        };
+        cb + (2.0 * cs - 1.0) * (d - cb)
+    }
+}
+
+// Where the overlay goes on the base.  Offsets move the overlay *away* from the anchored edge, so
+// `--anchor bottom-right --pos 10,10` leaves a 10 pixel margin (the same as ImageMagick's gravity).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    pub const NAMES: &'static str =
+        "top-left, top, top-right, left, center, right, bottom-left, bottom, bottom-right";
+
+    pub fn from_name(name: &str) -> Option<Anchor> {
+        let anchor = match name {
+            "top-left" => Anchor::TopLeft,
+            "top" => Anchor::Top,
+            "top-right" => Anchor::TopRight,
+            "left" => Anchor::Left,
+            "center" => Anchor::Center,
+            "right" => Anchor::Right,
+            "bottom-left" => Anchor::BottomLeft,
+            "bottom" => Anchor::Bottom,
+            "bottom-right" => Anchor::BottomRight,
+            _ => return None,
+        };
+        Some(anchor)
+    }
+
+    // Top-left corner of an `inner` sized box placed inside an `outer` sized box.
+    pub fn place(self, outer: (u32, u32), inner: (u32, u32), offset: (i64, i64)) -> (i64, i64) {
+        let free_x = outer.0 as i64 - inner.0 as i64;
+        let free_y = outer.1 as i64 - inner.1 as i64;
+        let x = match self {
+            Anchor::TopLeft | Anchor::Left | Anchor::BottomLeft => offset.0,
+            Anchor::Top | Anchor::Center | Anchor::Bottom => free_x / 2 + offset.0,
+            Anchor::TopRight | Anchor::Right | Anchor::BottomRight => free_x - offset.0,
+        };
+        let y = match self {
+            Anchor::TopLeft | Anchor::Top | Anchor::TopRight => offset.1,
+            Anchor::Left | Anchor::Center | Anchor::Right => free_y / 2 + offset.1,
+            Anchor::BottomLeft | Anchor::Bottom | Anchor::BottomRight => free_y - offset.1,
+        };
+        (x, y)
+    }
+}
+
+pub struct CompositeOptions {
+    pub mode: BlendMode,
+    pub opacity: f32,
+    pub anchor: Anchor,
+    pub offset: (i64, i64),
+    // Blend in linear light rather than on sRGB-encoded values
+    pub linear: bool,
+}
+
+impl Default for CompositeOptions {
+    fn default() -> Self {
+        CompositeOptions {
+            mode: BlendMode::SrcOver,
+            opacity: 1.0,
+            anchor: Anchor::TopLeft,
+            offset: (0, 0),
+            linear: false,
+        }
+    }
+}
+
+pub fn composite(
+    base: &DynamicImage,
+    overlay: &DynamicImage,
+    options: &CompositeOptions,
+) -> DynamicImage {
+    let backdrop = base.to_rgba32f();
+    let source = overlay.to_rgba32f();
+    let (left, top) = options
+        .anchor
+        .place(base.dimensions(), overlay.dimensions(), options.offset);
+
+    let mut result = Rgba32FImage::new(base.width(), base.height());
+    let mut transparent = false;
+    for (x, y, pixel) in result.enumerate_pixels_mut() {
+        // The whole canvas is composited, not just the area under the overlay: outside of it the
+        // source is fully transparent, which matters for operators like src-in or clear.
+        let sx = x as i64 - left;
+        let sy = y as i64 - top;
+        let inside =
+            sx >= 0 && sy >= 0 && sx < source.width() as i64 && sy < source.height() as i64;
+        let src = if inside {
+            *source.get_pixel(sx as u32, sy as u32)
+        } else {
+            Rgba([0.0; 4])
+        };
+        *pixel = if options.linear {
+            let out = composite_pixel(
+                gamma::decode_pixel(*backdrop.get_pixel(x, y)),
+                gamma::decode_pixel(src),
+                options,
+            );
+            gamma::encode_pixel(out)
+        } else {
+            composite_pixel(*backdrop.get_pixel(x, y), src, options)
+        };
+        transparent |= pixel[3] < 1.0;
+    }
+
+    let color = output_color(base.color(), overlay.color(), transparent);
+    depth::convert(DynamicImage::ImageRgba32F(result), color)
+}
+
+fn composite_pixel(
+    backdrop: Rgba<f32>,
+    source: Rgba<f32>,
+    options: &CompositeOptions,
+) -> Rgba<f32> {
+    let alpha_b = backdrop[3].clamp(0.0, 1.0);
+    let alpha_s = source[3].clamp(0.0, 1.0) * options.opacity.clamp(0.0, 1.0);
+    let (fa, fb) = options.mode.fractions(alpha_s, alpha_b);
+
+    let alpha_o = alpha_s * fa + alpha_b * fb;
+    let mut out = Rgba([0.0, 0.0, 0.0, alpha_o]);
+    if alpha_o <= 0.0 {
+        return out;
+    }
+    for c in 0..3 {
+        let cb = backdrop[c];
+        // Where the backdrop is opaque the blend result replaces the source color; where it is
+        // transparent the source shows through unchanged.
+        let cs = (1.0 - alpha_b) * source[c] + alpha_b * options.mode.blend(cb, source[c]);
+        out[c] = (alpha_s * fa * cs + alpha_b * fb * cb) / alpha_o;
+    }
+    out
+}
+
+// Pick the color type to write the result in.  Keep color if either input has it, keep the
+// base's bit depth (or more if the overlay has it), and only add an alpha channel when needed.
+fn output_color(base: ColorType, overlay: ColorType, transparent: bool) -> ColorType {
+    let has_color = base.has_color() || overlay.has_color();
+    let has_alpha = base.has_alpha() || transparent;
+    let bits = depth::bits(base).max(depth::bits(overlay));
+    depth::color_type(bits, has_color, has_alpha)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, RgbaImage};
+
+    fn solid(color: [u8; 4]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba(color)))
+    }
+
+    fn one_pixel(base: [u8; 4], overlay: [u8; 4], options: &CompositeOptions) -> [u8; 4] {
+        composite(&solid(base), &solid(overlay), options)
+            .to_rgba8()
+            .get_pixel(0, 0)
+            .0
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!(BlendMode::from_name("normal"), Some(BlendMode::SrcOver));
+        assert_eq!(
+            BlendMode::from_name("soft-light"),
+            Some(BlendMode::SoftLight)
+        );
+        assert_eq!(BlendMode::from_name("softlight"), None);
+        assert_eq!(Anchor::from_name("bottom-right"), Some(Anchor::BottomRight));
+        assert_eq!(Anchor::from_name("middle"), None);
+    }
+
+    #[test]
+    fn anchors_measure_offsets_from_their_edge() {
+        let outer = (100, 50);
+        let inner = (20, 10);
+        assert_eq!(Anchor::TopLeft.place(outer, inner, (5, 5)), (5, 5));
+        assert_eq!(Anchor::Center.place(outer, inner, (0, 0)), (40, 20));
+        assert_eq!(Anchor::BottomRight.place(outer, inner, (10, 10)), (70, 30));
+    }
+
+    #[test]
+    fn blends_known_values() {
+        let multiply = CompositeOptions {
+            mode: BlendMode::Multiply,
+            ..CompositeOptions::default()
+        };
+        assert_eq!(
+            one_pixel([200, 100, 50, 255], [255, 128, 0, 255], &multiply),
+            [200, 50, 0, 255]
+        );
+
+        let half = CompositeOptions {
+            opacity: 0.5,
+            ..CompositeOptions::default()
+        };
+        assert_eq!(
+            one_pixel([0, 0, 0, 255], [255, 255, 255, 255], &half),
+            [128, 128, 128, 255]
+        );
+
+        let clear = CompositeOptions {
+            mode: BlendMode::Clear,
+            ..CompositeOptions::default()
+        };
+        assert_eq!(one_pixel([9, 9, 9, 255], [1, 2, 3, 255], &clear)[3], 0);
+    }
+
+    #[test]
+    fn opacity_is_kept_within_0_and_1() {
+        let opacity = |opacity| CompositeOptions {
+            opacity,
+            ..CompositeOptions::default()
+        };
+        let (base, overlay) = ([0, 0, 0, 255], [255, 255, 255, 255]);
+        assert_eq!(
+            one_pixel(base, overlay, &opacity(1.5)),
+            one_pixel(base, overlay, &opacity(1.0))
+        );
+        assert_eq!(one_pixel(base, overlay, &opacity(-0.5)), base);
+    }
+
+    #[test]
+    fn keeps_the_base_size_and_drops_unneeded_alpha() {
+        let base = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 3, Rgb([10, 20, 30])));
+        let overlay = solid([255, 0, 0, 255]);
+        let options = CompositeOptions {
+            anchor: Anchor::BottomRight,
+            ..CompositeOptions::default()
+        };
+        let result = composite(&base, &overlay, &options);
+        assert_eq!(result.color(), ColorType::Rgb8);
+        let result = result.to_rgb8();
+        assert_eq!(result.dimensions(), (4, 3));
+        assert_eq!(result.get_pixel(3, 2), &Rgb([255, 0, 0]));
+        assert_eq!(result.get_pixel(0, 0), &Rgb([10, 20, 30]));
+    }
+}
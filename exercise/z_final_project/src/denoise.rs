@@ -0,0 +1,451 @@
+// Removing noise while keeping edges, which blurring smears.
+//
+// - Median: each pixel becomes the median of the square around it, one channel at a time.  Specks
+//   and salt-and-pepper noise go completely, and edges stay sharp, though fine lines and corners
+//   thinner than the radius are lost.  Uses Perreaux and Hébert's constant-time algorithm: a
+//   histogram of each column of the square is kept up to date as the square moves down, and the
+//   square's histogram is moved right by adding one column's histogram and taking away another's.
+//   So the time doesn't depend on the radius.  That works in 8-bit levels, so it's only used for
+//   8-bit images; deeper and float images have each square sorted, which takes time with the
+//   square of the radius but keeps their full precision.
+// - Bilateral: a Gaussian blur (of `spatial` sigma) where neighbors count less the more their color
+//   differs from the pixel's, by a Gaussian of `range` sigma.  Smooths flat areas and leaves edges
+//   stronger than `range` alone.  Takes time with the square of `spatial`.
+// - Non-local means (Buades, Coll and Morel, 2005): each pixel becomes a weighted mean of the
+//   pixels in the square of `search` radius around it, weighted by how alike the patches of
+//   `patch` radius around the two pixels are.  Noise is averaged away and detail that repeats
+//   (texture, lines, edges) is kept.  `strength` sets how unlike patches may be and still count;
+//   about the noise's standard deviation works.  Slow, but the patch comparisons are summed with
+//   running sums, so the time goes with the search area and not the patch size.
+//
+// Color distances are over red, green and blue together, so colors don't drift.  Alpha is left
+// alone, and the sigmas and strength are in 8-bit levels.
+
+use crate::{depth, parallel};
+use image::DynamicImage;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    Median {
+        radius: u32,
+    },
+    Bilateral {
+        spatial: f32,
+        range: f32,
+    },
+    NonLocalMeans {
+        strength: f32,
+        search: u32,
+        patch: u32,
+    },
+}
+
+impl Method {
+    pub const NAMES: &'static str = "median, bilateral, nlmeans";
+
+    // The sigmas and strength divide, so 0 would turn the whole image into NaN.
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Method::Bilateral { spatial, .. } if spatial <= 0.0 => {
+                Err("The spatial sigma must be more than 0".to_string())
+            }
+            Method::Bilateral { range, .. } if range <= 0.0 => {
+                Err("The range sigma must be more than 0".to_string())
+            }
+            Method::NonLocalMeans { strength, .. } if strength <= 0.0 => {
+                Err("The strength must be more than 0".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// An image as planes of floats, one per color channel
+struct Planes {
+    width: usize,
+    height: usize,
+    channels: Vec<Vec<f32>>,
+}
+
+impl Planes {
+    // The value of channel `c` at x, y, with coordinates outside the image moved to its edge.
+    fn at(&self, c: usize, x: isize, y: isize) -> f32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.channels[c][y * self.width + x]
+    }
+}
+
+pub fn denoise(img: &DynamicImage, method: Method) -> DynamicImage {
+    let mut rgba = img.to_rgba32f();
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    if width == 0 || height == 0 {
+        return img.clone();
+    }
+    // Gray images only need the one channel.
+    let count = if img.color().has_color() { 3 } else { 1 };
+    let planes = Planes {
+        width,
+        height,
+        channels: (0..count)
+            .map(|c| rgba.pixels().map(|p| p[c]).collect())
+            .collect(),
+    };
+    let result = match method {
+        Method::Median { radius } if depth::bits(img.color()) == 8 => median(&planes, radius),
+        Method::Median { radius } => sorted_median(&planes, radius),
+        Method::Bilateral { spatial, range } => bilateral(&planes, spatial, range / 255.0),
+        Method::NonLocalMeans {
+            strength,
+            search,
+            patch,
+        } => non_local_means(&planes, strength / 255.0, search, patch),
+    };
+    for (i, p) in rgba.pixels_mut().enumerate() {
+        for c in 0..3 {
+            p[c] = result[i * count + c.min(count - 1)];
+        }
+    }
+    depth::convert(DynamicImage::ImageRgba32F(rgba), img.color())
+}
+
+// A histogram of 8-bit levels, with a coarse one of 16 bins of 16 levels on top so the median can
+// be found in two short steps.
+#[derive(Clone)]
+struct Histogram {
+    coarse: [u32; 16],
+    fine: [u32; 256],
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            coarse: [0; 16],
+            fine: [0; 256],
+        }
+    }
+
+    fn add(&mut self, level: u8) {
+        self.coarse[level as usize >> 4] += 1;
+        self.fine[level as usize] += 1;
+    }
+
+    fn remove(&mut self, level: u8) {
+        self.coarse[level as usize >> 4] -= 1;
+        self.fine[level as usize] -= 1;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, add) in self.coarse.iter_mut().zip(other.coarse) {
+            *count += add;
+        }
+        for (count, add) in self.fine.iter_mut().zip(other.fine) {
+            *count += add;
+        }
+    }
+
+    // self + add - remove
+    fn shift(&mut self, add: &Histogram, remove: &Histogram) {
+        for (i, count) in self.coarse.iter_mut().enumerate() {
+            *count = *count + add.coarse[i] - remove.coarse[i];
+        }
+        for (i, count) in self.fine.iter_mut().enumerate() {
+            *count = *count + add.fine[i] - remove.fine[i];
+        }
+    }
+
+    // The level with `rank` values below it.
+    fn nth(&self, mut rank: u32) -> u8 {
+        let mut bin = 0;
+        while self.coarse[bin] <= rank {
+            rank -= self.coarse[bin];
+            bin += 1;
+        }
+        let mut level = bin * 16;
+        while self.fine[level] <= rank {
+            rank -= self.fine[level];
+            level += 1;
+        }
+        level as u8
+    }
+}
+
+// Results are pixel by pixel, with the channels of each pixel together.
+fn median(planes: &Planes, radius: u32) -> Vec<f32> {
+    let (width, height, count) = (planes.width, planes.height, planes.channels.len());
+    let r = radius as isize;
+    let levels: Vec<Vec<u8>> = planes
+        .channels
+        .iter()
+        .map(|plane| plane.iter().map(|&v| (v * 255.0).round() as u8).collect())
+        .collect();
+    let level = |c: usize, x: isize, y: isize| {
+        let x = x.clamp(0, width as isize - 1) as usize;
+        let y = y.clamp(0, height as isize - 1) as usize;
+        levels[c][y * width + x]
+    };
+    let rank = ((2 * r + 1) * (2 * r + 1) / 2) as u32;
+
+    let mut result = vec![0.0; width * height * count];
+    parallel::for_each_band(&mut result, width * count, |first, band| {
+        // Each column's histogram of the rows `radius` above and below the current row
+        let mut columns = vec![vec![Histogram::new(); width]; count];
+        for (c, columns) in columns.iter_mut().enumerate() {
+            for (x, column) in columns.iter_mut().enumerate() {
+                for dy in -r..=r {
+                    column.add(level(c, x as isize, first as isize + dy));
+                }
+            }
+        }
+        let column = |x: isize| x.clamp(0, width as isize - 1) as usize;
+        for (j, row) in band.chunks_exact_mut(width * count).enumerate() {
+            let y = (first + j) as isize;
+            for (c, columns) in columns.iter_mut().enumerate() {
+                if j > 0 {
+                    for (x, histogram) in columns.iter_mut().enumerate() {
+                        histogram.remove(level(c, x as isize, y - r - 1));
+                        histogram.add(level(c, x as isize, y + r));
+                    }
+                }
+                let mut square = Histogram::new();
+                for dx in -r..=r {
+                    square.merge(&columns[column(dx)]);
+                }
+                for x in 0..width as isize {
+                    if x > 0 {
+                        square.shift(&columns[column(x + r)], &columns[column(x - r - 1)]);
+                    }
+                    row[x as usize * count + c] = square.nth(rank) as f32 / 255.0;
+                }
+            }
+        }
+    });
+    result
+}
+
+// The median the slow way, for values that don't fit a histogram of 8-bit levels.
+fn sorted_median(planes: &Planes, radius: u32) -> Vec<f32> {
+    let (width, count) = (planes.width, planes.channels.len());
+    let r = radius as isize;
+    let mut result = vec![0.0; width * planes.height * count];
+    parallel::for_each_row(&mut result, width * count, |y, row| {
+        let y = y as isize;
+        let mut square = Vec::with_capacity(((2 * r + 1) * (2 * r + 1)) as usize);
+        for x in 0..width as isize {
+            for c in 0..count {
+                square.clear();
+                for dy in -r..=r {
+                    for dx in -r..=r {
+                        square.push(planes.at(c, x + dx, y + dy));
+                    }
+                }
+                let rank = square.len() / 2;
+                row[x as usize * count + c] =
+                    *square.select_nth_unstable_by(rank, f32::total_cmp).1;
+            }
+        }
+    });
+    result
+}
+
+fn bilateral(planes: &Planes, spatial: f32, range: f32) -> Vec<f32> {
+    let (width, count) = (planes.width, planes.channels.len());
+    let r = (2.0 * spatial).ceil().max(1.0) as isize;
+    let spatial_weights: Vec<f32> = (-r..=r)
+        .flat_map(|dy| (-r..=r).map(move |dx| (dx * dx + dy * dy) as f32))
+        .map(|d2| (-d2 / (2.0 * spatial * spatial)).exp())
+        .collect();
+    let range_scale = -1.0 / (2.0 * range * range * count as f32);
+
+    let mut result = vec![0.0; width * planes.height * count];
+    parallel::for_each_row(&mut result, width * count, |y, row| {
+        let y = y as isize;
+        for x in 0..width as isize {
+            let mut center = [0.0; 3];
+            for (c, v) in center.iter_mut().enumerate().take(count) {
+                *v = planes.at(c, x, y);
+            }
+            let mut sum = [0.0; 3];
+            let mut total = 0.0;
+            let mut weights = spatial_weights.iter();
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    let spatial_weight = weights.next().unwrap();
+                    let mut neighbor = [0.0; 3];
+                    let mut distance = 0.0;
+                    for c in 0..count {
+                        neighbor[c] = planes.at(c, x + dx, y + dy);
+                        distance += (neighbor[c] - center[c]).powi(2);
+                    }
+                    let weight = spatial_weight * (distance * range_scale).exp();
+                    for c in 0..count {
+                        sum[c] += weight * neighbor[c];
+                    }
+                    total += weight;
+                }
+            }
+            for c in 0..count {
+                row[x as usize * count + c] = sum[c] / total;
+            }
+        }
+    });
+    result
+}
+
+fn non_local_means(planes: &Planes, strength: f32, search: u32, patch: u32) -> Vec<f32> {
+    let (width, count) = (planes.width, planes.channels.len());
+    let (s, p) = (search as isize, patch as isize);
+    // Mean squared difference per value in a patch, scaled for the weight
+    let scale = -1.0 / (((2 * p + 1) * (2 * p + 1)) as f32 * count as f32 * strength * strength);
+    let padded = width + 2 * patch as usize;
+
+    let mut result = vec![0.0; width * planes.height * count];
+    parallel::for_each_band(&mut result, width * count, |first, band| {
+        let rows = band.len() / (width * count);
+        let (first, extended) = (first as isize, rows + 2 * patch as usize);
+        let mut sums = vec![0.0f32; rows * width * count];
+        let mut totals = vec![0.0f32; rows * width];
+        // Squared differences summed across patch rows, for the band's rows and `patch` beyond
+        let mut across = vec![0.0f32; extended * width];
+        let mut differences = vec![0.0f32; padded];
+        for dy in -s..=s {
+            for dx in -s..=s {
+                for (i, across) in across.chunks_exact_mut(width).enumerate() {
+                    let y = first - p + i as isize;
+                    for (i, difference) in differences.iter_mut().enumerate() {
+                        let x = i as isize - p;
+                        *difference = (0..count)
+                            .map(|c| planes.at(c, x, y) - planes.at(c, x + dx, y + dy))
+                            .map(|d| d * d)
+                            .sum();
+                    }
+                    let mut running: f32 = differences[..2 * patch as usize].iter().sum();
+                    for (x, sum) in across.iter_mut().enumerate() {
+                        running += differences[x + 2 * patch as usize];
+                        *sum = running;
+                        running -= differences[x];
+                    }
+                }
+                // Now down the patch columns, one output row at a time.
+                let mut patch_sums: Vec<f32> = vec![0.0; width];
+                for row in across.chunks_exact(width).take(2 * patch as usize) {
+                    for (sum, &v) in patch_sums.iter_mut().zip(row) {
+                        *sum += v;
+                    }
+                }
+                for j in 0..rows {
+                    let (top, bottom) = (j, j + 2 * patch as usize);
+                    for (x, sum) in patch_sums.iter_mut().enumerate() {
+                        *sum += across[bottom * width + x];
+                    }
+                    let y = first + j as isize;
+                    for (x, &sum) in patch_sums.iter().enumerate() {
+                        let weight = (sum * scale).exp();
+                        let i = j * width + x;
+                        for c in 0..count {
+                            sums[i * count + c] += weight * planes.at(c, x as isize + dx, y + dy);
+                        }
+                        totals[i] += weight;
+                    }
+                    for (x, sum) in patch_sums.iter_mut().enumerate() {
+                        *sum -= across[top * width + x];
+                    }
+                }
+            }
+        }
+        for (i, value) in band.iter_mut().enumerate() {
+            *value = sums[i] / totals[i / count];
+        }
+    });
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, ImageBuffer, Luma, Rgb, Rgb32FImage};
+
+    #[test]
+    fn median_removes_specks() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_fn(7, 7, |x, y| {
+            Luma([if (x, y) == (3, 3) { 255 } else { 40 + x as u8 }])
+        }));
+        let cleaned = denoise(&img, Method::Median { radius: 1 }).into_luma8();
+        assert_eq!(cleaned.get_pixel(3, 3), &Luma([43]));
+        assert_eq!(cleaned.get_pixel(0, 0), &Luma([40]));
+    }
+
+    #[test]
+    fn median_keeps_the_depth() {
+        // Levels closer together than 8 bits can tell apart
+        let img = DynamicImage::ImageLuma16(ImageBuffer::from_fn(5, 5, |x, y| {
+            Luma([if (x, y) == (2, 2) {
+                0
+            } else {
+                30000 + x as u16
+            }])
+        }));
+        let cleaned = denoise(&img, Method::Median { radius: 1 }).into_luma16();
+        assert_eq!(cleaned.get_pixel(2, 2), &Luma([30002]));
+        assert_eq!(cleaned.get_pixel(4, 0), &Luma([30004]));
+
+        // Floats past white stay past white
+        let img = DynamicImage::ImageRgb32F(Rgb32FImage::from_pixel(3, 3, Rgb([2.5, 0.5, -0.25])));
+        let cleaned = denoise(&img, Method::Median { radius: 1 }).into_rgb32f();
+        assert_eq!(cleaned.get_pixel(1, 1), &Rgb([2.5, 0.5, -0.25]));
+    }
+
+    #[test]
+    fn flat_images_stay_flat() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_pixel(6, 5, Luma([77])));
+        for method in [
+            Method::Bilateral {
+                spatial: 1.5,
+                range: 20.0,
+            },
+            Method::NonLocalMeans {
+                strength: 10.0,
+                search: 2,
+                patch: 1,
+            },
+        ] {
+            assert_eq!(denoise(&img, method), img, "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn bilateral_keeps_strong_edges() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_fn(8, 3, |x, _| {
+            Luma([if x < 4 { 20 } else { 220 }])
+        }));
+        let method = Method::Bilateral {
+            spatial: 2.0,
+            range: 10.0,
+        };
+        assert_eq!(denoise(&img, method), img);
+    }
+
+    #[test]
+    fn zero_sigmas_are_rejected() {
+        assert!(Method::Bilateral {
+            spatial: 0.0,
+            range: 30.0
+        }
+        .validate()
+        .is_err());
+        assert!(Method::Bilateral {
+            spatial: 3.0,
+            range: -1.0
+        }
+        .validate()
+        .is_err());
+        assert!(Method::NonLocalMeans {
+            strength: 0.0,
+            search: 7,
+            patch: 1
+        }
+        .validate()
+        .is_err());
+        assert!(Method::Median { radius: 0 }.validate().is_ok());
+    }
+}
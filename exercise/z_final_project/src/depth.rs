@@ -0,0 +1,133 @@
+// Bit depth bookkeeping.
+//
+// Operations that do their own pixel math work in f32 and then hand back an image with the same
+// depth they were given, so 16-bit scans and floating-point HDR data don't get squeezed through
+// 8 bits on the way.  Quantizing happens once, when the encoder needs it (or when --depth asks).
+
+use image::{ColorType, DynamicImage};
+
+// Bits per channel: 8, 16 or 32 (float).
+pub fn bits(color: ColorType) -> u8 {
+    (color.bytes_per_pixel() / color.channel_count()) * 8
+}
+
+pub fn parse_bits(text: &str) -> Option<u8> {
+    match text {
+        "8" => Some(8),
+        "16" => Some(16),
+        "32" | "float" => Some(32),
+        _ => None,
+    }
+}
+
+// The color type with the given depth and channels.  There is no gray floating-point type, so
+// 32-bit gray becomes 32-bit RGB.
+pub fn color_type(bits: u8, has_color: bool, has_alpha: bool) -> ColorType {
+    match (bits, has_color, has_alpha) {
+        (8, false, false) => ColorType::L8,
+        (8, false, true) => ColorType::La8,
+        (8, true, false) => ColorType::Rgb8,
+        (8, true, true) => ColorType::Rgba8,
+        (16, false, false) => ColorType::L16,
+        (16, false, true) => ColorType::La16,
+        (16, true, false) => ColorType::Rgb16,
+        (16, true, true) => ColorType::Rgba16,
+        (_, _, false) => ColorType::Rgb32F,
+        (_, _, true) => ColorType::Rgba32F,
+    }
+}
+
+pub fn convert(img: DynamicImage, color: ColorType) -> DynamicImage {
+    if img.color() == color {
+        return img;
+    }
+    match color {
+        ColorType::L8 => DynamicImage::ImageLuma8(img.to_luma8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ColorType::Rgba8 => DynamicImage::ImageRgba8(img.to_rgba8()),
+        ColorType::L16 => DynamicImage::ImageLuma16(img.to_luma16()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(img.to_rgb16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(img.to_rgba16()),
+        ColorType::Rgb32F => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+        _ => DynamicImage::ImageRgba32F(img.to_rgba32f()),
+    }
+}
+
+// Same channels, different depth.
+pub fn with_bits(img: DynamicImage, bits: u8) -> DynamicImage {
+    let color = color_type(bits, img.color().has_color(), img.color().has_alpha());
+    convert(img, color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{self, SaveOptions};
+    use crate::ops;
+    use image::{ImageBuffer, ImageFormat, Rgb};
+
+    #[test]
+    fn sixteen_bits_survive_a_chain() {
+        // A shallow ramp: neighboring samples differ by less than one 8-bit level
+        let img = DynamicImage::ImageRgb16(ImageBuffer::from_fn(24, 16, |x, y| {
+            Rgb([30000 + x as u16 * 7, 30000 + y as u16 * 5, 12345])
+        }));
+        let options = SaveOptions::default();
+        let bytes = codec::encode(&img, ImageFormat::Png, &options).unwrap();
+        let mut img = image::load_from_memory(&bytes).unwrap();
+        assert_eq!(img.color(), ColorType::Rgb16);
+
+        for operation in ops::parse_chain(&["blur", "1", "resize", "12x8"]).unwrap() {
+            img = operation.apply(&img, false);
+            assert_eq!(img.color(), ColorType::Rgb16);
+        }
+        // Values between 8-bit levels are still there
+        assert!(img
+            .to_rgb16()
+            .pixels()
+            .any(|p| p.0.iter().any(|&c| c % 257 != 0)));
+
+        let bytes = codec::encode(&img, ImageFormat::Png, &options).unwrap();
+        assert_eq!(image::load_from_memory(&bytes).unwrap(), img);
+    }
+
+    #[test]
+    fn depth_option_converts_the_output() {
+        let img = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(2, 2, Rgb([0x1234, 0, 65535])));
+        let eight = with_bits(img.clone(), 8);
+        assert_eq!(eight.color(), ColorType::Rgb8);
+        assert_eq!(eight.to_rgb8().get_pixel(0, 0), &Rgb([0x12, 0, 255]));
+        // Going back up scales 8-bit levels to the full 16-bit range
+        let sixteen = with_bits(eight, 16);
+        assert_eq!(sixteen.color(), ColorType::Rgb16);
+        assert_eq!(sixteen.to_rgb16().get_pixel(0, 0), &Rgb([0x1212, 0, 65535]));
+        assert_eq!(with_bits(img, 32).color(), ColorType::Rgb32F);
+
+        // The same happens when saving with --depth
+        let options = SaveOptions {
+            depth: Some(8),
+            ..SaveOptions::default()
+        };
+        let img = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(2, 2, Rgb([0x1234, 0, 65535])));
+        let bytes = codec::encode(&img, ImageFormat::Png, &options).unwrap();
+        assert_eq!(
+            image::load_from_memory(&bytes).unwrap().color(),
+            ColorType::Rgb8
+        );
+    }
+
+    #[test]
+    fn bits_and_color_types_match() {
+        for bits in [8, 16, 32] {
+            for (color, alpha) in [(false, false), (false, true), (true, false), (true, true)] {
+                let made = color_type(bits, color, alpha);
+                assert_eq!(self::bits(made), bits);
+                assert_eq!(made.has_alpha(), alpha);
+            }
+        }
+        assert_eq!(parse_bits("float"), Some(32));
+        assert_eq!(parse_bits("12"), None);
+    }
+}
@@ -0,0 +1,655 @@
+// Anti-aliased vector drawing: lines, arrows, rectangles, circles, ellipses, polygons and
+// SVG-style paths, filled and/or stroked.
+//
+// Every shape is turned into closed polygons first: curves are flattened into short line
+// segments, and a stroke becomes a strip of quads along the path with round discs at the joints.
+// The polygons are then rasterized with ab_glyph_rasterizer (the same rasterizer ab_glyph uses for
+// text), which computes exact pixel coverage and so gives us anti-aliasing for free.
+//
+// Shapes are written one per string (or one per line in a recipe file), e.g.
+//
+//     line 10,10 200,50 width=3 color=red
+//     arrow 300,300 220,240 color=yellow width=4
+//     rect 40,40 200,120 stroke=#00ff00 width=2 radius=12
+//     circle 400,300 50 fill=#ff000080
+//     ellipse 400,300 80,40 stroke=blue
+//     polygon 10,10 100,10 55,90 fill=white stroke=black
+//     polyline 10,10 50,60 90,10 width=2
+//     path "M 10 10 L 100 10 Q 150 50 100 90 C 80 120 20 120 10 90 Z" fill=cyan
+//
+use crate::color::parse_color;
+use crate::composite::{self, CompositeOptions};
+use ab_glyph_rasterizer::{point, Rasterizer};
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use std::f32::consts::PI;
+
+type P = (f32, f32);
+
+// How far a flattened curve may stray from the real one, in pixels.
+const TOLERANCE: f32 = 0.2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    Line { from: P, to: P },
+    Arrow { from: P, to: P, head: f32 },
+    Rect { corner: P, size: P, radius: f32 },
+    Ellipse { center: P, radii: P },
+    Polygon(Vec<P>),
+    Polyline(Vec<P>),
+    Path(Vec<Subpath>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subpath {
+    points: Vec<P>,
+    closed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Style {
+    pub fill: Option<Rgba<u8>>,
+    pub stroke: Option<Rgba<u8>>,
+    pub width: f32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+    pub shape: Shape,
+    pub style: Style,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let words = split_words(line);
+        let (name, rest) = words.split_first().ok_or("Empty drawing command")?;
+
+        let mut values = Vec::new();
+        let mut style = Style {
+            fill: None,
+            stroke: None,
+            width: 2.0,
+        };
+        let mut stroke_given = false;
+        let mut radius = 0.0;
+        let mut head = None;
+        for word in rest {
+            let (key, value) = match word.split_once('=') {
+                Some(pair) => pair,
+                None => {
+                    values.push(word.as_str());
+                    continue;
+                }
+            };
+            let color = || parse_color(value).ok_or(format!("Bad color: {}", value));
+            match key {
+                "fill" => style.fill = Some(color()?),
+                "stroke" | "color" => {
+                    style.stroke = Some(color()?);
+                    stroke_given = true;
+                }
+                "width" => style.width = parse_number(value)?,
+                "radius" => radius = parse_number(value)?,
+                "head" => head = Some(parse_number(value)?),
+                _ => return Err(format!("Unknown attribute: {}", key)),
+            }
+        }
+        // Draw an outline unless the shape was only asked to be filled.
+        if !stroke_given && style.fill.is_none() {
+            style.stroke = Some(Rgba([255, 0, 0, 255]));
+        }
+
+        let shape = match (name.as_str(), &values[..]) {
+            ("line", [from, to]) => Shape::Line {
+                from: parse_point(from)?,
+                to: parse_point(to)?,
+            },
+            ("arrow", [from, to]) => Shape::Arrow {
+                from: parse_point(from)?,
+                to: parse_point(to)?,
+                head: head.unwrap_or(style.width * 4.0 + 6.0),
+            },
+            ("rect", [corner, size]) => Shape::Rect {
+                corner: parse_point(corner)?,
+                size: parse_point(size)?,
+                radius,
+            },
+            ("circle", [center, r]) => {
+                let r = parse_number(r)?;
+                Shape::Ellipse {
+                    center: parse_point(center)?,
+                    radii: (r, r),
+                }
+            }
+            ("ellipse", [center, radii]) => Shape::Ellipse {
+                center: parse_point(center)?,
+                radii: parse_point(radii)?,
+            },
+            ("polygon", points) if points.len() >= 3 => Shape::Polygon(
+                points
+                    .iter()
+                    .map(|p| parse_point(p))
+                    .collect::<Result<_, _>>()?,
+            ),
+            ("polyline", points) if points.len() >= 2 => Shape::Polyline(
+                points
+                    .iter()
+                    .map(|p| parse_point(p))
+                    .collect::<Result<_, _>>()?,
+            ),
+            ("path", [data]) => Shape::Path(parse_path(data)?),
+            _ => return Err(format!("Can't understand drawing command: {}", line)),
+        };
+        Ok(Command { shape, style })
+    }
+}
+
+// Split a line into words at whitespace, keeping "quoted strings" together.
+pub fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut started = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if started {
+                    words.push(std::mem::take(&mut word));
+                    started = false;
+                }
+            }
+            c => {
+                word.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        words.push(word);
+    }
+    words
+}
+
+fn parse_number(text: &str) -> Result<f32, String> {
+    text.trim()
+        .parse()
+        .map_err(|_| format!("Not a number: {}", text))
+}
+
+fn parse_point(text: &str) -> Result<P, String> {
+    let (x, y) = text
+        .split_once(',')
+        .ok_or(format!("Expected X,Y but got: {}", text))?;
+    Ok((parse_number(x)?, parse_number(y)?))
+}
+
+// Parse SVG path data.  Supports M, L, H, V, Q, C and Z, in absolute and relative (lowercase)
+// form, including implicit repeats like "L 1 2 3 4".
+fn parse_path(data: &str) -> Result<Vec<Subpath>, String> {
+    let mut tokens = Vec::new();
+    let mut number = String::new();
+    for c in data.chars() {
+        let starts_new = c.is_ascii_alphabetic() && c != 'e' && c != 'E'
+            || c.is_whitespace()
+            || c == ','
+            || (c == '-' && !number.is_empty() && !number.ends_with(['e', 'E']));
+        if starts_new && !number.is_empty() {
+            tokens.push(std::mem::take(&mut number));
+        }
+        if c.is_ascii_alphabetic() && c != 'e' && c != 'E' {
+            tokens.push(c.to_string());
+        } else if !c.is_whitespace() && c != ',' {
+            number.push(c);
+        }
+    }
+    if !number.is_empty() {
+        tokens.push(number);
+    }
+
+    let mut subpaths: Vec<Subpath> = Vec::new();
+    let mut current: Vec<P> = Vec::new();
+    let mut pen = (0.0, 0.0);
+    let mut start = (0.0, 0.0);
+    let mut command = None;
+    let mut i = 0;
+    let mut finish = |current: &mut Vec<P>, closed: bool| {
+        if current.len() > 1 {
+            subpaths.push(Subpath {
+                points: std::mem::take(current),
+                closed,
+            });
+        }
+        current.clear();
+    };
+    while i < tokens.len() {
+        if tokens[i].chars().all(|c| c.is_ascii_alphabetic()) {
+            command = tokens[i].chars().next();
+            i += 1;
+        }
+        let c = command.ok_or("Path data must start with a command")?;
+        let relative = c.is_ascii_lowercase();
+        let arity = match c.to_ascii_uppercase() {
+            'M' | 'L' => 2,
+            'H' | 'V' => 1,
+            'Q' => 4,
+            'C' => 6,
+            'Z' => 0,
+            _ => return Err(format!("Unsupported path command: {}", c)),
+        };
+        let args: Vec<f32> = tokens
+            .get(i..i + arity)
+            .ok_or(format!("Not enough numbers for path command {}", c))?
+            .iter()
+            .map(|t| parse_number(t))
+            .collect::<Result<_, _>>()?;
+        i += arity;
+        let base = if relative { pen } else { (0.0, 0.0) };
+        let at = |n: usize| (base.0 + args[n], base.1 + args[n + 1]);
+        match c.to_ascii_uppercase() {
+            'M' => {
+                finish(&mut current, false);
+                pen = at(0);
+                start = pen;
+                current.push(pen);
+                // Extra coordinate pairs after a move are treated as lines.
+                command = Some(if relative { 'l' } else { 'L' });
+            }
+            'L' => pen = at(0),
+            'H' => pen = (base.0 + args[0], pen.1),
+            'V' => pen = (pen.0, base.1 + args[0]),
+            'Q' => {
+                let (c1, end) = (at(0), at(2));
+                flatten_quad(&mut current, pen, c1, end);
+                pen = end;
+            }
+            'C' => {
+                let (c1, c2, end) = (at(0), at(2), at(4));
+                flatten_cubic(&mut current, pen, c1, c2, end);
+                pen = end;
+            }
+            _ => {
+                // Z has no arguments to repeat, so a number after it would never be consumed.
+                if tokens
+                    .get(i)
+                    .is_some_and(|t| !t.chars().all(|c| c.is_ascii_alphabetic()))
+                {
+                    return Err("Z takes no arguments".to_string());
+                }
+                finish(&mut current, true);
+                pen = start;
+                current.push(pen);
+                continue;
+            }
+        }
+        if current.is_empty() {
+            current.push(start);
+        }
+        if matches!(c.to_ascii_uppercase(), 'L' | 'H' | 'V') {
+            current.push(pen);
+        }
+    }
+    finish(&mut current, false);
+    Ok(subpaths)
+}
+
+fn flatten_quad(points: &mut Vec<P>, p0: P, p1: P, p2: P) {
+    let steps = curve_steps(&[p0, p1, p2]);
+    for i in 1..=steps {
+        let t = i as f32 / steps as f32;
+        let u = 1.0 - t;
+        points.push((
+            u * u * p0.0 + 2.0 * u * t * p1.0 + t * t * p2.0,
+            u * u * p0.1 + 2.0 * u * t * p1.1 + t * t * p2.1,
+        ));
+    }
+}
+
+fn flatten_cubic(points: &mut Vec<P>, p0: P, p1: P, p2: P, p3: P) {
+    let steps = curve_steps(&[p0, p1, p2, p3]);
+    for i in 1..=steps {
+        let t = i as f32 / steps as f32;
+        let u = 1.0 - t;
+        let (a, b, c, d) = (u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t);
+        points.push((
+            a * p0.0 + b * p1.0 + c * p2.0 + d * p3.0,
+            a * p0.1 + b * p1.1 + c * p2.1 + d * p3.1,
+        ));
+    }
+}
+
+// Enough segments that the flattened curve stays within TOLERANCE of the real one, based on how
+// far the control polygon bends.
+fn curve_steps(control: &[P]) -> usize {
+    let bend = control
+        .windows(3)
+        .map(|w| {
+            distance(
+                (
+                    w[0].0 - 2.0 * w[1].0 + w[2].0,
+                    w[0].1 - 2.0 * w[1].1 + w[2].1,
+                ),
+                (0.0, 0.0),
+            )
+        })
+        .fold(0.0, f32::max);
+    ((bend / (8.0 * TOLERANCE)).sqrt().ceil() as usize).clamp(1, 1000)
+}
+
+fn distance(a: P, b: P) -> f32 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+impl Shape {
+    // The outline of the shape as a list of polylines, and whether it is a closed area that can be
+    // filled.
+    fn outline(&self) -> Vec<Subpath> {
+        let open = |points: Vec<P>| Subpath {
+            points,
+            closed: false,
+        };
+        let closed = |points: Vec<P>| Subpath {
+            points,
+            closed: true,
+        };
+        match self {
+            Shape::Line { from, to } => vec![open(vec![*from, *to])],
+            Shape::Arrow { from, to, .. } => vec![open(vec![*from, *to])],
+            Shape::Rect {
+                corner,
+                size,
+                radius,
+            } => vec![closed(rounded_rect(*corner, *size, *radius))],
+            Shape::Ellipse { center, radii } => {
+                vec![closed(ellipse(*center, *radii, 0.0, 2.0 * PI))]
+            }
+            Shape::Polygon(points) => vec![closed(points.clone())],
+            Shape::Polyline(points) => vec![open(points.clone())],
+            Shape::Path(subpaths) => subpaths.clone(),
+        }
+    }
+}
+
+// Points along an elliptical arc from angle `start` to `end` (radians, clockwise on screen).
+fn ellipse(center: P, radii: P, start: f32, end: f32) -> Vec<P> {
+    let biggest = radii.0.abs().max(radii.1.abs());
+    // Keep each chord's sagitta under TOLERANCE: r * (1 - cos(step / 2)) <= TOLERANCE
+    let step = if biggest > TOLERANCE {
+        2.0 * (1.0 - TOLERANCE / biggest).acos()
+    } else {
+        PI / 2.0
+    };
+    let steps = (((end - start).abs() / step).ceil() as usize).clamp(4, 4096);
+    (0..=steps)
+        .map(|i| {
+            let angle = start + (end - start) * i as f32 / steps as f32;
+            (
+                center.0 + radii.0 * angle.cos(),
+                center.1 + radii.1 * angle.sin(),
+            )
+        })
+        .collect()
+}
+
+fn rounded_rect(corner: P, size: P, radius: f32) -> Vec<P> {
+    let (x0, y0) = corner;
+    let (x1, y1) = (corner.0 + size.0, corner.1 + size.1);
+    let r = radius
+        .min(size.0.abs() / 2.0)
+        .min(size.1.abs() / 2.0)
+        .max(0.0);
+    if r == 0.0 {
+        return vec![(x0, y0), (x1, y0), (x1, y1), (x0, y1)];
+    }
+    let mut points = Vec::new();
+    points.extend(ellipse((x1 - r, y0 + r), (r, r), -PI / 2.0, 0.0));
+    points.extend(ellipse((x1 - r, y1 - r), (r, r), 0.0, PI / 2.0));
+    points.extend(ellipse((x0 + r, y1 - r), (r, r), PI / 2.0, PI));
+    points.extend(ellipse((x0 + r, y0 + r), (r, r), PI, 1.5 * PI));
+    points
+}
+
+// Draw the commands, in order, onto a copy of `img`.
+pub fn draw(img: &DynamicImage, commands: &[Command], linear: bool) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let mut layer = RgbaImage::new(width, height);
+    for command in commands {
+        let outline = command.shape.outline();
+        if let Some(fill) = command.style.fill {
+            let polygons: Vec<Vec<P>> = outline
+                .iter()
+                .filter(|subpath| subpath.closed || matches!(command.shape, Shape::Path(_)))
+                .map(|subpath| subpath.points.clone())
+                .collect();
+            paint(&mut layer, &polygons, fill);
+        }
+        if let Some(stroke) = command.style.stroke {
+            let mut polygons = Vec::new();
+            for subpath in &outline {
+                stroke_polygons(&mut polygons, subpath, command.style.width / 2.0);
+            }
+            if let Shape::Arrow { from, to, head } = command.shape {
+                polygons.push(arrow_head(from, to, head));
+            }
+            paint(&mut layer, &polygons, stroke);
+        }
+    }
+    let options = CompositeOptions {
+        linear,
+        ..CompositeOptions::default()
+    };
+    composite::composite(img, &DynamicImage::ImageRgba8(layer), &options)
+}
+
+// A stroke is the union of one quad per segment plus a disc at every point, which gives round
+// joins and caps.  They all wind the same way so the rasterizer adds them up instead of
+// cancelling them out.
+fn stroke_polygons(polygons: &mut Vec<Vec<P>>, subpath: &Subpath, half: f32) {
+    let mut points = subpath.points.clone();
+    if subpath.closed {
+        points.push(points[0]);
+    }
+    for pair in points.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let length = distance(a, b);
+        if length == 0.0 {
+            continue;
+        }
+        let nx = -(b.1 - a.1) / length * half;
+        let ny = (b.0 - a.0) / length * half;
+        polygons.push(positive_winding(vec![
+            (a.0 + nx, a.1 + ny),
+            (b.0 + nx, b.1 + ny),
+            (b.0 - nx, b.1 - ny),
+            (a.0 - nx, a.1 - ny),
+        ]));
+    }
+    for p in points {
+        polygons.push(positive_winding(ellipse(p, (half, half), 0.0, 2.0 * PI)));
+    }
+}
+
+fn arrow_head(from: P, to: P, size: f32) -> Vec<P> {
+    let length = distance(from, to).max(f32::EPSILON);
+    let (dx, dy) = ((to.0 - from.0) / length, (to.1 - from.1) / length);
+    let back = (to.0 - dx * size, to.1 - dy * size);
+    let spread = size * 0.5;
+    positive_winding(vec![
+        (to.0 + dx * size * 0.25, to.1 + dy * size * 0.25),
+        (back.0 - dy * spread, back.1 + dx * spread),
+        (back.0 + dy * spread, back.1 - dx * spread),
+    ])
+}
+
+fn positive_winding(mut polygon: Vec<P>) -> Vec<P> {
+    let area: f32 = polygon
+        .iter()
+        .zip(polygon.iter().cycle().skip(1))
+        .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+        .sum();
+    if area < 0.0 {
+        polygon.reverse();
+    }
+    polygon
+}
+
+// Fill polygons with a color (nonzero-ish winding: overlaps don't cancel as long as every polygon
+// winds the same way), painting over what is already in the layer.
+fn paint(layer: &mut RgbaImage, polygons: &[Vec<P>], color: Rgba<u8>) {
+    let (width, height) = layer.dimensions();
+    // The rasterizer keeps a running sum along each row and wraps from one row into the next, so
+    // it must never see anything outside the image horizontally.  Clip every edge to [0, width],
+    // and give it one spare column on each side for the coverage that spills past an edge.
+    let mut rasterizer = Rasterizer::new(width as usize + 3, height as usize);
+    let limit = width as f32;
+    for polygon in polygons {
+        for (a, b) in polygon.iter().zip(polygon.iter().cycle().skip(1)) {
+            for (a, b) in clip_horizontally(*a, *b, limit) {
+                rasterizer.draw_line(point(a.0 + 1.0, a.1), point(b.0 + 1.0, b.1));
+            }
+        }
+    }
+    rasterizer.for_each_pixel_2d(|x, y, coverage| {
+        if x == 0 || x > width || coverage <= 0.0 {
+            return;
+        }
+        let pixel = layer.get_pixel_mut(x - 1, y);
+        let a = color[3] as f32 / 255.0 * coverage.min(1.0);
+        let below = pixel[3] as f32 / 255.0;
+        let out = a + below * (1.0 - a);
+        for c in 0..3 {
+            let value = (color[c] as f32 * a + pixel[c] as f32 * below * (1.0 - a)) / out;
+            pixel[c] = value.round() as u8;
+        }
+        pixel[3] = (out * 255.0).round() as u8;
+    });
+}
+
+// Split an edge where it crosses x = 0 and x = limit, and squash the pieces outside onto those
+// lines.  The parts of a shape off to the side then still count for the coverage inside.
+fn clip_horizontally(a: P, b: P, limit: f32) -> Vec<(P, P)> {
+    let mut cuts = vec![0.0, 1.0];
+    for edge in [0.0, limit] {
+        let t = (edge - a.0) / (b.0 - a.0);
+        if t > 0.0 && t < 1.0 {
+            cuts.push(t);
+        }
+    }
+    cuts.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    let at = |t: f32| {
+        (
+            (a.0 + (b.0 - a.0) * t).clamp(0.0, limit),
+            a.1 + (b.1 - a.1) * t,
+        )
+    };
+    cuts.windows(2).map(|w| (at(w[0]), at(w[1]))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn parses_shapes_and_styles() {
+        let command = Command::parse("rect 1,2 30,40 radius=5 fill=white width=3").unwrap();
+        assert_eq!(
+            command.shape,
+            Shape::Rect {
+                corner: (1.0, 2.0),
+                size: (30.0, 40.0),
+                radius: 5.0
+            }
+        );
+        // Filled shapes only get an outline when one is asked for.
+        assert_eq!(command.style.fill, Some(Rgba([255, 255, 255, 255])));
+        assert_eq!(command.style.stroke, None);
+        assert_eq!(command.style.width, 3.0);
+
+        let command = Command::parse("circle 5,5 2").unwrap();
+        assert_eq!(
+            command.shape,
+            Shape::Ellipse {
+                center: (5.0, 5.0),
+                radii: (2.0, 2.0)
+            }
+        );
+        assert_eq!(command.style.stroke, Some(Rgba([255, 0, 0, 255])));
+        assert!(matches!(
+            Command::parse("polyline 0,0 1,1 2,0").unwrap().shape,
+            Shape::Polyline(points) if points.len() == 3
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        for line in [
+            "",
+            "blob 1,1",
+            "line 1,2",
+            "line 0,0 1,1 size=3",
+            "circle 1,2 x",
+            "rect 0,0 1,1 fill=nope",
+            "polygon 0,0 1,1",
+            "path \"10 10\"",
+            "path \"M 10\"",
+            "path \"M 0 0 A 1 1 0 0 0 5 5\"",
+        ] {
+            assert!(Command::parse(line).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn splits_words_keeping_quotes_together() {
+        assert_eq!(
+            split_words(r#"path "M 0 0 L 1 1"  fill=red"#),
+            ["path", "M 0 0 L 1 1", "fill=red"]
+        );
+    }
+
+    #[test]
+    fn parses_path_data() {
+        let square = vec![Subpath {
+            points: vec![(10.0, 10.0), (20.0, 10.0), (20.0, 20.0)],
+            closed: true,
+        }];
+        assert_eq!(parse_path("M 10 10 L 20 10 20 20 Z").unwrap(), square);
+        assert_eq!(parse_path("m10,10 h10 v10 z").unwrap(), square);
+        // Numbers can run together when the sign shows where the next one starts.
+        assert_eq!(
+            parse_path("M10-5L-3e1,2").unwrap(),
+            vec![Subpath {
+                points: vec![(10.0, -5.0), (-30.0, 2.0)],
+                closed: false,
+            }]
+        );
+        let curve = parse_path("M 0 0 Q 5 10 10 0").unwrap();
+        assert_eq!(curve[0].points.first(), Some(&(0.0, 0.0)));
+        assert_eq!(curve[0].points.last(), Some(&(10.0, 0.0)));
+    }
+
+    #[test]
+    fn numbers_after_close_are_rejected() {
+        assert_eq!(
+            parse_path("M 0 0 L 10 0 L 10 10 Z 5 5"),
+            Err("Z takes no arguments".to_string())
+        );
+        // A new command after Z is fine
+        assert_eq!(parse_path("M 0 0 L 10 0 Z M 5 5 L 6 6").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn fills_exact_pixels() {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(8, 8));
+        let command = Command::parse("rect 2,2 4,4 fill=white").unwrap();
+        let drawn = draw(&img, &[command], false).to_rgb8();
+        let white = Rgb([255, 255, 255]);
+        let black = Rgb([0, 0, 0]);
+        assert_eq!(drawn.get_pixel(2, 2), &white);
+        assert_eq!(drawn.get_pixel(5, 5), &white);
+        assert_eq!(drawn.get_pixel(1, 1), &black);
+        assert_eq!(drawn.get_pixel(6, 6), &black);
+    }
+}
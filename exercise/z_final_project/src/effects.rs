@@ -0,0 +1,285 @@
+// Creative effects, for the looks that get asked for over and over.
+//
+// - Vignette: darkens towards the corners.  `amount` is how dark the corners get (1 is black), and
+//   `falloff` the power of the distance from the center, so higher values keep more of the middle
+//   bright.  Oval on oblong images, to follow their shape.
+// - Grain: film-like noise, the same in every channel so it doesn't add color.  `amount` is its
+//   standard deviation in 8-bit levels.  The noise is worked out from `seed` and each pixel's
+//   position, so the same seed always gives the same grain.  It's always added to the sRGB values,
+//   even with --linear, so that it shows about as much in the shadows as in the highlights.
+// - Pixelate: averages square blocks of `size` pixels, from the top left corner.
+// - Oil paint: the Kuwahara filter.  Each pixel becomes the mean color of whichever of the four
+//   squares of `radius` with a corner on it is the most even in brightness, which flattens areas
+//   into strokes and keeps edges hard.  Means and variances come from summed-area tables, so any
+//   radius takes the same time.
+// - Halftone: the image as cyan, magenta, yellow and black dots on white paper, the way it would
+//   be printed.  Dots are on grids of `size` pixels turned to the usual screen angles (15, 75, 0
+//   and 45 degrees) plus `angle`, and each dot covers as much of its cell as there is ink there.
+
+use crate::parallel;
+use image::Rgba32FImage;
+use std::f64::consts::PI;
+
+// A copy of the image with `f(x, y, pixel)` run on every pixel, on all cores.
+fn for_each_pixel(img: &Rgba32FImage, f: impl Fn(u32, u32, &mut [f32]) + Sync) -> Rgba32FImage {
+    let mut out = img.clone();
+    let width = img.width() as usize;
+    parallel::for_each_row(&mut out, width * 4, |y, row| {
+        for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+            f(x as u32, y as u32, pixel);
+        }
+    });
+    out
+}
+
+pub fn vignette(img: &Rgba32FImage, amount: f32, falloff: f32) -> Rgba32FImage {
+    let (cx, cy) = (img.width() as f32 / 2.0, img.height() as f32 / 2.0);
+    for_each_pixel(img, |x, y, pixel| {
+        // 0 at the center and 1 in the corners
+        let dx = (x as f32 + 0.5 - cx) / cx;
+        let dy = (y as f32 + 0.5 - cy) / cy;
+        let distance = ((dx * dx + dy * dy) / 2.0).sqrt();
+        let factor = (1.0 - amount * distance.powf(falloff)).clamp(0.0, 1.0);
+        for value in &mut pixel[..3] {
+            *value *= factor;
+        }
+    })
+}
+
+pub fn grain(img: &Rgba32FImage, amount: f32, seed: u64) -> Rgba32FImage {
+    let amount = amount / 255.0;
+    for_each_pixel(img, |x, y, pixel| {
+        let noise = amount * gaussian(seed, x, y);
+        for value in &mut pixel[..3] {
+            *value = (*value + noise).clamp(0.0, 1.0);
+        }
+    })
+}
+
+// A normally distributed number for a pixel: the same every time for the same seed and pixel.
+fn gaussian(seed: u64, x: u32, y: u32) -> f32 {
+    let a = mix(seed ^ mix(((y as u64) << 32) | x as u64));
+    let b = mix(a);
+    // Box-Muller, with the first number kept away from 0
+    let u1 = ((a >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    let u2 = (b >> 11) as f64 / (1u64 << 53) as f64;
+    ((-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()) as f32
+}
+
+// SplitMix64's mixing function, which turns nearby numbers into unrelated ones.
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Expects premultiplied alpha, so transparent pixels don't darken their blocks.
+pub fn pixelate(img: &Rgba32FImage, size: u32) -> Rgba32FImage {
+    let size = size.max(1);
+    let mut out = img.clone();
+    for by in (0..img.height()).step_by(size as usize) {
+        for bx in (0..img.width()).step_by(size as usize) {
+            let (w, h) = (size.min(img.width() - bx), size.min(img.height() - by));
+            let mut sum = [0.0f64; 4];
+            for y in by..by + h {
+                for x in bx..bx + w {
+                    let p = img.get_pixel(x, y);
+                    for c in 0..4 {
+                        sum[c] += p[c] as f64;
+                    }
+                }
+            }
+            let count = (w * h) as f64;
+            let mean = image::Rgba(sum.map(|s| (s / count) as f32));
+            for y in by..by + h {
+                for x in bx..bx + w {
+                    out.put_pixel(x, y, mean);
+                }
+            }
+        }
+    }
+    out
+}
+
+// Sums over rectangles in constant time: entry (x, y) holds the sum of everything above and to the
+// left of pixel x, y.
+struct SummedArea {
+    width: usize,
+    sums: Vec<f64>,
+}
+
+impl SummedArea {
+    fn new(width: usize, height: usize, value: impl Fn(usize, usize) -> f64) -> SummedArea {
+        let stride = width + 1;
+        let mut sums = vec![0.0; stride * (height + 1)];
+        for y in 0..height {
+            let mut row = 0.0;
+            for x in 0..width {
+                row += value(x, y);
+                sums[(y + 1) * stride + x + 1] = sums[y * stride + x + 1] + row;
+            }
+        }
+        SummedArea { width, sums }
+    }
+
+    // The sum over columns x0..x1 and rows y0..y1 (not including x1 and y1).
+    fn sum(&self, (x0, y0): (usize, usize), (x1, y1): (usize, usize)) -> f64 {
+        let stride = self.width + 1;
+        self.sums[y1 * stride + x1] - self.sums[y0 * stride + x1] - self.sums[y1 * stride + x0]
+            + self.sums[y0 * stride + x0]
+    }
+}
+
+pub fn oil_paint(img: &Rgba32FImage, radius: u32) -> Rgba32FImage {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let value = |x: usize, y: usize, c: usize| img.get_pixel(x as u32, y as u32)[c] as f64;
+    let luma = |x: usize, y: usize| {
+        0.2126 * value(x, y, 0) + 0.7152 * value(x, y, 1) + 0.0722 * value(x, y, 2)
+    };
+    let channels: Vec<SummedArea> = (0..3)
+        .map(|c| SummedArea::new(width, height, |x, y| value(x, y, c)))
+        .collect();
+    let brightness = SummedArea::new(width, height, luma);
+    let squares = SummedArea::new(width, height, |x, y| luma(x, y).powi(2));
+
+    let r = radius as usize;
+    for_each_pixel(img, |x, y, pixel| {
+        let (x, y) = (x as usize, y as usize);
+        let (left, top) = (x.saturating_sub(r), y.saturating_sub(r));
+        let (right, bottom) = ((x + r + 1).min(width), (y + r + 1).min(height));
+        let quadrants = [
+            ((left, top), (x + 1, y + 1)),
+            ((x, top), (right, y + 1)),
+            ((left, y), (x + 1, bottom)),
+            ((x, y), (right, bottom)),
+        ];
+        let (from, to) = quadrants
+            .into_iter()
+            .min_by(|&(from, to), &(from2, to2)| {
+                let variance = |from: (usize, usize), to: (usize, usize)| {
+                    let count = ((to.0 - from.0) * (to.1 - from.1)) as f64;
+                    let mean = brightness.sum(from, to) / count;
+                    squares.sum(from, to) / count - mean * mean
+                };
+                variance(from, to).total_cmp(&variance(from2, to2))
+            })
+            .unwrap();
+        let count = ((to.0 - from.0) * (to.1 - from.1)) as f64;
+        for (c, sums) in channels.iter().enumerate() {
+            pixel[c] = (sums.sum(from, to) / count) as f32;
+        }
+    })
+}
+
+// The screen angle of each ink, in degrees: cyan, magenta, yellow, black
+const SCREEN_ANGLES: [f64; 4] = [15.0, 75.0, 0.0, 45.0];
+
+pub fn halftone(img: &Rgba32FImage, size: f32, angle: f32) -> Rgba32FImage {
+    let (width, height) = (img.width() as i64, img.height() as i64);
+    let size = (size as f64).max(1.0);
+    let inks: Vec<[f64; 4]> = img.pixels().map(|p| cmyk(p.0)).collect();
+    let ink_at = |x: f64, y: f64, ink: usize| {
+        let x = (x.floor() as i64).clamp(0, width - 1);
+        let y = (y.floor() as i64).clamp(0, height - 1);
+        inks[(y * width + x) as usize][ink]
+    };
+
+    for_each_pixel(img, |x, y, pixel| {
+        let (px, py) = (x as f64 + 0.5, y as f64 + 0.5);
+        let mut coverage = [0.0; 4];
+        for (ink, screen) in SCREEN_ANGLES.iter().enumerate() {
+            let (sin, cos) = ((screen + angle as f64) * PI / 180.0).sin_cos();
+            // Position on the turned grid
+            let u = px * cos + py * sin;
+            let v = -px * sin + py * cos;
+            let (cell_u, cell_v) = ((u / size).floor(), (v / size).floor());
+            // Big dots reach into the cells around theirs.
+            for du in -1..=1 {
+                for dv in -1..=1 {
+                    let cu = (cell_u + du as f64 + 0.5) * size;
+                    let cv = (cell_v + dv as f64 + 0.5) * size;
+                    let amount = ink_at(cu * cos - cv * sin, cu * sin + cv * cos, ink);
+                    // A dot with the cell's share of ink, with its edge anti-aliased
+                    let radius = size * (amount / PI).sqrt();
+                    let distance = ((u - cu).powi(2) + (v - cv).powi(2)).sqrt();
+                    let covered = (radius - distance + 0.5).clamp(0.0, 1.0);
+                    coverage[ink] = f64::max(coverage[ink], covered);
+                }
+            }
+        }
+        let [c, m, y, k] = coverage;
+        pixel[0] = ((1.0 - c) * (1.0 - k)) as f32;
+        pixel[1] = ((1.0 - m) * (1.0 - k)) as f32;
+        pixel[2] = ((1.0 - y) * (1.0 - k)) as f32;
+    })
+}
+
+// How much cyan, magenta, yellow and black ink an RGB color takes, from 0 to 1, using as much
+// black as possible.
+fn cmyk(p: [f32; 4]) -> [f64; 4] {
+    let (r, g, b) = (p[0] as f64, p[1] as f64, p[2] as f64);
+    let k = 1.0 - r.max(g).max(b);
+    if k >= 1.0 {
+        return [0.0, 0.0, 0.0, 1.0];
+    }
+    let ink = |v: f64| (1.0 - v - k) / (1.0 - k);
+    [ink(r), ink(g), ink(b), k]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn pattern(width: u32, height: u32) -> Rgba32FImage {
+        Rgba32FImage::from_fn(width, height, |x, y| {
+            Rgba([x as f32 / width as f32, y as f32 / height as f32, 0.5, 1.0])
+        })
+    }
+
+    #[test]
+    fn grain_depends_only_on_the_seed() {
+        let img = pattern(16, 12);
+        assert_eq!(grain(&img, 20.0, 7), grain(&img, 20.0, 7));
+        assert_ne!(grain(&img, 20.0, 7), grain(&img, 20.0, 8));
+        assert_eq!(grain(&img, 0.0, 7), img);
+    }
+
+    #[test]
+    fn grain_has_the_asked_for_spread() {
+        let img = Rgba32FImage::from_pixel(64, 64, Rgba([0.5, 0.5, 0.5, 1.0]));
+        let grainy = grain(&img, 16.0, 1);
+        let noise: Vec<f32> = grainy.pixels().map(|p| (p[0] - 0.5) * 255.0).collect();
+        let mean = noise.iter().sum::<f32>() / noise.len() as f32;
+        let deviation =
+            (noise.iter().map(|n| (n - mean).powi(2)).sum::<f32>() / noise.len() as f32).sqrt();
+        assert!(mean.abs() < 1.0, "{}", mean);
+        assert!((deviation - 16.0).abs() < 1.0, "{}", deviation);
+        // The same in every channel, and alpha alone
+        assert!(grainy
+            .pixels()
+            .all(|p| p[0] == p[1] && p[1] == p[2] && p[3] == 1.0));
+    }
+
+    #[test]
+    fn pixelate_averages_blocks() {
+        let img = pattern(5, 3);
+        assert_eq!(pixelate(&img, 1), img);
+        let blocky = pixelate(&img, 2);
+        // The top left block covers columns 0 and 1; the last column is a block of its own
+        assert_eq!(blocky.get_pixel(1, 1)[0], 0.1);
+        assert_eq!(blocky.get_pixel(4, 0)[0], 0.8);
+        assert_eq!(blocky.get_pixel(0, 2)[1], 2.0 / 3.0);
+    }
+
+    #[test]
+    fn vignette_darkens_the_corners() {
+        let img = Rgba32FImage::from_pixel(9, 9, Rgba([1.0, 1.0, 1.0, 1.0]));
+        assert_eq!(vignette(&img, 0.0, 2.0), img);
+        let dark = vignette(&img, 1.0, 2.0);
+        assert_eq!(dark.get_pixel(4, 4)[0], 1.0);
+        assert!(dark.get_pixel(0, 0)[0] < 0.25);
+        assert_eq!(dark.get_pixel(0, 0)[3], 1.0);
+    }
+}
@@ -0,0 +1,119 @@
+// Linear-light processing.
+//
+// Pixel values in ordinary image files are sRGB-encoded: they are roughly the square root of the
+// actual light intensity, which spends more bits on dark tones.  Averaging encoded values (which
+// is what blurring, resizing and blending all do) therefore comes out too dark, and high-contrast
+// edges get dark halos.  With --linear, operations decode to linear light first, do their math,
+// and encode back to sRGB at the end.  Alpha is never gamma-encoded and is left alone.
+
+use crate::{depth, parallel};
+use image::{DynamicImage, Rgba, Rgba32FImage};
+
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+pub fn decode_pixel(pixel: Rgba<f32>) -> Rgba<f32> {
+    let [r, g, b, a] = pixel.0;
+    Rgba([srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a])
+}
+
+pub fn encode_pixel(pixel: Rgba<f32>) -> Rgba<f32> {
+    let [r, g, b, a] = pixel.0;
+    Rgba([linear_to_srgb(r), linear_to_srgb(g), linear_to_srgb(b), a])
+}
+
+// Decode to floating-point linear light.
+pub fn linearize(img: &DynamicImage) -> Rgba32FImage {
+    let mut img = img.to_rgba32f();
+    let width = img.width() as usize;
+    parallel::for_each_pixel(&mut img, width, |pixel| *pixel = decode_pixel(*pixel));
+    img
+}
+
+// Encode linear light back to sRGB, in the given color type.
+pub fn delinearize(mut img: Rgba32FImage, color: image::ColorType) -> DynamicImage {
+    let width = img.width() as usize;
+    parallel::for_each_pixel(&mut img, width, |pixel| *pixel = encode_pixel(*pixel));
+    depth::convert(DynamicImage::ImageRgba32F(img), color)
+}
+
+// Run `op` on the image, in linear light if `linear` is set.  The result comes back sRGB-encoded
+// at the input's depth (so a blur of an 8-bit image is still 8-bit), with whatever channels the
+// operation kept.
+pub fn in_linear_light(
+    img: &DynamicImage,
+    linear: bool,
+    op: impl FnOnce(&DynamicImage) -> DynamicImage,
+) -> DynamicImage {
+    if !linear {
+        return op(img);
+    }
+    let result = op(&DynamicImage::ImageRgba32F(linearize(img)));
+    let color = depth::color_type(
+        depth::bits(img.color()),
+        result.color().has_color() && img.color().has_color(),
+        result.color().has_alpha() && img.color().has_alpha(),
+    );
+    delinearize(result.into_rgba32f(), color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    #[test]
+    fn every_8_bit_level_round_trips() {
+        for level in 0..=255u8 {
+            let value = level as f32 / 255.0;
+            let back = linear_to_srgb(srgb_to_linear(value));
+            assert_eq!((back * 255.0).round() as u8, level);
+        }
+    }
+
+    #[test]
+    fn every_16_bit_level_round_trips() {
+        for level in 0..=65535u16 {
+            let value = level as f32 / 65535.0;
+            let back = linear_to_srgb(srgb_to_linear(value));
+            assert_eq!((back * 65535.0).round() as u16, level);
+        }
+    }
+
+    #[test]
+    fn images_round_trip_at_their_depth() {
+        let eight = DynamicImage::ImageRgb8(ImageBuffer::from_fn(16, 16, |x, y| {
+            Rgb([(x * 16 + y) as u8, 255 - (y * 16 + x) as u8, 128])
+        }));
+        let sixteen = DynamicImage::ImageRgb16(ImageBuffer::from_fn(16, 16, |x, y| {
+            Rgb([(x * 4096 + y * 17) as u16, (y * 4000 + x) as u16, 40000])
+        }));
+        for img in [eight, sixteen] {
+            let back = delinearize(linearize(&img), img.color());
+            assert_eq!(back, img);
+            assert_eq!(in_linear_light(&img, true, |img| img.clone()), img);
+        }
+    }
+
+    #[test]
+    fn midpoints_and_alpha() {
+        // Middle gray in sRGB is about a fifth of the light
+        assert!((srgb_to_linear(0.5) - 0.214).abs() < 0.001);
+        let pixel = decode_pixel(Rgba([1.0, 0.0, 0.5, 0.25]));
+        assert_eq!(pixel[3], 0.25);
+        assert_eq!(encode_pixel(pixel)[3], 0.25);
+    }
+}
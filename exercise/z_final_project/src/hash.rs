@@ -0,0 +1,243 @@
+// Perceptual hashes: 64-bit fingerprints that stay (nearly) the same when an image is resized,
+// re-encoded or slightly recolored, so near-duplicates can be found by counting differing bits.
+//
+// - aHash: shrink to 8x8 gray, one bit per pixel: is it brighter than the mean?
+// - dHash: shrink to 9x8 gray, one bit per pixel: is it brighter than its right neighbor?
+// - pHash: shrink to 32x32 gray, take the DCT, and keep one bit per low frequency (the top-left
+//   8x8 coefficients): is it above the median?  The most robust of the three.
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Average,
+    Difference,
+    Perceptual,
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "ahash" => Some(Algorithm::Average),
+            "dhash" => Some(Algorithm::Difference),
+            "phash" => Some(Algorithm::Perceptual),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Average => "ahash",
+            Algorithm::Difference => "dhash",
+            Algorithm::Perceptual => "phash",
+        }
+    }
+
+    pub fn hash(self, img: &DynamicImage) -> u64 {
+        match self {
+            Algorithm::Average => average_hash(img),
+            Algorithm::Difference => difference_hash(img),
+            Algorithm::Perceptual => perceptual_hash(img),
+        }
+    }
+}
+
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// Gray values of the image squeezed (ignoring aspect ratio) to width x height.
+fn shrink(img: &DynamicImage, width: u32, height: u32) -> Vec<f64> {
+    img.resize_exact(width, height, FilterType::Triangle)
+        .to_luma32f()
+        .pixels()
+        .map(|p| p[0] as f64)
+        .collect()
+}
+
+fn bits(values: impl Iterator<Item = bool>) -> u64 {
+    values.fold(0, |hash, bit| hash << 1 | bit as u64)
+}
+
+pub fn average_hash(img: &DynamicImage) -> u64 {
+    let pixels = shrink(img, 8, 8);
+    let mean = pixels.iter().sum::<f64>() / 64.0;
+    bits(pixels.iter().map(|&p| p > mean))
+}
+
+pub fn difference_hash(img: &DynamicImage) -> u64 {
+    let pixels = shrink(img, 9, 8);
+    bits(
+        pixels
+            .chunks(9)
+            .flat_map(|row| row.windows(2).map(|pair| pair[0] > pair[1])),
+    )
+}
+
+pub fn perceptual_hash(img: &DynamicImage) -> u64 {
+    const N: usize = 32;
+    let pixels = shrink(img, N as u32, N as u32);
+
+    // DCT-II, only for the 8x8 lowest frequencies since that's all we keep.  The normalization
+    // factors don't matter because we only compare coefficients with each other.
+    let cosines: Vec<Vec<f64>> = (0..8)
+        .map(|k| {
+            (0..N)
+                .map(|n| (PI / N as f64 * (n as f64 + 0.5) * k as f64).cos())
+                .collect()
+        })
+        .collect();
+    let mut coefficients = Vec::with_capacity(64);
+    for u in 0..8 {
+        for v in 0..8 {
+            let mut sum = 0.0;
+            for y in 0..N {
+                for x in 0..N {
+                    sum += pixels[y * N + x] * cosines[u][y] * cosines[v][x];
+                }
+            }
+            coefficients.push(sum);
+        }
+    }
+
+    // The DC term (the overall brightness) would throw off the median, so leave it out.
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = sorted[sorted.len() / 2];
+    bits(coefficients.iter().map(|&c| c > median))
+}
+
+// All files under `dir` (recursively) whose extension looks like an image format we can read.
+// Symlinked directories aren't followed, so a link back up the tree can't send this round in
+// circles.
+pub fn find_images(dir: &Path) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Skipping {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                pending.push(path);
+            } else if image::ImageFormat::from_path(&path).is_ok() {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+// Group hashes that are within `max_distance` bits of each other, directly or through a chain of
+// similar images.  Returns the indices of each group with more than one member.
+pub fn group_duplicates(hashes: &[u64], max_distance: u32) -> Vec<Vec<usize>> {
+    // Union-find over all pairs; comparing 64-bit hashes is cheap enough that this is fine even for
+    // libraries with thousands of photos.
+    let mut parent: Vec<usize> = (0..hashes.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for i in 0..hashes.len() {
+        for j in i + 1..hashes.len() {
+            if distance(hashes[i], hashes[j]) <= max_distance {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = vec![Vec::new(); hashes.len()];
+    for i in 0..hashes.len() {
+        let r = root(&mut parent, i);
+        groups[r].push(i);
+    }
+    groups.retain(|group| group.len() > 1);
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    // Dark on the left and bright on the right, or the other way round
+    fn halves(dark_left: bool) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, _| {
+            Luma([if (x < 32) == dark_left { 0 } else { 255 }])
+        }))
+    }
+
+    fn gradient(rising: bool) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(90, 40, |x, _| {
+            Luma([if rising { x * 2 } else { 180 - x * 2 } as u8])
+        }))
+    }
+
+    // Something with detail at several scales
+    fn pattern() -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(96, 96, |x, y| {
+            let (x, y) = (x as f32, y as f32);
+            let value = 128.0
+                + 60.0 * (x / 9.0).sin() * (y / 13.0).cos()
+                + 50.0 * (x / 40.0 - y / 30.0).sin();
+            Luma([value as u8])
+        }))
+    }
+
+    #[test]
+    fn average_hash_marks_the_bright_half() {
+        assert_eq!(average_hash(&halves(true)), 0x0f0f_0f0f_0f0f_0f0f);
+        assert_eq!(average_hash(&halves(false)), 0xf0f0_f0f0_f0f0_f0f0);
+    }
+
+    #[test]
+    fn difference_hash_follows_the_slope() {
+        assert_eq!(difference_hash(&gradient(true)), 0);
+        assert_eq!(difference_hash(&gradient(false)), u64::MAX);
+    }
+
+    #[test]
+    fn perceptual_hash_survives_resizing_but_not_inverting() {
+        let img = pattern();
+        let hash = perceptual_hash(&img);
+        let smaller = img.resize_exact(70, 60, FilterType::Lanczos3);
+        assert!(distance(hash, perceptual_hash(&smaller)) <= 4);
+        let mut inverted = img.clone();
+        inverted.invert();
+        assert!(distance(hash, perceptual_hash(&inverted)) >= 56);
+    }
+
+    #[test]
+    fn groups_chains_of_near_duplicates() {
+        let hashes = [0, 1, 3, 0xff00, 0xff01, u64::MAX];
+        assert_eq!(group_duplicates(&hashes, 1), [vec![0, 1, 2], vec![3, 4]]);
+        assert_eq!(group_duplicates(&hashes, 0), Vec::<Vec<usize>>::new());
+        assert_eq!(group_duplicates(&hashes, 64), [vec![0, 1, 2, 3, 4, 5]]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finds_images_without_following_symlink_loops() {
+        let dir = std::env::temp_dir().join(format!("mirage-hash-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("inner")).unwrap();
+        std::fs::write(dir.join("inner/a.png"), b"").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"").unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("inner/loop")).unwrap();
+        let found = find_images(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found, [dir.join("inner/a.png")]);
+    }
+}
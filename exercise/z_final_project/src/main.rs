@@ -25,6 +25,34 @@
 //
 //     let positive_number: u32 = some_string.parse().expect("Failed to parse a number");
 
+mod alpha;
+mod analysis;
+mod blobs;
+mod blur;
+mod carve;
+mod codec;
+mod color;
+mod compare;
+mod composite;
+mod denoise;
+mod depth;
+mod draw;
+mod effects;
+mod gamma;
+mod hash;
+mod montage;
+mod morphology;
+mod ops;
+mod parallel;
+mod preview;
+mod resize;
+mod serve;
+mod shell;
+mod stream;
+mod text;
+mod warp;
+mod watch;
+
 fn main() {
     // 1. First, you need to implement some basic command-line argument handling
     // so you can make your program do different things.  Here's a little bit
@@ -36,34 +64,363 @@ fn main() {
     if args.is_empty() {
         print_usage_and_exit();
     }
+    let settings = take_settings(&mut args);
     let subcommand = args.remove(0);
     match subcommand.as_str() {
         // EXAMPLE FOR CONVERSION OPERATIONS
         "blur" => {
+            let passes = take_option(&mut args, "--passes").map(|passes| {
+                passes
+                    .parse::<u32>()
+                    .expect("Failed to parse the number of passes")
+            });
+            if args.len() != 2 && args.len() != 3 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            let sigma = match args.pop() {
+                Some(sigma) => sigma
+                    .parse::<f32>()
+                    .expect("Failed to parse the blur sigma"),
+                None => 2.0,
+            };
+            let method = match passes {
+                Some(0) => blur::Method::Gaussian,
+                Some(passes) => blur::Method::Box(passes),
+                None => blur::Method::default_for(sigma),
+            };
+            blur(infile, outfile, sigma, method, &settings);
+        }
+
+        // Color adjustments, which also work on images too big for memory with --tiled
+        "brighten" => {
+            if args.len() != 3 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            let amount = args.remove(0).parse().expect("Failed to parse the amount");
+            brighten(infile, outfile, amount, &settings);
+        }
+
+        // Cut out the rectangle at X,Y
+        "crop" => {
+            if args.len() != 4 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            let (x, y) = parse_pair(&args.remove(0));
+            let (width, height) = parse_size(&args.remove(0));
+            let (x, y) = (x.max(0) as u32, y.max(0) as u32);
+            crop(infile, outfile, (x, y, width, height), &settings);
+        }
+
+        // Scale to an exact size, or to a width or height with the other one 0
+        "resize" => {
+            if args.len() != 3 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            let size = parse_size(&args.remove(0));
+            resize(infile, outfile, size, &settings);
+        }
+
+        "invert" => {
             if args.len() != 2 {
                 print_usage_and_exit();
             }
             let infile = args.remove(0);
             let outfile = args.remove(0);
-            // **OPTION**
-            // Improve the blur implementation -- see the blur() function below
-            blur(infile, outfile);
+            invert(infile, outfile, &settings);
+        }
+
+        "grayscale" => {
+            if args.len() != 2 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            grayscale(infile, outfile, &settings);
+        }
+
+        // Any angle, clockwise; right angles come out exact
+        "rotate" => {
+            let interpolation = take_option(&mut args, "--interpolation").map(|name| {
+                warp::Interpolation::from_name(&name).unwrap_or_else(|| {
+                    println!(
+                        "Unknown interpolation. Use one of: {}",
+                        warp::Interpolation::NAMES
+                    );
+                    std::process::exit(-1);
+                })
+            });
+            if args.len() != 3 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            let degrees = args.remove(0).parse().expect("Failed to parse the angle");
+            let interpolation = interpolation.unwrap_or(warp::Interpolation::Bilinear);
+            rotate(infile, outfile, degrees, interpolation, &settings);
+        }
+
+        // Put one image on top of another, e.g. for watermarks and badges
+        "composite" => {
+            let mut options = composite::CompositeOptions {
+                linear: settings.linear,
+                ..composite::CompositeOptions::default()
+            };
+            if let Some(mode) = take_option(&mut args, "--mode") {
+                options.mode = composite::BlendMode::from_name(&mode).unwrap_or_else(|| {
+                    println!(
+                        "Unknown blend mode. Use one of: {}",
+                        composite::BlendMode::NAMES
+                    );
+                    std::process::exit(-1);
+                });
+            }
+            if let Some(anchor) = take_option(&mut args, "--anchor") {
+                options.anchor = parse_anchor(&anchor);
+            }
+            if let Some(opacity) = take_option(&mut args, "--opacity") {
+                options.opacity = opacity.parse().expect("Failed to parse the opacity");
+                if !(0.0..=1.0).contains(&options.opacity) {
+                    println!("The opacity must be from 0 to 1");
+                    std::process::exit(-1);
+                }
+            }
+            if let Some(pos) = take_option(&mut args, "--pos") {
+                options.offset = parse_pair(&pos);
+            }
+            if args.len() != 3 {
+                print_usage_and_exit();
+            }
+            let base = args.remove(0);
+            let overlay = args.remove(0);
+            let outfile = args.remove(0);
+            composite(base, overlay, outfile, options, &settings);
+        }
+
+        // Captions and labels
+        "text" => {
+            let mut options = text::TextOptions {
+                linear: settings.linear,
+                ..text::TextOptions::default()
+            };
+            let font = match take_option(&mut args, "--font") {
+                Some(path) => text::Font::load(&path).expect("Failed to load the font"),
+                None => text::Font::Bitmap,
+            };
+            if let Some(size) = take_option(&mut args, "--size") {
+                options.size = size.parse().expect("Failed to parse the text size");
+            }
+            if let Some(color) = take_option(&mut args, "--color") {
+                options.color = color::parse_color(&color).expect("Failed to parse the color");
+            }
+            if let Some(anchor) = take_option(&mut args, "--anchor") {
+                options.anchor = parse_anchor(&anchor);
+            }
+            if let Some(pos) = take_option(&mut args, "--pos") {
+                options.offset = parse_pair(&pos);
+            }
+            if let Some(align) = take_option(&mut args, "--align") {
+                options.align = text::Align::from_name(&align).unwrap_or_else(|| {
+                    println!("Unknown alignment. Use one of: left, center, right");
+                    std::process::exit(-1);
+                });
+            }
+            if let Some(width) = take_option(&mut args, "--width") {
+                options.width = Some(width.parse().expect("Failed to parse the width"));
+            }
+            if let Some(outline) = take_option(&mut args, "--outline") {
+                options.outline = outline.parse().expect("Failed to parse the outline width");
+            }
+            if let Some(color) = take_option(&mut args, "--outline-color") {
+                options.outline_color =
+                    color::parse_color(&color).expect("Failed to parse the outline color");
+            }
+            if let Some(shadow) = take_option(&mut args, "--shadow") {
+                options.shadow = Some(parse_pair(&shadow));
+            }
+            if let Some(color) = take_option(&mut args, "--shadow-color") {
+                options.shadow_color =
+                    color::parse_color(&color).expect("Failed to parse the shadow color");
+            }
+            if args.len() != 3 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            // Let "\n" on the command line start a new line
+            let caption = args.remove(0).replace("\\n", "\n");
+            draw_text(infile, outfile, &caption, &font, &options, &settings);
+        }
+
+        // Lines, boxes, arrows and other shapes, given as strings or read from a recipe file
+        "draw" => {
+            let mut shapes = Vec::new();
+            if let Some(recipe) = take_option(&mut args, "--recipe") {
+                let recipe = std::fs::read_to_string(recipe).expect("Failed to read the recipe");
+                shapes.extend(
+                    recipe
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(String::from),
+                );
+            }
+            if args.len() < 2 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            shapes.append(&mut args);
+            let commands: Vec<draw::Command> = shapes
+                .iter()
+                .map(|shape| {
+                    draw::Command::parse(shape).unwrap_or_else(|e| {
+                        println!("{}", e);
+                        std::process::exit(-1);
+                    })
+                })
+                .collect();
+            draw(infile, outfile, &commands, &settings);
         }
 
-        // **OPTION**
-        // Brighten -- see the brighten() function below
+        // Lay out many images in a grid, to look at a whole batch at once
+        "montage" => {
+            let mut options = montage::MontageOptions {
+                linear: settings.linear,
+                ..montage::MontageOptions::default()
+            };
+            let font = match take_option(&mut args, "--font") {
+                Some(path) => text::Font::load(&path).expect("Failed to load the font"),
+                None => text::Font::Bitmap,
+            };
+            if let Some(columns) = take_option(&mut args, "--columns") {
+                options.columns = Some(columns.parse().expect("Failed to parse the columns"));
+            }
+            if let Some(cell) = take_option(&mut args, "--cell") {
+                options.cell = parse_size(&cell);
+                if options.cell.0 == 0 || options.cell.1 == 0 {
+                    println!("The cell must be at least 1x1");
+                    std::process::exit(-1);
+                }
+            }
+            if let Some(spacing) = take_option(&mut args, "--spacing") {
+                options.spacing = spacing.parse().expect("Failed to parse the spacing");
+            }
+            if let Some(color) = take_option(&mut args, "--background") {
+                options.background =
+                    color::parse_color(&color).expect("Failed to parse the background color");
+            }
+            if let Some(size) = take_option(&mut args, "--label-size") {
+                options.label_size = size.parse().expect("Failed to parse the label size");
+            }
+            if let Some(color) = take_option(&mut args, "--label-color") {
+                options.label_color =
+                    color::parse_color(&color).expect("Failed to parse the label color");
+            }
+            options.labels = take_flag(&mut args, "--labels");
+            if args.len() < 2 {
+                print_usage_and_exit();
+            }
+            let outfile = args.pop().unwrap();
+            let infiles = montage::expand_globs(&args);
+            montage(infiles, outfile, &font, &options, &settings);
+        }
 
-        // **OPTION**
-        // Crop -- see the crop() function below
+        // Measure how different two images are, e.g. in visual regression tests
+        "compare" => {
+            let diff = take_option(&mut args, "--diff");
+            let max_mse = take_option(&mut args, "--max-mse")
+                .map(|v| v.parse().expect("Failed to parse the maximum MSE"));
+            let min_psnr = take_option(&mut args, "--min-psnr")
+                .map(|v| v.parse().expect("Failed to parse the minimum PSNR"));
+            let min_ssim = take_option(&mut args, "--min-ssim")
+                .map(|v| v.parse().expect("Failed to parse the minimum SSIM"));
+            if args.len() != 2 {
+                print_usage_and_exit();
+            }
+            let file_a = args.remove(0);
+            let file_b = args.remove(0);
+            compare(file_a, file_b, diff, max_mse, min_psnr, min_ssim, &settings);
+        }
 
-        // **OPTION**
-        // Rotate -- see the rotate() function below
+        // Perceptual hashes, which barely change when an image is resized or re-encoded
+        "hash" => {
+            let algorithm =
+                take_option(&mut args, "--algorithm").map(|name| parse_hash_algorithm(&name));
+            if args.is_empty() {
+                print_usage_and_exit();
+            }
+            hash(args, algorithm);
+        }
+
+        // Find groups of near-identical images in a directory
+        "dupes" => {
+            let algorithm = take_option(&mut args, "--algorithm")
+                .map_or(hash::Algorithm::Perceptual, |name| {
+                    parse_hash_algorithm(&name)
+                });
+            let max_distance = take_option(&mut args, "--distance")
+                .map_or(8, |d| d.parse().expect("Failed to parse the distance"));
+            if args.len() != 1 {
+                print_usage_and_exit();
+            }
+            let dir = args.remove(0);
+            dupes(dir, algorithm, max_distance);
+        }
+
+        // Just change the file format (see the output options in the usage)
+        "convert" => {
+            if args.len() != 2 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            convert(infile, outfile, &settings);
+        }
+
+        // Get rid of transparency by painting the image onto a solid color
+        "flatten" => {
+            let background = match take_option(&mut args, "--background") {
+                Some(color) => {
+                    color::parse_color(&color).expect("Failed to parse the background color")
+                }
+                None => image::Rgba([255, 255, 255, 255]),
+            };
+            if args.len() != 2 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            flatten(infile, outfile, background, &settings);
+        }
 
-        // **OPTION**
-        // Invert -- see the invert() function below
+        // Save the alpha channel as a grayscale mask
+        "extract-alpha" => {
+            if args.len() != 2 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            extract_alpha(infile, outfile, &settings);
+        }
 
-        // **OPTION**
-        // Grayscale -- see the grayscale() function below
+        // Make a grayscale mask the alpha channel (white is opaque)
+        "set-alpha" => {
+            if args.len() != 3 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let mask = args.remove(0);
+            let outfile = args.remove(0);
+            set_alpha(infile, mask, outfile, &settings);
+        }
 
         // A VERY DIFFERENT EXAMPLE...a really fun one. :-)
         "fractal" => {
@@ -71,111 +428,993 @@ fn main() {
                 print_usage_and_exit();
             }
             let outfile = args.remove(0);
-            fractal(outfile);
+            fractal(outfile, &settings);
+        }
+
+        // Keep OUTDIR up to date with the recipe applied to the inputs
+        "watch" => {
+            let recipe = take_option(&mut args, "--recipe");
+            let outdir = take_option(&mut args, "--out");
+            let debounce = take_option(&mut args, "--debounce")
+                .map(|ms| ms.parse().expect("Failed to parse the debounce time"))
+                .unwrap_or(200);
+            let (Some(recipe), Some(outdir)) = (recipe, outdir) else {
+                print_usage_and_exit();
+            };
+            if args.is_empty() {
+                print_usage_and_exit();
+            }
+            let debounce = std::time::Duration::from_millis(debounce);
+            watch::run(&args, &recipe, &outdir, debounce, &settings).unwrap_or_else(|e| {
+                println!("{}", e);
+                std::process::exit(-1);
+            });
+        }
+
+        // Edge, line and circle detection
+        "edges" => {
+            let canny = take_canny_options(&mut args);
+            if args.len() != 2 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            edges(infile, outfile, &canny, &settings);
+        }
+
+        "lines" | "circles" => {
+            let canny = take_canny_options(&mut args);
+            let draw_to = take_option(&mut args, "--draw");
+            let color = take_option(&mut args, "--color")
+                .map(|color| color::parse_color(&color).expect("Failed to parse the color"))
+                .unwrap_or(image::Rgba([255, 0, 0, 255]));
+            let max = take_option(&mut args, "--max")
+                .map_or(20, |max| max.parse().expect("Failed to parse the maximum"));
+            let detect = if subcommand == "lines" {
+                let min_votes = take_option(&mut args, "--min-votes")
+                    .map(|votes| votes.parse().expect("Failed to parse the minimum votes"));
+                Detect::Lines { min_votes, max }
+            } else {
+                let radius = take_option(&mut args, "--radius").unwrap_or_else(|| {
+                    print_usage_and_exit();
+                });
+                let (min, max_radius) = radius
+                    .split_once('-')
+                    .map(|(a, b)| (a.parse(), b.parse()))
+                    .and_then(|(a, b)| Some((a.ok()?, b.ok()?)))
+                    .expect("Expected a range of radii like 10-40");
+                let min_score = take_option(&mut args, "--min-score").map_or(0.5, |score| {
+                    score.parse().expect("Failed to parse the minimum score")
+                });
+                Detect::Circles {
+                    radii: (min, max_radius),
+                    min_score,
+                    max,
+                }
+            };
+            if args.len() != 1 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            detect_shapes(infile, &canny, detect, draw_to, color, &settings);
+        }
+
+        // Noise removal that keeps edges
+        "denoise" => {
+            let radius = take_option(&mut args, "--radius").map_or(2, |radius| {
+                radius.parse().expect("Failed to parse the radius")
+            });
+            let spatial = take_option(&mut args, "--spatial").map_or(3.0, |sigma| {
+                sigma.parse().expect("Failed to parse the spatial sigma")
+            });
+            let range = take_option(&mut args, "--range").map_or(30.0, |sigma| {
+                sigma.parse().expect("Failed to parse the range sigma")
+            });
+            let strength = take_option(&mut args, "--strength").map_or(10.0, |strength| {
+                strength.parse().expect("Failed to parse the strength")
+            });
+            let search = take_option(&mut args, "--search").map_or(7, |radius| {
+                radius.parse().expect("Failed to parse the search radius")
+            });
+            let patch = take_option(&mut args, "--patch").map_or(1, |radius| {
+                radius.parse().expect("Failed to parse the patch radius")
+            });
+            if args.len() != 3 {
+                print_usage_and_exit();
+            }
+            let method = match args.remove(0).as_str() {
+                "median" => denoise::Method::Median { radius },
+                "bilateral" => denoise::Method::Bilateral { spatial, range },
+                "nlmeans" => denoise::Method::NonLocalMeans {
+                    strength,
+                    search,
+                    patch,
+                },
+                _ => {
+                    println!(
+                        "Unknown denoising method. Use one of: {}",
+                        denoise::Method::NAMES
+                    );
+                    std::process::exit(-1);
+                }
+            };
+            method.validate().unwrap_or_else(|e| {
+                println!("{}", e);
+                std::process::exit(-1);
+            });
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            denoise(infile, outfile, method, &settings);
+        }
+
+        // Erosion, dilation and the operations built from them
+        "morph" => {
+            let radius = take_option(&mut args, "--radius").map_or(1, |radius| {
+                radius.parse().expect("Failed to parse the radius")
+            });
+            let element = match take_option(&mut args, "--element") {
+                None => morphology::Element::new(morphology::Shape::Square, radius),
+                Some(name) => match morphology::Shape::from_name(&name) {
+                    Some(shape) => morphology::Element::new(shape, radius),
+                    None => codec::open(&name)
+                        .and_then(|img| morphology::Element::from_image(&img))
+                        .unwrap_or_else(|e| {
+                            println!("Failed to read the structuring element {}: {}", name, e);
+                            std::process::exit(-1);
+                        }),
+                },
+            };
+            let iterations = take_option(&mut args, "--iterations").map_or(1, |iterations| {
+                iterations
+                    .parse()
+                    .expect("Failed to parse the number of iterations")
+            });
+            let threshold = take_option(&mut args, "--threshold")
+                .map(|level| level.parse().expect("Failed to parse the threshold"));
+            if args.len() != 3 {
+                print_usage_and_exit();
+            }
+            let operation =
+                morphology::Operation::from_name(&args.remove(0)).unwrap_or_else(|| {
+                    println!(
+                        "Unknown morphological operation. Use one of: {}",
+                        morphology::Operation::NAMES
+                    );
+                    std::process::exit(-1);
+                });
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            morph(
+                infile, outfile, operation, &element, iterations, threshold, &settings,
+            );
+        }
+
+        // Count and measure the separate objects in an image
+        "blobs" => {
+            let mut options = blobs::BlobOptions {
+                dark: take_flag(&mut args, "--dark"),
+                ..blobs::BlobOptions::default()
+            };
+            if let Some(level) = take_option(&mut args, "--threshold") {
+                options.threshold = level.parse().expect("Failed to parse the threshold");
+            }
+            if let Some(connectivity) = take_option(&mut args, "--connectivity") {
+                options.connectivity = match connectivity.as_str() {
+                    "4" => blobs::Connectivity::Four,
+                    "8" => blobs::Connectivity::Eight,
+                    _ => {
+                        println!("Connectivity must be 4 or 8");
+                        std::process::exit(-1);
+                    }
+                };
+            }
+            if let Some(area) = take_option(&mut args, "--min-area") {
+                options.min_area = area.parse().expect("Failed to parse the minimum area");
+            }
+            let csv = take_flag(&mut args, "--csv");
+            let labels_to = take_option(&mut args, "--labels");
+            if args.len() != 1 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            find_blobs(infile, &options, csv, labels_to, &settings);
+        }
+
+        // Content-aware resizing
+        "carve" => {
+            let energy = match take_option(&mut args, "--energy") {
+                Some(name) => carve::Energy::from_name(&name).unwrap_or_else(|| {
+                    println!("Unknown energy function. Use one of: gradient, forward");
+                    std::process::exit(-1);
+                }),
+                None => carve::Energy::Forward,
+            };
+            let open_mask = |path: String| codec::open(path).expect("Failed to open the mask.");
+            let options = carve::CarveOptions {
+                energy,
+                protect: take_option(&mut args, "--protect").map(open_mask),
+                remove: take_option(&mut args, "--remove").map(open_mask),
+            };
+            if args.len() != 3 && !(args.len() == 2 && options.remove.is_some()) {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            let outfile = args.remove(0);
+            let size = args.pop().map(|size| parse_size(&size)).unwrap_or((0, 0));
+            carve(infile, outfile, size, &options, &settings);
         }
 
-        // **OPTION**
-        // Generate -- see the generate() function below -- this should be sort of like "fractal()"!
+        // Process images sent over HTTP
+        "serve" => {
+            let port = take_option(&mut args, "--port").unwrap_or("8080".to_string());
+            let host = take_option(&mut args, "--host").unwrap_or("127.0.0.1".to_string());
+            let max_size = take_option(&mut args, "--max-size").unwrap_or("32M".to_string());
+            let limits = serve::Limits {
+                max_bytes: stream::parse_memory(&max_size).expect("Failed to parse the size limit"),
+                max_pixels: take_option(&mut args, "--max-pixels")
+                    .map(|pixels| pixels.parse().expect("Failed to parse the pixel limit"))
+                    .unwrap_or(50_000_000),
+                timeout: std::time::Duration::from_secs_f64(
+                    take_option(&mut args, "--timeout")
+                        .map(|secs| secs.parse().expect("Failed to parse the timeout"))
+                        .unwrap_or(30.0),
+                ),
+            };
+            if !args.is_empty() {
+                print_usage_and_exit();
+            }
+            let address = format!("{}:{}", host, port);
+            serve::run(&address, limits, settings.linear, &settings.save).unwrap_or_else(|e| {
+                println!("{}", e);
+                std::process::exit(-1);
+            });
+        }
+
+        // Show an image in the terminal
+        "view" => {
+            let mode = match take_option(&mut args, "--mode") {
+                Some(mode) => parse_preview_mode(&mode),
+                None => settings.preview.unwrap_or_else(preview::Mode::detect),
+            };
+            let columns = take_option(&mut args, "--width")
+                .map(|width| width.parse().expect("Failed to parse the width"));
+            if args.len() != 1 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            view(infile, mode, columns);
+        }
+
+        // Try settings out interactively
+        "shell" => {
+            if args.len() != 1 {
+                print_usage_and_exit();
+            }
+            let infile = args.remove(0);
+            shell::run(&infile, &settings).unwrap_or_else(|e| {
+                println!("{}", e);
+                std::process::exit(-1);
+            });
+        }
 
-        // For everything else...
+        // Anything else is a chain of operations: INFILE OUTFILE OP [ARGS] [OP [ARGS]]...
         _ => {
-            print_usage_and_exit();
+            let recipe = take_option(&mut args, "--recipe");
+            // A mistyped subcommand isn't an input file
+            let input = subcommand == "-" || std::path::Path::new(&subcommand).is_file();
+            if !input && recipe.is_none() {
+                print_usage_and_exit();
+            }
+            if args.is_empty() || (recipe.is_some() && args.len() != 1) {
+                print_usage_and_exit();
+            }
+            let infile = subcommand;
+            let outfile = args.remove(0);
+            let operations = match recipe {
+                Some(recipe) => std::fs::read_to_string(&recipe)
+                    .map_err(|e| format!("Failed to read {}: {}", recipe, e))
+                    .and_then(|text| ops::parse_recipe(&text)),
+                None if args.is_empty() => Err("No operations given".to_string()),
+                None => ops::parse_chain(&args),
+            };
+            let operations = operations.unwrap_or_else(|e| {
+                println!("{}", e);
+                std::process::exit(-1);
+            });
+            transform(infile, outfile, &operations, &settings);
         }
     }
 }
 
-fn print_usage_and_exit() {
+fn print_usage_and_exit() -> ! {
     println!("USAGE (when in doubt, use a .png extension on your filenames)");
-    println!("blur INFILE OUTFILE");
+    println!("blur INFILE OUTFILE [SIGMA] [--passes N]  (SIGMA is 2 by default)");
+    println!("    --passes N  approximate the Gaussian with N box blurs, which take the same time");
+    println!("    whatever SIGMA is; more passes are closer (0 is exact; by default exact up to");
+    println!("    SIGMA 3 and 3 passes beyond)");
+    println!("brighten INFILE OUTFILE AMOUNT  (in 8-bit levels; negative darkens)");
+    println!("crop INFILE OUTFILE X,Y WxH");
+    println!("resize INFILE OUTFILE WxH  (0 for W or H keeps the aspect ratio)");
+    println!("invert INFILE OUTFILE");
+    println!("grayscale INFILE OUTFILE");
+    println!("rotate INFILE OUTFILE DEGREES [--interpolation nearest|bilinear|bicubic]");
+    println!(
+        "    (clockwise; the canvas grows to fit, and the new corners are transparent or black)"
+    );
     println!("fractal OUTFILE");
+    println!("composite BASE OVERLAY OUTFILE [--mode MODE] [--opacity 0..1] [--anchor ANCHOR] [--pos X,Y]");
+    println!(
+        "text INFILE OUTFILE TEXT [--font FILE.ttf] [--size PX] [--color COLOR] [--anchor ANCHOR]"
+    );
+    println!("    [--pos X,Y] [--align left|center|right] [--width PX] [--outline PX] [--outline-color COLOR]");
+    println!("    [--shadow DX,DY] [--shadow-color COLOR]");
+    println!("draw INFILE OUTFILE [SHAPE]... [--recipe FILE]");
+    println!(
+        "    SHAPE is one of (each as a single quoted argument, or one per line in the recipe):"
+    );
+    println!("    line X1,Y1 X2,Y2 | arrow X1,Y1 X2,Y2 [head=PX] | rect X,Y W,H [radius=PX]");
+    println!("    circle X,Y R | ellipse X,Y RX,RY | polygon X,Y X,Y X,Y... | polyline X,Y X,Y...");
+    println!("    path \"SVG PATH DATA\"");
+    println!("    followed by any of: stroke=COLOR fill=COLOR width=PX");
+    println!(
+        "montage INFILE... OUTFILE [--columns N] [--cell WxH] [--spacing PX] [--background COLOR]"
+    );
+    println!("    [--labels] [--label-size PX] [--label-color COLOR] [--font FILE.ttf]");
+    println!("compare FILE_A FILE_B [--diff OUTFILE] [--max-mse N] [--min-psnr DB] [--min-ssim N]");
+    println!("    (exits with status 1 if the images differ by more than allowed, 2 on errors)");
+    println!("convert INFILE OUTFILE");
+    println!(
+        "carve INFILE OUTFILE [WxH] [--energy gradient|forward] [--protect MASK] [--remove MASK]"
+    );
+    println!(
+        "    resize by removing or adding low-detail seams (0 for W or H keeps it); MASK is an"
+    );
+    println!(
+        "    image of the same size whose white areas are kept, or removed (then WxH is optional)"
+    );
+    println!("edges INFILE OUTFILE [--sigma 1.4] [--low 0.1] [--high 0.2]");
+    println!("    Canny edges; --low and --high are fractions of the strongest gradient");
+    println!("lines INFILE [--min-votes N] [--max 20] [--draw OUTFILE] [--color COLOR]");
+    println!("circles INFILE --radius MIN-MAX [--min-score 0.5] [--max 20] [--draw OUTFILE] [--color COLOR]");
+    println!("    print the straight lines or circles found (Hough transform) as JSON; they take");
+    println!("    the same options as edges");
+    println!(
+        "morph OP INFILE OUTFILE [--element square|disk|cross|FILE] [--radius 1] [--iterations 1]"
+    );
+    println!(
+        "    [--threshold LEVEL]  where OP is one of {}",
+        morphology::Operation::NAMES
+    );
+    println!("    FILE is an image whose white pixels make the element, centered; --threshold");
+    println!("    turns the image black and white first, at LEVEL (0-255)");
+    println!("blobs INFILE [--threshold 128] [--dark] [--connectivity 4|8] [--min-area N] [--csv]");
+    println!(
+        "    [--labels OUTFILE]  print the area, bounding box, centroid and perimeter of each"
+    );
+    println!("    connected area at least as bright as the threshold (or darker, with --dark), as");
+    println!("    JSON or CSV; --labels also writes them in false colors");
+    println!(
+        "denoise METHOD INFILE OUTFILE  where METHOD is one of {}",
+        denoise::Method::NAMES
+    );
+    println!("    median [--radius 2] | bilateral [--spatial 3] [--range 30]");
+    println!("    nlmeans [--strength 10] [--search 7] [--patch 1]");
+    println!(
+        "    (--range and --strength are in 8-bit levels; about the noise's standard deviation)"
+    );
+    println!("flatten INFILE OUTFILE [--background COLOR]");
+    println!("extract-alpha INFILE OUTFILE");
+    println!("set-alpha INFILE MASK OUTFILE");
+    println!("hash INFILE... [--algorithm ahash|dhash|phash]");
+    println!("dupes DIR [--algorithm ahash|dhash|phash] [--distance BITS]");
+    println!(
+        "view FILE [--mode truecolor|256|sixel] [--width COLUMNS]  (show FILE in the terminal)"
+    );
+    println!("shell INFILE  (apply operations one at a time, with undo; type help inside)");
+    println!("serve [--port 8080] [--host 127.0.0.1] [--max-size 32M] [--max-pixels 50000000]");
+    println!("    [--timeout 30]  run operation chains over HTTP: POST an image to");
+    println!(
+        "    /process?ops=blur+2+invert&format=png, or JSON {{\"ops\", \"format\", \"image\"}}"
+    );
+    println!(
+        "    with the image in base64; --max-size limits the request body, --timeout is in seconds"
+    );
+    println!();
+    println!(
+        "INFILE OUTFILE OP [ARGS] [OP [ARGS]]...  apply a chain of operations, where OP is one of"
+    );
+    println!("    {}", ops::NAMES);
+    println!("    e.g. mirage in.png out.png blur 2.5 invert crop 0,0 640x480");
+    println!("INFILE OUTFILE --recipe FILE  apply the operations in FILE (one or more per line)");
+    println!("watch INPUT... --recipe FILE --out OUTDIR [--debounce 200]");
+    println!(
+        "    apply the recipe to each INPUT (an image, or the images in a directory), writing to"
+    );
+    println!("    OUTDIR, and again whenever an input or the recipe changes (after --debounce ms of quiet)");
+    println!();
+    println!("Use - as INFILE or OUTFILE to read from stdin or write to stdout (needs --format).");
+    println!();
+    println!("OUTPUT OPTIONS (for every subcommand that writes an image)");
+    println!(
+        "--format png|jpeg|webp|tiff|bmp|gif|...  (instead of guessing from OUTFILE's extension)"
+    );
+    println!("--depth 8|16|32  (bits per channel; by default the input's depth is kept)");
+    println!("--quality 1-100 --subsampling 444|422|420  (JPEG)");
+    println!("--png-compression fast|default|best");
+    println!("--png-filter none|sub|up|avg|paeth|adaptive");
+    println!("--tiff-compression none|lzw|deflate|packbits");
+    println!();
+    println!(
+        "--tiled [--memory SIZE]  read, process and write PNG or TIFF a band of rows at a time,"
+    );
+    println!(
+        "    using at most SIZE (like 512M or 2G; 256M by default) -- for blur, brighten, crop,"
+    );
+    println!(
+        "    resize, invert, grayscale, convert and chains of them on images too big for memory"
+    );
+    println!(
+        "--preview [--preview-mode truecolor|256|sixel]  also show the result in the terminal"
+    );
+    println!("    (the mode is guessed from TERM, TERM_PROGRAM and COLORTERM by default)");
+    println!("--threads N  run blurs and color adjustments on N threads (one per core by default)");
+    println!("--linear  blur, resize, flatten and blend in linear light (gamma-correct) instead of on sRGB values");
     // **OPTION**
     // Print useful information about what subcommands and arguments you can use
     // println!("...");
     std::process::exit(-1);
 }
 
-fn blur(infile: String, outfile: String) {
-    // Here's how you open an existing image file
-    let img = image::open(infile).expect("Failed to open INFILE.");
-    // **OPTION**
-    // Parse the blur amount (an f32) from the command-line and pass it through
-    // to this function, instead of hard-coding it to 2.0.
-    let img2 = img.blur(2.0);
-    // Here's how you save an image to a file.
-    img2.save(outfile).expect("Failed writing OUTFILE.");
+// Remove `--flag VALUE` from the arguments, wherever it appears, and return the value.
+fn take_option(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == flag)?;
+    if index + 1 >= args.len() {
+        print_usage_and_exit();
+    }
+    args.remove(index);
+    Some(args.remove(index))
+}
+
+// Remove `--flag` from the arguments and return whether it was there.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let found = args.iter().any(|arg| arg == flag);
+    args.retain(|arg| arg != flag);
+    found
+}
+
+// Options that work with every subcommand
+struct Settings {
+    save: codec::SaveOptions,
+    // Do blurring, resizing and blending in linear light instead of on sRGB values
+    linear: bool,
+    // Process the image a band of rows at a time, within this many bytes
+    tiled: Option<usize>,
+    // Show the result in the terminal
+    preview: Option<preview::Mode>,
+}
+
+fn take_settings(args: &mut Vec<String>) -> Settings {
+    let save = take_save_options(args);
+    let linear = take_flag(args, "--linear");
+    if let Some(threads) = take_option(args, "--threads") {
+        let threads = threads
+            .parse()
+            .expect("Failed to parse the number of threads");
+        parallel::set_threads(threads).expect("Failed to start the threads");
+    }
+    let mut tiled = take_flag(args, "--tiled").then_some(256 << 20);
+    if let Some(memory) = take_option(args, "--memory") {
+        tiled = Some(stream::parse_memory(&memory).expect("Failed to parse the memory budget"));
+    }
+    let mut preview = take_flag(args, "--preview").then(preview::Mode::detect);
+    if let Some(mode) = take_option(args, "--preview-mode") {
+        preview = Some(parse_preview_mode(&mode));
+    }
+    Settings {
+        save,
+        linear,
+        tiled,
+        preview,
+    }
+}
+
+// The options that control how output files are written.
+fn take_save_options(args: &mut Vec<String>) -> codec::SaveOptions {
+    let mut options = codec::SaveOptions::default();
+    if let Some(format) = take_option(args, "--format") {
+        options.format = Some(codec::parse_format(&format).expect("Unknown output format"));
+    }
+    if let Some(bits) = take_option(args, "--depth") {
+        options.depth = Some(depth::parse_bits(&bits).expect("Depth must be 8, 16 or 32"));
+    }
+    if let Some(quality) = take_option(args, "--quality") {
+        options.jpeg_quality = quality.parse().expect("Failed to parse the JPEG quality");
+    }
+    if let Some(subsampling) = take_option(args, "--subsampling") {
+        options.jpeg_subsampling =
+            codec::parse_subsampling(&subsampling).expect("Unknown chroma subsampling");
+    }
+    if let Some(compression) = take_option(args, "--png-compression") {
+        options.png_compression =
+            codec::parse_png_compression(&compression).expect("Unknown PNG compression");
+    }
+    if let Some(filter) = take_option(args, "--png-filter") {
+        options.png_filter = codec::parse_png_filter(&filter).expect("Unknown PNG filter");
+    }
+    if let Some(compression) = take_option(args, "--tiff-compression") {
+        options.tiff_compression =
+            codec::parse_tiff_compression(&compression).expect("Unknown TIFF compression");
+    }
+    options
+}
+
+// Parse "X,Y" into a pair of numbers.
+fn parse_pair(text: &str) -> (i64, i64) {
+    let (x, y) = text
+        .split_once(',')
+        .expect("Expected a pair of numbers like X,Y");
+    (
+        x.trim().parse().expect("Failed to parse the first number"),
+        y.trim().parse().expect("Failed to parse the second number"),
+    )
+}
+
+// Parse "WIDTHxHEIGHT", e.g. "320x240".
+fn parse_size(text: &str) -> (u32, u32) {
+    let (w, h) = text.split_once('x').expect("Expected a size like 320x240");
+    (
+        w.parse().expect("Failed to parse the width"),
+        h.parse().expect("Failed to parse the height"),
+    )
+}
+
+fn parse_hash_algorithm(name: &str) -> hash::Algorithm {
+    hash::Algorithm::from_name(name).unwrap_or_else(|| {
+        println!("Unknown hash algorithm. Use one of: ahash, dhash, phash");
+        std::process::exit(-1);
+    })
+}
+
+// Options for Canny edge detection, shared by edges, lines and circles
+fn take_canny_options(args: &mut Vec<String>) -> analysis::CannyOptions {
+    let mut options = analysis::CannyOptions::default();
+    if let Some(sigma) = take_option(args, "--sigma") {
+        options.sigma = sigma.parse().expect("Failed to parse the sigma");
+    }
+    if let Some(low) = take_option(args, "--low") {
+        options.low = low.parse().expect("Failed to parse the low threshold");
+    }
+    if let Some(high) = take_option(args, "--high") {
+        options.high = high.parse().expect("Failed to parse the high threshold");
+    }
+    options
+}
+
+fn parse_preview_mode(name: &str) -> preview::Mode {
+    preview::Mode::from_name(name).unwrap_or_else(|| {
+        println!("Unknown preview mode. Use one of: {}", preview::Mode::NAMES);
+        std::process::exit(-1);
+    })
+}
+
+fn parse_anchor(name: &str) -> composite::Anchor {
+    composite::Anchor::from_name(name).unwrap_or_else(|| {
+        println!("Unknown anchor. Use one of: {}", composite::Anchor::NAMES);
+        std::process::exit(-1);
+    })
+}
+
+// Write OUTFILE, and show it in the terminal with --preview.
+fn save(img: &image::DynamicImage, outfile: &str, settings: &Settings) {
+    codec::save(img, outfile, &settings.save).expect("Failed writing OUTFILE.");
+    if let Some(mode) = settings.preview {
+        let text = preview::render(img, mode, preview::available_size());
+        // With an OUTFILE of -, stdout is taken by the image file itself.
+        if outfile == "-" {
+            eprint!("{}", text);
+        } else {
+            print!("{}", text);
+        }
+    }
 }
 
-fn brighten(infile: String, outfile: String) {
-    // See blur() for an example of how to open / save an image.
+// Show an image in the terminal.
+fn view(infile: String, mode: preview::Mode, columns: Option<u32>) {
+    let img = codec::open(infile).expect("Failed to open FILE.");
+    let (available, rows) = preview::available_size();
+    print!(
+        "{}",
+        preview::render(&img, mode, (columns.unwrap_or(available), rows))
+    );
+}
 
-    // .brighten() takes one argument, an i32.  Positive numbers brighten the
-    // image. Negative numbers darken it.  It returns a new image.
+// Run single-image operations on the whole image in memory, or a band of rows at a time with
+// --tiled.
+fn transform(infile: String, outfile: String, operations: &[ops::Operation], settings: &Settings) {
+    if let Some(budget) = settings.tiled {
+        let save = &settings.save;
+        stream::process(&infile, &outfile, operations, settings.linear, budget, save)
+            .unwrap_or_else(|e| {
+                println!("{}", e);
+                std::process::exit(-1);
+            });
+        if settings.preview.is_some() {
+            println!("(No preview with --tiled: the image is never all in memory)");
+        }
+        return;
+    }
+    let mut img = codec::open(infile).expect("Failed to open INFILE.");
+    for operation in operations {
+        img = operation.apply(&img, settings.linear);
+    }
+    save(&img, &outfile, settings);
+}
 
-    // Challenge: parse the brightness amount from the command-line and pass it
-    // through to this function.
+fn blur(infile: String, outfile: String, sigma: f32, method: blur::Method, settings: &Settings) {
+    transform(
+        infile,
+        outfile,
+        &[ops::Operation::Blur(sigma, method)],
+        settings,
+    );
 }
 
-fn crop(infile: String, outfile: String) {
-    // See blur() for an example of how to open an image.
+fn composite(
+    base: String,
+    overlay: String,
+    outfile: String,
+    options: composite::CompositeOptions,
+    settings: &Settings,
+) {
+    let base = codec::open(base).expect("Failed to open BASE.");
+    let overlay = codec::open(overlay).expect("Failed to open OVERLAY.");
+    let img = composite::composite(&base, &overlay, &options);
+    save(&img, &outfile, settings);
+}
+
+fn draw_text(
+    infile: String,
+    outfile: String,
+    caption: &str,
+    font: &text::Font,
+    options: &text::TextOptions,
+    settings: &Settings,
+) {
+    let img = codec::open(infile).expect("Failed to open INFILE.");
+    let img2 = text::draw_text(&img, caption, font, options);
+    save(&img2, &outfile, settings);
+}
+
+fn draw(infile: String, outfile: String, commands: &[draw::Command], settings: &Settings) {
+    let img = codec::open(infile).expect("Failed to open INFILE.");
+    let img2 = draw::draw(&img, commands, settings.linear);
+    save(&img2, &outfile, settings);
+}
+
+fn montage(
+    infiles: Vec<String>,
+    outfile: String,
+    font: &text::Font,
+    options: &montage::MontageOptions,
+    settings: &Settings,
+) {
+    let images: Vec<(String, image::DynamicImage)> = infiles
+        .into_iter()
+        .map(|infile| {
+            let img = codec::open(&infile).unwrap_or_else(|e| {
+                println!("Failed to open {}: {}", infile, e);
+                std::process::exit(-1);
+            });
+            let name = std::path::Path::new(&infile)
+                .file_name()
+                .map_or(infile.clone(), |name| name.to_string_lossy().into_owned());
+            (name, img)
+        })
+        .collect();
+    if images.is_empty() {
+        println!("No input images found.");
+        std::process::exit(-1);
+    }
+    let sheet = montage::montage(&images, font, options);
+    save(&sheet, &outfile, settings);
+}
+
+fn compare(
+    file_a: String,
+    file_b: String,
+    diff: Option<String>,
+    max_mse: Option<f64>,
+    min_psnr: Option<f64>,
+    min_ssim: Option<f64>,
+    settings: &Settings,
+) {
+    // Status 1 is for images that differ too much, so errors get 2.
+    let error = |e: String| -> ! {
+        println!("{}", e);
+        std::process::exit(2);
+    };
+    let a = codec::open(file_a).unwrap_or_else(|e| error(e));
+    let b = codec::open(file_b).unwrap_or_else(|e| error(e));
+    let result = compare::compare(&a, &b).unwrap_or_else(|e| error(e));
+
+    println!(
+        "{:<8}{:>14}{:>12}{:>10}",
+        "channel", "MSE", "PSNR (dB)", "SSIM"
+    );
+    for scores in result.channels.iter().chain([&result.overall]) {
+        println!(
+            "{:<8}{:>14.8}{:>12.2}{:>10.5}",
+            scores.name, scores.mse, scores.psnr, scores.ssim
+        );
+    }
+    if let Some(diff) = diff {
+        let diff_image = image::DynamicImage::ImageRgb8(compare::diff_image(&a, &b));
+        codec::save(&diff_image, &diff, &settings.save).unwrap_or_else(|e| error(e));
+    }
+
+    let overall = &result.overall;
+    let mut failed = false;
+    if max_mse.is_some_and(|max| overall.mse > max) {
+        println!("FAIL: MSE {} is above {}", overall.mse, max_mse.unwrap());
+        failed = true;
+    }
+    if min_psnr.is_some_and(|min| overall.psnr < min) {
+        println!(
+            "FAIL: PSNR {:.2} dB is below {}",
+            overall.psnr,
+            min_psnr.unwrap()
+        );
+        failed = true;
+    }
+    if min_ssim.is_some_and(|min| overall.ssim < min) {
+        println!(
+            "FAIL: SSIM {:.5} is below {}",
+            overall.ssim,
+            min_ssim.unwrap()
+        );
+        failed = true;
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
 
-    // .crop() takes four arguments: x: u32, y: u32, width: u32, height: u32
-    // You may hard-code them, if you like.  It returns a new image.
+fn hash(infiles: Vec<String>, algorithm: Option<hash::Algorithm>) {
+    let algorithms = match algorithm {
+        Some(algorithm) => vec![algorithm],
+        None => vec![
+            hash::Algorithm::Average,
+            hash::Algorithm::Difference,
+            hash::Algorithm::Perceptual,
+        ],
+    };
+    for infile in infiles {
+        let img = codec::open(&infile).expect("Failed to open INFILE.");
+        for algorithm in &algorithms {
+            print!("{}:{:016x} ", algorithm.name(), algorithm.hash(&img));
+        }
+        println!("{}", infile);
+    }
+}
 
-    // Challenge: parse the four values from the command-line and pass them
-    // through to this function.
+fn dupes(dir: String, algorithm: hash::Algorithm, max_distance: u32) {
+    let mut files = Vec::new();
+    let mut hashes = Vec::new();
+    for path in hash::find_images(std::path::Path::new(&dir)) {
+        match codec::open(&path) {
+            Ok(img) => {
+                hashes.push(algorithm.hash(&img));
+                files.push(path);
+            }
+            Err(e) => eprintln!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    // One group per paragraph, first file of each group is the "original"
+    for group in hash::group_duplicates(&hashes, max_distance) {
+        for i in group {
+            println!("{}", files[i].display());
+        }
+        println!();
+    }
+}
 
-    // See blur() for an example of how to save the image.
+fn convert(infile: String, outfile: String, settings: &Settings) {
+    transform(infile, outfile, &[], settings);
 }
 
-fn rotate(infile: String, outfile: String) {
-    // See blur() for an example of how to open an image.
+fn edges(infile: String, outfile: String, canny: &analysis::CannyOptions, settings: &Settings) {
+    let img = codec::open(infile).expect("Failed to open INFILE.");
+    let edges = analysis::canny(&img, canny);
+    save(&analysis::edge_image(&edges), &outfile, settings);
+}
 
-    // There are 3 rotate functions to choose from (all clockwise):
-    //   .rotate90()
-    //   .rotate180()
-    //   .rotate270()
-    // All three methods return a new image.  Pick one and use it!
+// What to look for with the Hough transform
+enum Detect {
+    // At least this many votes (by default a quarter of the image's smaller side)
+    Lines {
+        min_votes: Option<u32>,
+        max: usize,
+    },
+    Circles {
+        radii: (u32, u32),
+        min_score: f32,
+        max: usize,
+    },
+}
 
-    // Challenge: parse the rotation amount from the command-line, pass it
-    // through to this function to select which method to call.
+// Print the lines or circles in the image as JSON, and draw them with --draw.
+fn detect_shapes(
+    infile: String,
+    canny: &analysis::CannyOptions,
+    detect: Detect,
+    draw_to: Option<String>,
+    color: image::Rgba<u8>,
+    settings: &Settings,
+) {
+    let img = codec::open(infile).expect("Failed to open INFILE.");
+    let edges = analysis::canny(&img, canny);
+    let (lines, circles, json) = match detect {
+        Detect::Lines { min_votes, max } => {
+            let min_votes = min_votes.unwrap_or(img.width().min(img.height()) / 4);
+            let lines = analysis::hough_lines(&edges, min_votes, max);
+            let json = analysis::lines_json(&lines);
+            (lines, vec![], json)
+        }
+        Detect::Circles {
+            radii,
+            min_score,
+            max,
+        } => {
+            let circles = analysis::hough_circles(&edges, radii, min_score, max);
+            let json = analysis::circles_json(&circles);
+            (vec![], circles, json)
+        }
+    };
+    // With --draw -, the picture is what goes to stdout.
+    if draw_to.as_deref() != Some("-") {
+        println!("{}", serde_json::to_string_pretty(&json).unwrap());
+    }
+    if let Some(outfile) = draw_to {
+        let drawn = analysis::draw_shapes(&img, &lines, &circles, color, settings.linear);
+        save(&drawn, &outfile, settings);
+    }
+}
 
-    // See blur() for an example of how to save the image.
+fn denoise(infile: String, outfile: String, method: denoise::Method, settings: &Settings) {
+    let img = codec::open(infile).expect("Failed to open INFILE.");
+    let img2 = denoise::denoise(&img, method);
+    save(&img2, &outfile, settings);
 }
 
-fn invert(infile: String, outfile: String) {
-    // See blur() for an example of how to open an image.
+fn morph(
+    infile: String,
+    outfile: String,
+    operation: morphology::Operation,
+    element: &morphology::Element,
+    iterations: u32,
+    threshold: Option<u8>,
+    settings: &Settings,
+) {
+    let mut img = codec::open(infile).expect("Failed to open INFILE.");
+    if let Some(level) = threshold {
+        img = morphology::threshold(&img, level);
+    }
+    let img2 = morphology::morph(&img, operation, element, iterations);
+    save(&img2, &outfile, settings);
+}
 
-    // .invert() takes no arguments and converts the image in-place, so you
-    // will use the same image to save out to a different file.
+// Print the blobs' measurements, and write the labeled image with --labels.
+fn find_blobs(
+    infile: String,
+    options: &blobs::BlobOptions,
+    csv: bool,
+    labels_to: Option<String>,
+    settings: &Settings,
+) {
+    let img = codec::open(infile).expect("Failed to open INFILE.");
+    let labels = blobs::find_blobs(&img, options);
+    // With --labels -, the picture is what goes to stdout.
+    if labels_to.as_deref() != Some("-") {
+        if csv {
+            print!("{}", blobs::blobs_csv(&labels.blobs));
+        } else {
+            let json = blobs::blobs_json(&labels.blobs);
+            println!("{}", serde_json::to_string_pretty(&json).unwrap());
+        }
+    }
+    if let Some(outfile) = labels_to {
+        save(&blobs::label_image(&labels), &outfile, settings);
+    }
+}
 
-    // See blur() for an example of how to save the image.
+fn carve(
+    infile: String,
+    outfile: String,
+    size: (u32, u32),
+    options: &carve::CarveOptions,
+    settings: &Settings,
+) {
+    let img = codec::open(infile).expect("Failed to open INFILE.");
+    let img2 = carve::carve(&img, size, options).unwrap_or_else(|e| {
+        println!("{}", e);
+        std::process::exit(-1);
+    });
+    save(&img2, &outfile, settings);
 }
 
-fn grayscale(infile: String, outfile: String) {
-    // See blur() for an example of how to open an image.
+fn flatten(infile: String, outfile: String, background: image::Rgba<u8>, settings: &Settings) {
+    let img = codec::open(infile).expect("Failed to open INFILE.");
+    let img2 = alpha::flatten(&img, background, settings.linear);
+    save(&img2, &outfile, settings);
+}
 
-    // .grayscale() takes no arguments. It returns a new image.
+fn extract_alpha(infile: String, outfile: String, settings: &Settings) {
+    let img = codec::open(infile).expect("Failed to open INFILE.");
+    let mask = alpha::extract_alpha(&img);
+    save(&mask, &outfile, settings);
+}
 
-    // See blur() for an example of how to save the image.
+fn set_alpha(infile: String, mask: String, outfile: String, settings: &Settings) {
+    let img = codec::open(infile).expect("Failed to open INFILE.");
+    let mask = codec::open(mask).expect("Failed to open MASK.");
+    let img2 = alpha::set_alpha(&img, &mask).unwrap_or_else(|e| {
+        println!("{}", e);
+        std::process::exit(-1);
+    });
+    save(&img2, &outfile, settings);
 }
 
-fn generate(outfile: String) {
-    // Create an ImageBuffer -- see fractal() for an example
+// Positive amounts brighten the image, negative ones darken it, in 8-bit levels.
+fn brighten(infile: String, outfile: String, amount: i32, settings: &Settings) {
+    transform(
+        infile,
+        outfile,
+        &[ops::Operation::Brighten(amount)],
+        settings,
+    );
+}
 
-    // Iterate over the coordinates and pixels of the image -- see fractal() for an example
+fn crop(infile: String, outfile: String, rect: (u32, u32, u32, u32), settings: &Settings) {
+    let (x, y, width, height) = rect;
+    let crop = ops::Operation::Crop {
+        x,
+        y,
+        width,
+        height,
+    };
+    transform(infile, outfile, &[crop], settings);
+}
 
-    // Set the image to some solid color. -- see fractal() for an example
+fn resize(infile: String, outfile: String, size: (u32, u32), settings: &Settings) {
+    transform(
+        infile,
+        outfile,
+        &[ops::Operation::Resize(size.0, size.1)],
+        settings,
+    );
+}
 
-    // Challenge: parse some color data from the command-line, pass it through
-    // to this function to use for the solid color.
+fn rotate(
+    infile: String,
+    outfile: String,
+    degrees: f32,
+    interpolation: warp::Interpolation,
+    settings: &Settings,
+) {
+    let rotate = ops::Operation::Warp(warp::Transform::Rotate(degrees), interpolation);
+    transform(infile, outfile, &[rotate], settings);
+}
 
-    // Challenge 2: Generate something more interesting!
+fn invert(infile: String, outfile: String, settings: &Settings) {
+    transform(infile, outfile, &[ops::Operation::Invert], settings);
+}
 
-    // See blur() for an example of how to save the image
+fn grayscale(infile: String, outfile: String, settings: &Settings) {
+    transform(infile, outfile, &[ops::Operation::Grayscale], settings);
 }
 
 // This code was adapted from https://github.com/PistonDevelopers/image
-fn fractal(outfile: String) {
+fn fractal(outfile: String, settings: &Settings) {
     let width = 800;
     let height = 800;
 
@@ -207,23 +1446,6 @@ fn fractal(outfile: String) {
         *pixel = image::Rgb([red, green, blue]);
     }
 
-    imgbuf.save(outfile).unwrap();
+    let img = image::DynamicImage::ImageRgb8(imgbuf);
+    save(&img, &outfile, settings);
 }
-
-// **SUPER CHALLENGE FOR LATER** - Let's face it, you don't have time for this during class.
-//
-// Make all of the subcommands stackable!
-//
-// For example, if you run:
-//
-//   cargo run infile.png outfile.png blur 2.5 invert rotate 180 brighten 10
-//
-// ...then your program would:
-// - read infile.png
-// - apply a blur of 2.5
-// - invert the colors
-// - rotate the image 180 degrees clockwise
-// - brighten the image by 10
-// - and write the result to outfile.png
-//
-// Good luck!
@@ -0,0 +1,203 @@
+// Contact sheets: many images shrunk to the same cell size and laid out in a grid, optionally with
+// their file names underneath.  The sheet keeps the bit depth of the deepest input.
+
+use crate::text::{self, Align, Font, TextOptions};
+use crate::{depth, ops};
+use image::imageops;
+use image::{DynamicImage, Rgba, Rgba32FImage};
+
+pub struct MontageOptions {
+    // Defaults to a roughly square grid
+    pub columns: Option<u32>,
+    pub cell: (u32, u32),
+    pub spacing: u32,
+    pub background: Rgba<u8>,
+    pub labels: bool,
+    pub label_size: f32,
+    pub label_color: Rgba<u8>,
+    // Resize the thumbnails in linear light
+    pub linear: bool,
+}
+
+impl Default for MontageOptions {
+    fn default() -> Self {
+        MontageOptions {
+            columns: None,
+            cell: (256, 256),
+            spacing: 8,
+            background: Rgba([255, 255, 255, 255]),
+            labels: false,
+            label_size: 14.0,
+            label_color: Rgba([0, 0, 0, 255]),
+            linear: false,
+        }
+    }
+}
+
+// Expand any wildcard patterns among the inputs (for shells that don't, or quoted arguments).
+pub fn expand_globs(inputs: &[String]) -> Vec<String> {
+    let mut files = Vec::new();
+    for input in inputs {
+        if !input.contains(['*', '?', '[']) {
+            files.push(input.clone());
+            continue;
+        }
+        let pattern = glob::glob(input).expect("Failed to parse the file pattern");
+        let mut matches: Vec<String> = pattern
+            .filter_map(Result::ok)
+            .map(|path| path.display().to_string())
+            .collect();
+        matches.sort();
+        files.append(&mut matches);
+    }
+    files
+}
+
+// `images` are (label, image) pairs, laid out left to right, top to bottom.
+pub fn montage(
+    images: &[(String, DynamicImage)],
+    font: &Font,
+    options: &MontageOptions,
+) -> DynamicImage {
+    let count = images.len().max(1) as u32;
+    let columns = options
+        .columns
+        .unwrap_or_else(|| (count as f64).sqrt().ceil() as u32)
+        .clamp(1, count);
+    let rows = count.div_ceil(columns);
+
+    let label_options = TextOptions {
+        size: options.label_size,
+        color: options.label_color,
+        align: Align::Center,
+        ..TextOptions::default()
+    };
+    let label_height = if options.labels {
+        text::render_text("Ag", font, &label_options).height()
+    } else {
+        0
+    };
+
+    let (cell_width, cell_height) = options.cell;
+    let step_x = cell_width + options.spacing;
+    let step_y = cell_height + label_height + options.spacing;
+    let background = options.background.0.map(|c| c as f32 / 255.0);
+    let mut sheet = Rgba32FImage::from_pixel(
+        columns * step_x + options.spacing,
+        rows * step_y + options.spacing,
+        Rgba(background),
+    );
+
+    for (i, (label, img)) in images.iter().enumerate() {
+        let left = options.spacing + i as u32 % columns * step_x;
+        let top = options.spacing + i as u32 / columns * step_y;
+
+        // Shrink (or grow) to fit the cell without changing the aspect ratio, and center it.
+        let (width, height) = fit((img.width(), img.height()), options.cell);
+        // The same resize as the resize operation, so --linear and alpha are handled alike.
+        let thumbnail = ops::Operation::Resize(width, height)
+            .apply(img, options.linear)
+            .to_rgba32f();
+        let x = left + cell_width.saturating_sub(thumbnail.width()) / 2;
+        let y = top + cell_height.saturating_sub(thumbnail.height()) / 2;
+        imageops::overlay(&mut sheet, &thumbnail, x as i64, y as i64);
+
+        if options.labels {
+            let rendered = text::render_text(label, font, &label_options);
+            // Names wider than the cell are cut off on both sides.
+            let (width, height) = rendered.dimensions();
+            let visible = width.min(cell_width);
+            let rendered = imageops::crop_imm(&rendered, (width - visible) / 2, 0, visible, height);
+            let x = left + cell_width.saturating_sub(visible) / 2;
+            let rendered = DynamicImage::ImageRgba8(rendered.to_image()).to_rgba32f();
+            imageops::overlay(&mut sheet, &rendered, x as i64, (top + cell_height) as i64);
+        }
+    }
+
+    let bits = images
+        .iter()
+        .map(|(_, img)| depth::bits(img.color()))
+        .max()
+        .unwrap_or(8);
+    // Leave out the alpha channel when there's no transparency to store
+    let color = depth::color_type(bits, true, options.background[3] < 255);
+    depth::convert(DynamicImage::ImageRgba32F(sheet), color)
+}
+
+// The largest size with the same aspect ratio as `size` that fits in `cell`, at least 1x1.
+fn fit((width, height): (u32, u32), (cell_width, cell_height): (u32, u32)) -> (u32, u32) {
+    let scale = (cell_width as f64 / width as f64).min(cell_height as f64 / height as f64);
+    (
+        ((width as f64 * scale).round() as u32).clamp(1, cell_width),
+        ((height as f64 * scale).round() as u32).clamp(1, cell_height),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    fn squares(count: usize, color: Rgba<u8>) -> Vec<(String, DynamicImage)> {
+        (0..count)
+            .map(|i| {
+                let img = RgbaImage::from_pixel(10, 10, color);
+                (format!("{}.png", i), DynamicImage::ImageRgba8(img))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn grid_has_room_for_every_cell() {
+        let options = MontageOptions {
+            columns: Some(2),
+            cell: (20, 10),
+            spacing: 3,
+            ..MontageOptions::default()
+        };
+        let sheet = montage(&squares(5, Rgba([255, 0, 0, 255])), &Font::Bitmap, &options);
+        // 2 columns and 3 rows, with spacing around and between them
+        assert_eq!(sheet.width(), 2 * (20 + 3) + 3);
+        assert_eq!(sheet.height(), 3 * (10 + 3) + 3);
+    }
+
+    #[test]
+    fn thumbnails_are_centered_on_the_background() {
+        let options = MontageOptions {
+            cell: (20, 10),
+            spacing: 2,
+            background: Rgba([0, 0, 255, 255]),
+            ..MontageOptions::default()
+        };
+        let sheet =
+            montage(&squares(1, Rgba([255, 0, 0, 255])), &Font::Bitmap, &options).into_rgba8();
+        assert_eq!(sheet.dimensions(), (24, 14));
+        let blue = Rgba([0, 0, 255, 255]);
+        let red = Rgba([255, 0, 0, 255]);
+        for (x, y, pixel) in sheet.enumerate_pixels() {
+            let inside = (7..17).contains(&x) && (2..12).contains(&y);
+            assert_eq!(*pixel, if inside { red } else { blue }, "at {},{}", x, y);
+        }
+    }
+
+    #[test]
+    fn labels_add_a_strip_under_each_row() {
+        let options = MontageOptions {
+            columns: Some(1),
+            cell: (30, 30),
+            spacing: 0,
+            labels: true,
+            ..MontageOptions::default()
+        };
+        let sheet = montage(&squares(2, Rgba([255, 0, 0, 255])), &Font::Bitmap, &options);
+        // At 14 pixels the bitmap fallback is the 8x13 font
+        assert_eq!(sheet.height(), 2 * (30 + 13));
+    }
+
+    #[test]
+    fn fitting_keeps_the_aspect_ratio() {
+        assert_eq!(fit((100, 50), (20, 20)), (20, 10));
+        assert_eq!(fit((5, 10), (20, 20)), (10, 20));
+        assert_eq!(fit((1000, 1), (10, 10)), (10, 1));
+    }
+}
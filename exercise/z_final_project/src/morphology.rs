@@ -0,0 +1,415 @@
+// Mathematical morphology: growing and shrinking the bright parts of an image.
+//
+// Erosion replaces each pixel with the darkest one under the structuring element centered on it,
+// and dilation with the brightest, so on a black-and-white image erosion eats away white areas and
+// dilation grows them.  The rest are built from those two:
+// - open: erode, then dilate.  Removes white specks smaller than the element.
+// - close: dilate, then erode.  Fills black specks and gaps smaller than the element.
+// - top-hat: the image minus its opening, which keeps just the small bright details.
+// - black-hat: the closing minus the image, which keeps just the small dark details.
+// - gradient: the dilation minus the erosion, which outlines shapes.
+//
+// Color images are done one channel at a time; alpha is left alone.  Pixels beyond the edges count
+// as copies of the nearest edge pixel, so nothing grows in from the borders.
+//
+// Each row of the element is a run of pixels, and the darkest (or brightest) pixel of every run of
+// a given length along an image row takes a constant number of comparisons per pixel (van Herk,
+// Gil and Werman), so big elements cost their height rather than their area.
+
+use crate::{depth, parallel};
+use image::{DynamicImage, GrayImage, Luma};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Erode,
+    Dilate,
+    Open,
+    Close,
+    TopHat,
+    BlackHat,
+    Gradient,
+}
+
+impl Operation {
+    pub const NAMES: &'static str = "erode, dilate, open, close, tophat, blackhat, gradient";
+
+    pub fn from_name(name: &str) -> Option<Operation> {
+        match name {
+            "erode" => Some(Operation::Erode),
+            "dilate" => Some(Operation::Dilate),
+            "open" => Some(Operation::Open),
+            "close" => Some(Operation::Close),
+            "tophat" | "top-hat" => Some(Operation::TopHat),
+            "blackhat" | "black-hat" => Some(Operation::BlackHat),
+            "gradient" => Some(Operation::Gradient),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Square,
+    Disk,
+    Cross,
+}
+
+impl Shape {
+    pub fn from_name(name: &str) -> Option<Shape> {
+        match name {
+            "square" => Some(Shape::Square),
+            "disk" => Some(Shape::Disk),
+            "cross" => Some(Shape::Cross),
+            _ => None,
+        }
+    }
+}
+
+// A structuring element, as runs of pixels: (row, first column, last column), relative to its
+// center.
+#[derive(Clone, Debug)]
+pub struct Element {
+    runs: Vec<(isize, isize, isize)>,
+}
+
+impl Element {
+    // A shape reaching `radius` pixels from the center: 1 makes a 3x3 square.
+    pub fn new(shape: Shape, radius: u32) -> Element {
+        let r = radius as isize;
+        let runs = (-r..=r)
+            .map(|dy| match shape {
+                Shape::Square => (dy, -r, r),
+                // Rounding the radius up by half a pixel gives rounder small disks.
+                Shape::Disk => {
+                    let half = ((r * r + r - dy * dy) as f64).sqrt().floor() as isize;
+                    (dy, -half, half)
+                }
+                Shape::Cross if dy == 0 => (dy, -r, r),
+                Shape::Cross => (dy, 0, 0),
+            })
+            .collect();
+        Element { runs }
+    }
+
+    // An element drawn as an image: its white pixels, centered on the middle of the image.
+    pub fn from_image(img: &DynamicImage) -> Result<Element, String> {
+        let mask = img.to_luma32f();
+        let (cx, cy) = (img.width() as isize / 2, img.height() as isize / 2);
+        let mut runs = Vec::new();
+        for (y, row) in mask.rows().enumerate() {
+            let mut start = None;
+            // One past the end, so that a run reaching the right edge ends.
+            for (x, p) in row.map(|p| p[0]).chain([0.0]).enumerate() {
+                match (p > 0.5, start) {
+                    (true, None) => start = Some(x),
+                    (false, Some(first)) => {
+                        runs.push((y as isize - cy, first as isize - cx, x as isize - 1 - cx));
+                        start = None;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if runs.is_empty() {
+            return Err("The structuring element has no white pixels".to_string());
+        }
+        Ok(Element { runs })
+    }
+
+    // How far the element reaches from its center, sideways
+    fn reach(&self) -> usize {
+        self.runs
+            .iter()
+            .map(|&(_, first, last)| first.abs().max(last.abs()) as usize)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+// One channel of an image
+#[derive(Clone)]
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+// Run `operation` with `element`, eroding and dilating `iterations` times at each step.
+pub fn morph(
+    img: &DynamicImage,
+    operation: Operation,
+    element: &Element,
+    iterations: u32,
+) -> DynamicImage {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let erode = |plane: Plane| (0..iterations).fold(plane, |p, _| extreme(&p, element, f32::min));
+    let dilate = |plane: Plane| (0..iterations).fold(plane, |p, _| extreme(&p, element, f32::max));
+    let run = |plane: Plane| -> Plane {
+        match operation {
+            Operation::Erode => erode(plane),
+            Operation::Dilate => dilate(plane),
+            Operation::Open => dilate(erode(plane)),
+            Operation::Close => erode(dilate(plane)),
+            Operation::TopHat => {
+                let opened = dilate(erode(plane.clone()));
+                difference(&plane, &opened)
+            }
+            Operation::BlackHat => {
+                let closed = erode(dilate(plane.clone()));
+                difference(&closed, &plane)
+            }
+            Operation::Gradient => {
+                let dilated = dilate(plane.clone());
+                difference(&dilated, &erode(plane))
+            }
+        }
+    };
+
+    // Gray images only need the one channel.
+    let mut rgba = img.to_rgba32f();
+    let channels = if img.color().has_color() { 3 } else { 1 };
+    for c in 0..channels {
+        let plane = Plane {
+            width,
+            height,
+            data: rgba.pixels().map(|p| p[c]).collect(),
+        };
+        let done = run(plane);
+        for (p, &v) in rgba.pixels_mut().zip(&done.data) {
+            if channels == 1 {
+                p[0] = v;
+                p[1] = v;
+                p[2] = v;
+            } else {
+                p[c] = v;
+            }
+        }
+    }
+    depth::convert(DynamicImage::ImageRgba32F(rgba), img.color())
+}
+
+// Turn the image black and white: white where its brightness is at least `level` (in 8-bit
+// levels), black elsewhere.
+pub fn threshold(img: &DynamicImage, level: u8) -> DynamicImage {
+    let luma = img.to_luma8();
+    DynamicImage::ImageLuma8(GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        if luma.get_pixel(x, y)[0] >= level {
+            Luma([255])
+        } else {
+            Luma([0])
+        }
+    }))
+}
+
+// `a - b`, pixel by pixel, for the hats and the gradient (which can't go below 0).
+fn difference(a: &Plane, b: &Plane) -> Plane {
+    Plane {
+        width: a.width,
+        height: a.height,
+        data: a.data.iter().zip(&b.data).map(|(a, b)| a - b).collect(),
+    }
+}
+
+// Erode (with `f32::min`) or dilate (with `f32::max`) once.
+fn extreme(plane: &Plane, element: &Element, pick: fn(f32, f32) -> f32) -> Plane {
+    let (width, height) = (plane.width, plane.height);
+    if width == 0 || height == 0 {
+        return plane.clone();
+    }
+    // Rows are padded with copies of their end pixels, out to the element's reach.
+    let pad = element.reach();
+    let stride = width + 2 * pad;
+
+    // For each length of run in the element, the extreme of every run of that length along every
+    // row, by where the run starts in the padded row.
+    let mut lengths: Vec<usize> = element
+        .runs
+        .iter()
+        .map(|&(_, first, last)| (last - first + 1) as usize)
+        .collect();
+    lengths.sort_unstable();
+    lengths.dedup();
+    let extremes: Vec<Vec<f32>> = lengths
+        .iter()
+        .map(|&length| {
+            let mut out = vec![0.0; stride * height];
+            parallel::for_each_row(&mut out, stride, |y, out_row| {
+                let row = &plane.data[y * width..(y + 1) * width];
+                let padded: Vec<f32> = (0..stride)
+                    .map(|i| row[i.saturating_sub(pad).min(width - 1)])
+                    .collect();
+                window_extremes(&padded, length, pick, out_row);
+            });
+            out
+        })
+        .collect();
+
+    let mut data = vec![0.0; width * height];
+    parallel::for_each_row(&mut data, width, |y, out_row| {
+        for (x, out) in out_row.iter_mut().enumerate() {
+            *out = element
+                .runs
+                .iter()
+                .map(|&(dy, first, last)| {
+                    let row = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+                    let which = lengths
+                        .binary_search(&((last - first + 1) as usize))
+                        .unwrap();
+                    let start = (x as isize + first + pad as isize) as usize;
+                    extremes[which][row * stride + start]
+                })
+                .reduce(pick)
+                .unwrap();
+        }
+    });
+    Plane {
+        width,
+        height,
+        data,
+    }
+}
+
+// out[i] = the extreme of row[i..i + length], for every i where that fits, in three comparisons per
+// value: split the row into blocks of `length`, and take running extremes forward from the start
+// of each block and backward from its end.  Every window covers the end of one block and the
+// start of the next.
+fn window_extremes(row: &[f32], length: usize, pick: fn(f32, f32) -> f32, out: &mut [f32]) {
+    if length > row.len() {
+        return;
+    }
+    let mut forward = row.to_vec();
+    let mut backward = row.to_vec();
+    for i in 1..row.len() {
+        if i % length != 0 {
+            forward[i] = pick(forward[i - 1], row[i]);
+        }
+    }
+    for i in (0..row.len() - 1).rev() {
+        if (i + 1) % length != 0 {
+            backward[i] = pick(backward[i + 1], row[i]);
+        }
+    }
+    for i in 0..=row.len() - length {
+        out[i] = pick(backward[i], forward[i + length - 1]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(width: u32, height: u32, white: &[(u32, u32)]) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| {
+            Luma([if white.contains(&(x, y)) { 255 } else { 0 }])
+        }))
+    }
+
+    fn white_pixels(img: &DynamicImage) -> Vec<(u32, u32)> {
+        let img = img.to_luma8();
+        img.enumerate_pixels()
+            .filter(|(_, _, p)| p[0] == 255)
+            .map(|(x, y, _)| (x, y))
+            .collect()
+    }
+
+    #[test]
+    fn names_parse() {
+        assert_eq!(Operation::from_name("top-hat"), Some(Operation::TopHat));
+        assert_eq!(Operation::from_name("blackhat"), Some(Operation::BlackHat));
+        assert_eq!(Operation::from_name("thin"), None);
+        assert_eq!(Shape::from_name("disk"), Some(Shape::Disk));
+        assert_eq!(Shape::from_name("circle"), None);
+    }
+
+    #[test]
+    fn elements_have_their_shapes() {
+        assert_eq!(
+            Element::new(Shape::Cross, 1).runs,
+            vec![(-1, 0, 0), (0, -1, 1), (1, 0, 0)]
+        );
+        assert_eq!(
+            Element::new(Shape::Disk, 2).runs,
+            vec![(-2, -1, 1), (-1, -2, 2), (0, -2, 2), (1, -2, 2), (2, -1, 1)]
+        );
+        // The same cross, drawn
+        let drawn = gray(3, 3, &[(1, 0), (0, 1), (1, 1), (2, 1), (1, 2)]);
+        assert_eq!(
+            Element::from_image(&drawn).unwrap().runs,
+            Element::new(Shape::Cross, 1).runs
+        );
+        assert!(Element::from_image(&gray(3, 3, &[])).is_err());
+    }
+
+    #[test]
+    fn dilate_and_erode_a_point() {
+        let cross = Element::new(Shape::Cross, 1);
+        let point = gray(5, 5, &[(2, 2)]);
+        let plus = morph(&point, Operation::Dilate, &cross, 1);
+        assert_eq!(
+            white_pixels(&plus),
+            vec![(2, 1), (1, 2), (2, 2), (3, 2), (2, 3)]
+        );
+        assert_eq!(morph(&plus, Operation::Erode, &cross, 1), point);
+        // Opening removes specks smaller than the element, closing fills holes
+        let square = Element::new(Shape::Square, 1);
+        assert_eq!(
+            white_pixels(&morph(&point, Operation::Open, &square, 1)),
+            vec![]
+        );
+        let all: Vec<(u32, u32)> = (0..5).flat_map(|y| (0..5).map(move |x| (x, y))).collect();
+        let holey: Vec<(u32, u32)> = all.iter().copied().filter(|&p| p != (2, 2)).collect();
+        let holey = gray(5, 5, &holey);
+        assert_eq!(
+            white_pixels(&morph(&holey, Operation::Close, &square, 1)),
+            all
+        );
+        // White up to the border stays white: nothing grows in from outside
+        assert_eq!(
+            morph(&gray(5, 5, &all), Operation::Erode, &square, 2),
+            gray(5, 5, &all)
+        );
+    }
+
+    #[test]
+    fn hats_and_gradient() {
+        let square = Element::new(Shape::Square, 1);
+        let point = gray(5, 5, &[(2, 2)]);
+        assert_eq!(morph(&point, Operation::TopHat, &square, 1), point);
+        let hole = DynamicImage::ImageLuma8(GrayImage::from_fn(5, 5, |x, y| {
+            Luma([if (x, y) == (2, 2) { 0 } else { 255 }])
+        }));
+        assert_eq!(morph(&hole, Operation::BlackHat, &square, 1), point);
+        let gradient = morph(&point, Operation::Gradient, &square, 1);
+        assert_eq!(white_pixels(&gradient).len(), 9);
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let img = GrayImage::from_fn(13, 9, |x, y| Luma([((x * 37 + y * 91) % 256) as u8]));
+        let element = Element::new(Shape::Disk, 2);
+        let eroded = morph(
+            &DynamicImage::ImageLuma8(img.clone()),
+            Operation::Erode,
+            &element,
+            1,
+        );
+        let eroded = eroded.to_luma8();
+        for (x, y, pixel) in eroded.enumerate_pixels() {
+            let mut darkest = 255;
+            for &(dy, first, last) in &element.runs {
+                for dx in first..=last {
+                    let sx = (x as isize + dx).clamp(0, 12) as u32;
+                    let sy = (y as isize + dy).clamp(0, 8) as u32;
+                    darkest = darkest.min(img.get_pixel(sx, sy)[0]);
+                }
+            }
+            assert_eq!(pixel[0], darkest, "at {},{}", x, y);
+        }
+    }
+
+    #[test]
+    fn threshold_splits_at_the_level() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_fn(4, 1, |x, _| Luma([x as u8 * 50])));
+        assert_eq!(white_pixels(&threshold(&img, 100)), vec![(2, 0), (3, 0)]);
+    }
+}
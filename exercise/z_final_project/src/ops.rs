@@ -0,0 +1,648 @@
+// The basic single-image operations: blurring, cropping, resizing and color adjustments, the
+// effects in effects.rs and the distortions in warp.rs.
+//
+// They can run on a whole image in memory (`Operation::apply`) or, for images too big for that, a
+// band of rows at a time (see stream.rs).  Both share the per-pixel math here so they give the
+// same results.  The effects and distortions only run on whole images.
+//
+// Chains of operations are written the way they're typed on the command line, e.g.
+// "blur 2.5 invert crop 0,0 640x480", and saved in recipe files, one or more operations per line
+// with # starting a comment.
+
+use crate::{alpha, blur, depth, effects, gamma, parallel, resize, warp};
+use image::{ColorType, DynamicImage, Rgba};
+use std::fmt;
+
+// Changes one pixel in place
+pub type PixelFn = Box<dyn Fn(&mut Rgba<f32>) + Send + Sync>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    // Gaussian blur with this sigma
+    Blur(f32, blur::Method),
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    // A size of 0 in either direction keeps the aspect ratio
+    Resize(u32, u32),
+    Invert,
+    Grayscale,
+    // In 8-bit levels, whatever the image's depth: 255 turns black into white
+    Brighten(i32),
+    Vignette {
+        amount: f32,
+        falloff: f32,
+    },
+    // Standard deviation in 8-bit levels
+    Grain {
+        amount: f32,
+        seed: u64,
+    },
+    // Block size
+    Pixelate(u32),
+    // Radius
+    OilPaint(u32),
+    // Cell size in pixels, and degrees to turn the screens by
+    Halftone {
+        size: f32,
+        angle: f32,
+    },
+    Warp(warp::Transform, warp::Interpolation),
+}
+
+impl Operation {
+    pub fn apply(&self, img: &DynamicImage, linear: bool) -> DynamicImage {
+        match *self {
+            Operation::Blur(sigma, method) => {
+                let blurred = gamma::in_linear_light(img, linear, |img| {
+                    alpha::premultiplied(img, |img| {
+                        DynamicImage::ImageRgba32F(blur::blur(&img.to_rgba32f(), sigma, method))
+                    })
+                });
+                depth::convert(blurred, img.color())
+            }
+            Operation::Crop { .. } => {
+                let (x, y, width, height) = self.crop_rect((img.width(), img.height()));
+                img.crop_imm(x, y, width, height)
+            }
+            Operation::Resize(..) => {
+                let (width, height) = self.output_size((img.width(), img.height()));
+                let resized = gamma::in_linear_light(img, linear, |img| {
+                    alpha::premultiplied(img, |img| {
+                        DynamicImage::ImageRgba32F(resize::resize(&img.to_rgba32f(), width, height))
+                    })
+                });
+                depth::convert(resized, img.color())
+            }
+            Operation::Vignette { amount, falloff } => {
+                let darkened = gamma::in_linear_light(img, linear, |img| {
+                    DynamicImage::ImageRgba32F(effects::vignette(
+                        &img.to_rgba32f(),
+                        amount,
+                        falloff,
+                    ))
+                });
+                depth::convert(darkened, img.color())
+            }
+            // Grain is added to sRGB values whatever --linear says (see effects.rs).
+            Operation::Grain { amount, seed } => {
+                let grainy = effects::grain(&img.to_rgba32f(), amount, seed);
+                depth::convert(DynamicImage::ImageRgba32F(grainy), img.color())
+            }
+            Operation::Pixelate(size) => {
+                let pixelated = gamma::in_linear_light(img, linear, |img| {
+                    alpha::premultiplied(img, |img| {
+                        DynamicImage::ImageRgba32F(effects::pixelate(&img.to_rgba32f(), size))
+                    })
+                });
+                depth::convert(pixelated, img.color())
+            }
+            Operation::OilPaint(radius) => {
+                let painted = alpha::premultiplied(img, |img| {
+                    DynamicImage::ImageRgba32F(effects::oil_paint(&img.to_rgba32f(), radius))
+                });
+                depth::convert(painted, img.color())
+            }
+            Operation::Halftone { size, angle } => {
+                let printed = effects::halftone(&img.to_rgba32f(), size, angle);
+                depth::convert(
+                    DynamicImage::ImageRgba32F(printed),
+                    self.output_color(img.color()),
+                )
+            }
+            Operation::Warp(ref transform, interpolation) => {
+                let warped = gamma::in_linear_light(img, linear, |img| {
+                    alpha::premultiplied(img, |img| {
+                        DynamicImage::ImageRgba32F(warp::warp(
+                            &img.to_rgba32f(),
+                            transform,
+                            interpolation,
+                        ))
+                    })
+                });
+                depth::convert(warped, img.color())
+            }
+            _ => {
+                let adjust = self.per_pixel().unwrap();
+                let adjusted = gamma::in_linear_light(img, linear, |img| {
+                    let mut pixels = img.to_rgba32f();
+                    let width = pixels.width() as usize;
+                    parallel::for_each_pixel(&mut pixels, width, &adjust);
+                    DynamicImage::ImageRgba32F(pixels)
+                });
+                depth::convert(adjusted, self.output_color(img.color()))
+            }
+        }
+    }
+
+    // The function that does the work for operations that look at one pixel at a time.
+    pub fn per_pixel(&self) -> Option<PixelFn> {
+        match *self {
+            Operation::Invert => Some(Box::new(|pixel| {
+                for c in 0..3 {
+                    pixel[c] = 1.0 - pixel[c];
+                }
+            })),
+            Operation::Grayscale => Some(Box::new(|pixel| {
+                // Rec. 709 luma, the same weights the image crate uses
+                let luma = 0.2126 * pixel[0] + 0.7152 * pixel[1] + 0.0722 * pixel[2];
+                for c in 0..3 {
+                    pixel[c] = luma;
+                }
+            })),
+            Operation::Brighten(amount) => {
+                let amount = amount as f32 / 255.0;
+                Some(Box::new(move |pixel| {
+                    for c in 0..3 {
+                        pixel[c] = (pixel[c] + amount).clamp(0.0, 1.0);
+                    }
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn output_size(&self, (width, height): (u32, u32)) -> (u32, u32) {
+        match *self {
+            Operation::Crop { .. } => {
+                let (_, _, width, height) = self.crop_rect((width, height));
+                (width, height)
+            }
+            Operation::Resize(0, 0) => (width, height),
+            Operation::Resize(w, 0) => (w, scale(height, w, width)),
+            Operation::Resize(0, h) => (scale(width, h, height), h),
+            Operation::Resize(w, h) => (w, h),
+            Operation::Warp(ref transform, _) => transform.output_size((width, height)),
+            _ => (width, height),
+        }
+    }
+
+    pub fn output_color(&self, color: ColorType) -> ColorType {
+        match self {
+            Operation::Grayscale => depth::color_type(depth::bits(color), false, color.has_alpha()),
+            // Colored inks, even on a gray image
+            Operation::Halftone { .. } => {
+                depth::color_type(depth::bits(color), true, color.has_alpha())
+            }
+            _ => color,
+        }
+    }
+
+    // Whether the operation can run a band of rows at a time, with --tiled.
+    pub fn streams(&self) -> bool {
+        matches!(
+            self,
+            Operation::Blur(..)
+                | Operation::Crop { .. }
+                | Operation::Resize(..)
+                | Operation::Invert
+                | Operation::Grayscale
+                | Operation::Brighten(_)
+        )
+    }
+
+    // The crop rectangle, cut down to what's inside the image (like `DynamicImage::crop_imm`).
+    pub fn crop_rect(&self, (width, height): (u32, u32)) -> (u32, u32, u32, u32) {
+        match *self {
+            Operation::Crop {
+                x,
+                y,
+                width: w,
+                height: h,
+            } => {
+                let (x, y) = (x.min(width), y.min(height));
+                (x, y, w.min(width - x), h.min(height - y))
+            }
+            _ => (0, 0, width, height),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    // The operation's words in a chain, which parse back to the same operation.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operation::Blur(sigma, method) if method == blur::Method::default_for(sigma) => {
+                write!(f, "blur {}", sigma)
+            }
+            Operation::Blur(sigma, blur::Method::Gaussian) => {
+                write!(f, "blur {} --passes 0", sigma)
+            }
+            Operation::Blur(sigma, blur::Method::Box(passes)) => {
+                write!(f, "blur {} --passes {}", sigma, passes)
+            }
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => write!(f, "crop {},{} {}x{}", x, y, width, height),
+            Operation::Resize(width, height) => write!(f, "resize {}x{}", width, height),
+            Operation::Invert => write!(f, "invert"),
+            Operation::Grayscale => write!(f, "grayscale"),
+            Operation::Brighten(amount) => write!(f, "brighten {}", amount),
+            Operation::Vignette { amount, falloff } => {
+                write!(f, "vignette {} --falloff {}", amount, falloff)
+            }
+            Operation::Grain { amount, seed } => write!(f, "grain {} --seed {}", amount, seed),
+            Operation::Pixelate(size) => write!(f, "pixelate {}", size),
+            Operation::OilPaint(radius) => write!(f, "oilpaint {}", radius),
+            Operation::Halftone { size, angle } => {
+                write!(f, "halftone {} --angle {}", size, angle)
+            }
+            Operation::Warp(ref transform, warp::Interpolation::Bilinear) => {
+                write!(f, "{}", transform)
+            }
+            Operation::Warp(ref transform, interpolation) => {
+                write!(f, "{} --interpolation {}", transform, interpolation)
+            }
+        }
+    }
+}
+
+pub const NAMES: &str = "blur [SIGMA] [--passes N], crop X,Y WxH, resize WxH, invert, grayscale, \
+                         brighten AMOUNT, vignette [AMOUNT] [--falloff POWER], \
+                         grain [AMOUNT] [--seed N], pixelate SIZE, oilpaint [RADIUS], \
+                         halftone [SIZE] [--angle DEGREES], affine A,B,C,D,E,F, rotate DEGREES, \
+                         perspective X,Y X,Y X,Y X,Y [--size WxH], swirl DEGREES [--radius R], \
+                         ripple AMPLITUDE WAVELENGTH, polar, unpolar \
+                         (distortions take [--interpolation nearest|bilinear|bicubic])";
+
+// Parse a chain of operations like ["blur", "2.5", "invert"].
+pub fn parse_chain<S: AsRef<str>>(words: &[S]) -> Result<Vec<Operation>, String> {
+    let mut words = words.iter().map(|word| word.as_ref()).peekable();
+    let mut operations = Vec::new();
+    while let Some(name) = words.next() {
+        let mut argument = |what: &str| {
+            words
+                .next()
+                .ok_or_else(|| format!("{} needs {}", name, what))
+        };
+        let operation = match name {
+            "blur" => {
+                let (sigma, flags) = optional_arguments(&mut words, &["--passes"])?;
+                let sigma = sigma.map_or(Ok(2.0), parse_number)?;
+                let method = match flags[0].map(parse_number).transpose()? {
+                    Some(0) => blur::Method::Gaussian,
+                    Some(passes) => blur::Method::Box(passes),
+                    None => blur::Method::default_for(sigma),
+                };
+                Operation::Blur(sigma, method)
+            }
+            "crop" => {
+                let (x, y) = parse_pair(argument("a position X,Y")?)?;
+                let (width, height) = parse_size(argument("a size WxH")?)?;
+                Operation::Crop {
+                    x,
+                    y,
+                    width,
+                    height,
+                }
+            }
+            "resize" => {
+                let (width, height) = parse_size(argument("a size WxH")?)?;
+                Operation::Resize(width, height)
+            }
+            "invert" => Operation::Invert,
+            "grayscale" => Operation::Grayscale,
+            "brighten" => Operation::Brighten(parse_number(argument("an amount")?)?),
+            "vignette" => {
+                let (amount, flags) = optional_arguments(&mut words, &["--falloff"])?;
+                Operation::Vignette {
+                    amount: amount.map_or(Ok(0.5), parse_number)?,
+                    falloff: flags[0].map_or(Ok(2.0), parse_number)?,
+                }
+            }
+            "grain" => {
+                let (amount, flags) = optional_arguments(&mut words, &["--seed"])?;
+                Operation::Grain {
+                    amount: amount.map_or(Ok(16.0), parse_number)?,
+                    seed: flags[0].map_or(Ok(0), parse_number)?,
+                }
+            }
+            "pixelate" => Operation::Pixelate(parse_number(argument("a block size")?)?),
+            "oilpaint" => {
+                let (radius, _) = optional_arguments(&mut words, &[])?;
+                Operation::OilPaint(radius.map_or(Ok(4), parse_number)?)
+            }
+            "halftone" => {
+                let (size, flags) = optional_arguments(&mut words, &["--angle"])?;
+                Operation::Halftone {
+                    size: size.map_or(Ok(8.0), parse_number)?,
+                    angle: flags[0].map_or(Ok(0.0), parse_number)?,
+                }
+            }
+            "affine" => {
+                let text = argument("a matrix A,B,C,D,E,F")?;
+                let numbers = text
+                    .split(',')
+                    .map(|n| parse_number(n.trim()))
+                    .collect::<Result<Vec<f32>, _>>()?;
+                let matrix = numbers.try_into().map_err(|_| {
+                    format!("Expected six numbers like A,B,C,D,E,F, not \"{}\"", text)
+                })?;
+                parse_warp(&mut words, warp::Transform::Affine(matrix))?
+            }
+            "rotate" => {
+                let degrees = parse_number(argument("an angle in degrees")?)?;
+                parse_warp(&mut words, warp::Transform::Rotate(degrees))?
+            }
+            "perspective" => {
+                let mut corners = [(0.0, 0.0); 4];
+                for corner in corners.iter_mut() {
+                    *corner = parse_point(argument("four corners X,Y")?)?;
+                }
+                let (size, interpolation) = warp_flags(&mut words, Some("--size"))?;
+                let transform = warp::Transform::Perspective {
+                    corners,
+                    size: size.map_or(Ok((0, 0)), parse_size)?,
+                };
+                Operation::Warp(checked(transform)?, interpolation)
+            }
+            "swirl" => {
+                let degrees = parse_number(argument("an angle in degrees")?)?;
+                let (radius, interpolation) = warp_flags(&mut words, Some("--radius"))?;
+                let transform = warp::Transform::Swirl {
+                    degrees,
+                    radius: radius.map_or(Ok(0.0), parse_number)?,
+                };
+                Operation::Warp(checked(transform)?, interpolation)
+            }
+            "ripple" => {
+                let amplitude = parse_number(argument("an amplitude")?)?;
+                let wavelength = parse_number(argument("a wavelength")?)?;
+                let transform = warp::Transform::Ripple {
+                    amplitude,
+                    wavelength,
+                };
+                parse_warp(&mut words, transform)?
+            }
+            "polar" => parse_warp(&mut words, warp::Transform::Polar)?,
+            "unpolar" => parse_warp(&mut words, warp::Transform::Unpolar)?,
+            _ => {
+                return Err(format!(
+                    "Unknown operation \"{}\". Use one of: {}",
+                    name, NAMES
+                ))
+            }
+        };
+        operations.push(operation);
+    }
+    Ok(operations)
+}
+
+pub fn parse_recipe(text: &str) -> Result<Vec<Operation>, String> {
+    let mut operations = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let chain = parse_chain(&words).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+        operations.extend(chain);
+    }
+    Ok(operations)
+}
+
+// A recipe file for the operations, one per line.
+pub fn recipe(operations: &[Operation]) -> String {
+    operations.iter().map(|op| format!("{}\n", op)).collect()
+}
+
+// An operation's optional number, and the values of its optional `flags`, which can come in any
+// order.  Stops at the first word that's neither, which starts the next operation.
+fn optional_arguments<'a>(
+    words: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>,
+    flags: &[&str],
+) -> Result<(Option<&'a str>, Vec<Option<&'a str>>), String> {
+    let mut number = None;
+    let mut values = vec![None; flags.len()];
+    loop {
+        match words.peek() {
+            Some(word) if flags.contains(word) => {
+                let flag = words.next().unwrap();
+                let value = words
+                    .next()
+                    .ok_or_else(|| format!("{} needs a value", flag))?;
+                values[flags.iter().position(|&f| f == flag).unwrap()] = Some(value);
+            }
+            Some(word) if number.is_none() && word.parse::<f64>().is_ok() => {
+                number = words.next();
+            }
+            _ => return Ok((number, values)),
+        }
+    }
+}
+
+// A distortion, with its --interpolation if it has one.
+fn parse_warp<'a>(
+    words: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>,
+    transform: warp::Transform,
+) -> Result<Operation, String> {
+    let (_, interpolation) = warp_flags(words, None)?;
+    Ok(Operation::Warp(checked(transform)?, interpolation))
+}
+
+// The value of a distortion's own `flag`, if it has one, and its interpolation.
+fn warp_flags<'a>(
+    words: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>,
+    flag: Option<&str>,
+) -> Result<(Option<&'a str>, warp::Interpolation), String> {
+    let mut names = vec!["--interpolation"];
+    names.extend(flag);
+    let (number, flags) = optional_arguments(words, &names)?;
+    if let Some(number) = number {
+        return Err(format!("Unexpected number \"{}\"", number));
+    }
+    let interpolation = match flags[0] {
+        None => warp::Interpolation::Bilinear,
+        Some(name) => warp::Interpolation::from_name(name).ok_or_else(|| {
+            format!(
+                "Unknown interpolation \"{}\". Use one of: {}",
+                name,
+                warp::Interpolation::NAMES
+            )
+        })?,
+    };
+    Ok((flags.get(1).copied().flatten(), interpolation))
+}
+
+fn checked(transform: warp::Transform) -> Result<warp::Transform, String> {
+    transform.validate()?;
+    Ok(transform)
+}
+
+fn parse_number<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+    text.parse()
+        .map_err(|_| format!("Failed to parse the number \"{}\"", text))
+}
+
+fn parse_pair(text: &str) -> Result<(u32, u32), String> {
+    let (x, y) = text
+        .split_once(',')
+        .ok_or_else(|| format!("Expected a pair of numbers like X,Y, not \"{}\"", text))?;
+    Ok((parse_number(x.trim())?, parse_number(y.trim())?))
+}
+
+fn parse_point(text: &str) -> Result<(f32, f32), String> {
+    let (x, y) = text
+        .split_once(',')
+        .ok_or_else(|| format!("Expected a point like X,Y, not \"{}\"", text))?;
+    Ok((parse_number(x.trim())?, parse_number(y.trim())?))
+}
+
+fn parse_size(text: &str) -> Result<(u32, u32), String> {
+    let (w, h) = text
+        .split_once('x')
+        .ok_or_else(|| format!("Expected a size like 320x240, not \"{}\"", text))?;
+    Ok((parse_number(w)?, parse_number(h)?))
+}
+
+// `length * numerator / denominator`, rounded, but at least 1.
+fn scale(length: u32, numerator: u32, denominator: u32) -> u32 {
+    ((length as f64 * numerator as f64 / denominator.max(1) as f64).round() as u32).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    fn parse(chain: &str) -> Result<Vec<Operation>, String> {
+        parse_chain(&chain.split_whitespace().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn chains_parse() {
+        assert_eq!(
+            parse("blur 2.5 invert crop 0,0 640x480").unwrap(),
+            vec![
+                Operation::Blur(2.5, blur::Method::Gaussian),
+                Operation::Invert,
+                Operation::Crop {
+                    x: 0,
+                    y: 0,
+                    width: 640,
+                    height: 480
+                },
+            ]
+        );
+        // Optional arguments and flags, in any order
+        assert_eq!(
+            parse("blur grain --seed 7 30 vignette --falloff 3").unwrap(),
+            vec![
+                Operation::Blur(2.0, blur::Method::Gaussian),
+                Operation::Grain {
+                    amount: 30.0,
+                    seed: 7
+                },
+                Operation::Vignette {
+                    amount: 0.5,
+                    falloff: 3.0
+                },
+            ]
+        );
+        assert_eq!(
+            parse("blur 10 blur 10 --passes 0 blur 1 --passes 4").unwrap(),
+            vec![
+                Operation::Blur(10.0, blur::Method::Box(3)),
+                Operation::Blur(10.0, blur::Method::Gaussian),
+                Operation::Blur(1.0, blur::Method::Box(4)),
+            ]
+        );
+        assert_eq!(
+            parse("rotate 90 --interpolation nearest").unwrap(),
+            vec![Operation::Warp(
+                warp::Transform::Rotate(90.0),
+                warp::Interpolation::Nearest
+            )]
+        );
+    }
+
+    #[test]
+    fn bad_chains_are_rejected() {
+        for chain in [
+            "blurr 2",
+            "crop 0,0",
+            "crop 0;0 10x10",
+            "resize big",
+            "brighten",
+            "brighten lots",
+            "blur 2 --passes",
+            "rotate 90 --interpolation cubic",
+            "affine 1,0,0,1",
+        ] {
+            assert!(parse(chain).is_err(), "{}", chain);
+        }
+    }
+
+    #[test]
+    fn recipes_skip_comments_and_count_lines() {
+        let text = "# thumbnails\nresize 0x100   # 100 high\n\ninvert grayscale\n";
+        assert_eq!(
+            parse_recipe(text).unwrap(),
+            vec![
+                Operation::Resize(0, 100),
+                Operation::Invert,
+                Operation::Grayscale
+            ]
+        );
+        let e = parse_recipe("invert\nbrighten\n").unwrap_err();
+        assert!(e.starts_with("Line 2:"), "{}", e);
+    }
+
+    #[test]
+    fn operations_print_back_to_themselves() {
+        let chain = "blur 2.5 blur 10 blur 1 --passes 0 blur 1 --passes 5 crop 3,4 50x60 \
+                     resize 0x100 invert grayscale brighten -20 vignette 0.3 --falloff 1.5 \
+                     grain 8 --seed 42 pixelate 6 oilpaint 3 halftone 5 --angle 45 \
+                     affine 1,0.5,0,1,10,-3 rotate 30 --interpolation bicubic \
+                     perspective 0,0 100,10 90,80 5,70 --size 200x100 swirl 90 --radius 40 \
+                     ripple 4 20 polar unpolar --interpolation nearest";
+        let operations = parse(chain).unwrap();
+        assert_eq!(operations.len(), 21);
+        assert_eq!(parse_recipe(&recipe(&operations)).unwrap(), operations);
+        for operation in &operations {
+            assert_eq!(
+                parse(&operation.to_string()).unwrap(),
+                vec![operation.clone()]
+            );
+        }
+    }
+
+    #[test]
+    fn resize_keeps_the_aspect_ratio() {
+        assert_eq!(Operation::Resize(0, 50).output_size((200, 100)), (100, 50));
+        assert_eq!(Operation::Resize(30, 0).output_size((200, 100)), (30, 15));
+        assert_eq!(Operation::Resize(0, 0).output_size((200, 100)), (200, 100));
+        let crop = parse("crop 150,90 100x100").unwrap().remove(0);
+        assert_eq!(crop.crop_rect((200, 100)), (150, 90, 50, 10));
+    }
+
+    #[test]
+    fn small_chain_on_a_known_image() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_fn(4, 2, |x, _| Luma([x as u8 * 60])));
+        let result = parse("crop 1,0 2x2 invert brighten 5")
+            .unwrap()
+            .iter()
+            .fold(img, |img, operation| operation.apply(&img, false))
+            .into_luma8();
+        assert_eq!(result.into_raw(), vec![200, 140, 200, 140]);
+    }
+
+    #[test]
+    fn brighten_honors_linear_light() {
+        let img = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([128])));
+        let brighten = Operation::Brighten(50);
+        assert_eq!(
+            brighten.apply(&img, false).into_luma8().get_pixel(0, 0),
+            &Luma([178])
+        );
+        // 128 is 0.2159 in linear light; adding 50/255 gives 0.4119, which is 171.9 in sRGB
+        assert_eq!(
+            brighten.apply(&img, true).into_luma8().get_pixel(0, 0),
+            &Luma([172])
+        );
+    }
+}
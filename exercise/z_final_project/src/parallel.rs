@@ -0,0 +1,115 @@
+// Running pixel loops on all cores.
+//
+// Images are split into bands of whole rows that run on rayon's thread pool, which has one thread
+// per core unless --threads says otherwise.  Every pixel is computed exactly as it would be by a
+// plain loop, and the bands are the same whatever the number of threads (work that carries running
+// sums from row to row restarts them at each band), so the output is too.
+
+use image::{Pixel, Rgba};
+use rayon::prelude::*;
+
+// Bands per image
+const BANDS: usize = 64;
+
+// Size the thread pool.  Has to happen before anything runs on it.
+pub fn set_threads(threads: usize) -> Result<(), String> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+        .map_err(|e| e.to_string())
+}
+
+// Call `f(y, row)` for every row of `data`, which holds rows of `row_len` values each.
+pub fn for_each_row(data: &mut [f32], row_len: usize, f: impl Fn(usize, &mut [f32]) + Sync) {
+    for_each_band(data, row_len, |first, band| {
+        for (j, row) in band.chunks_exact_mut(row_len).enumerate() {
+            f(first + j, row);
+        }
+    });
+}
+
+// Call `f(first_row, band)` for bands of whole rows of `data`, for work that carries something
+// over from one row to the next.
+pub fn for_each_band(data: &mut [f32], row_len: usize, f: impl Fn(usize, &mut [f32]) + Sync) {
+    if row_len == 0 {
+        return;
+    }
+    let rows = data.len() / row_len;
+    // A few bands per thread on most machines, so that a slow band doesn't hold up the others.
+    let band = rows.div_ceil(BANDS).max(1);
+    data.par_chunks_mut(band * row_len)
+        .enumerate()
+        .for_each(|(i, chunk)| f(i * band, chunk));
+}
+
+// Call `f` on every pixel of RGBA float data.
+pub fn for_each_pixel(data: &mut [f32], width: usize, f: impl Fn(&mut Rgba<f32>) + Sync) {
+    for_each_row(data, width * 4, |_, row| {
+        for pixel in row.chunks_exact_mut(4) {
+            f(Rgba::from_slice_mut(pixel));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{blur, denoise, warp};
+    use image::{DynamicImage, Rgba, Rgba32FImage};
+
+    fn on_threads<T: Send>(threads: usize, f: impl FnOnce() -> T + Send) -> T {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+            .install(f)
+    }
+
+    #[test]
+    fn output_is_the_same_on_any_number_of_threads() {
+        // Uneven values, so that sums in a different order would show
+        let img = Rgba32FImage::from_fn(37, 29, |x, y| {
+            let v = |k: u32| ((x * 7919 + y * 104729 + k * 31337) % 1000) as f32 / 999.0;
+            Rgba([v(0), v(1), v(2), 1.0])
+        });
+        let run = || {
+            let dynamic = DynamicImage::ImageRgba32F(img.clone());
+            vec![
+                blur::blur(&img, 1.7, blur::Method::Gaussian),
+                blur::blur(&img, 6.0, blur::Method::Box(3)),
+                denoise::denoise(&dynamic, denoise::Method::Median { radius: 2 }).into_rgba32f(),
+                denoise::denoise(
+                    &dynamic,
+                    denoise::Method::Bilateral {
+                        spatial: 2.0,
+                        range: 30.0,
+                    },
+                )
+                .into_rgba32f(),
+                denoise::denoise(
+                    &dynamic,
+                    denoise::Method::NonLocalMeans {
+                        strength: 20.0,
+                        search: 3,
+                        patch: 2,
+                    },
+                )
+                .into_rgba32f(),
+                warp::warp(
+                    &img,
+                    &warp::Transform::Rotate(30.0),
+                    warp::Interpolation::Bicubic,
+                ),
+            ]
+        };
+        let one = on_threads(1, run);
+        let four = on_threads(4, run);
+        for (i, (a, b)) in one.iter().zip(&four).enumerate() {
+            let same = a
+                .as_raw()
+                .iter()
+                .zip(b.as_raw())
+                .all(|(a, b)| a.to_bits() == b.to_bits());
+            assert!(same, "result {} differs", i);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+// Showing images in the terminal, for checking results over SSH without copying files around.
+//
+// - Truecolor: each character cell is an upper half block (▀) whose foreground is one pixel and
+//   whose background is the pixel below, in 24-bit ANSI colors.
+// - 256 colors: the same, with each pixel mapped to the nearest of the xterm 256-color palette's
+//   color cube and gray ramp.  For terminals that don't do 24-bit color.
+// - Sixel: real pixels, in terminals that support the DEC sixel graphics protocol, quantized to the
+//   same 240 colors.
+//
+// Transparent areas show a gray checkerboard.  Images are only ever scaled down.
+
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgb, RgbImage};
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    TrueColor,
+    Color256,
+    Sixel,
+}
+
+// Pixels per character cell, assumed for sizing sixel images.  Terminals can't be asked without
+// putting them into raw mode, and most fonts are close to this.
+const CELL_WIDTH: u32 = 10;
+const CELL_HEIGHT: u32 = 20;
+
+impl Mode {
+    pub const NAMES: &'static str = "truecolor, 256, sixel";
+
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "truecolor" | "24bit" => Some(Mode::TrueColor),
+            "256" => Some(Mode::Color256),
+            "sixel" => Some(Mode::Sixel),
+            _ => None,
+        }
+    }
+
+    // Guess what the terminal supports from the environment: sixel for the terminals known to do
+    // it, truecolor where COLORTERM says so, and 256 colors otherwise, which nearly everything has.
+    pub fn detect() -> Mode {
+        let var = |name| std::env::var(name).unwrap_or_default().to_lowercase();
+        let (term, program, colorterm) = (var("TERM"), var("TERM_PROGRAM"), var("COLORTERM"));
+        let sixel_terms = ["mlterm", "foot", "contour", "yaft"];
+        let sixel_programs = ["wezterm", "iterm.app", "mintty"];
+        if term.contains("sixel")
+            || sixel_terms.iter().any(|t| term.starts_with(t))
+            || sixel_programs.contains(&program.as_str())
+        {
+            Mode::Sixel
+        } else if colorterm == "truecolor" || colorterm == "24bit" || term.contains("direct") {
+            Mode::TrueColor
+        } else {
+            Mode::Color256
+        }
+    }
+}
+
+// The terminal's size in character cells (80x24 if it can't be found out), less a line for the
+// prompt after the image.
+pub fn available_size() -> (u32, u32) {
+    let (columns, rows) = match terminal_size::terminal_size() {
+        Some((terminal_size::Width(w), terminal_size::Height(h))) if w > 0 && h > 0 => {
+            (w as u32, h as u32)
+        }
+        _ => (80, 24),
+    };
+    (columns, rows.saturating_sub(1).max(1))
+}
+
+// Render the image to fit in `columns` x `rows` character cells.
+pub fn render(img: &DynamicImage, mode: Mode, (columns, rows): (u32, u32)) -> String {
+    // Half blocks make character cells two pixels high; sixels are real pixels.
+    let (max_width, max_height) = match mode {
+        Mode::Sixel => (columns * CELL_WIDTH, rows * CELL_HEIGHT),
+        _ => (columns, rows * 2),
+    };
+    let scale = (max_width as f64 / img.width() as f64)
+        .min(max_height as f64 / img.height() as f64)
+        .min(1.0);
+    let width = ((img.width() as f64 * scale).round() as u32).max(1);
+    let height = ((img.height() as f64 * scale).round() as u32).max(1);
+    let small = img.resize_exact(width, height, FilterType::Triangle);
+    let pixels = over_checkerboard(&small);
+    match mode {
+        Mode::TrueColor => half_blocks(&pixels, |c| format!("2;{};{};{}", c[0], c[1], c[2])),
+        Mode::Color256 => half_blocks(&pixels, |c| format!("5;{}", xterm_color(c))),
+        Mode::Sixel => sixel(&pixels),
+    }
+}
+
+// Flatten the image onto a checkerboard, so that transparency shows.
+fn over_checkerboard(img: &DynamicImage) -> RgbImage {
+    let rgba = img.to_rgba8();
+    RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let p = rgba.get_pixel(x, y);
+        let square = if (x / 8 + y / 8) % 2 == 0 {
+            102.0
+        } else {
+            153.0
+        };
+        let alpha = p[3] as f32 / 255.0;
+        let mix = |c: u8| (c as f32 * alpha + square * (1.0 - alpha)).round() as u8;
+        Rgb([mix(p[0]), mix(p[1]), mix(p[2])])
+    })
+}
+
+// Rows of ▀ characters, with the color escape code arguments from `color` (after the 38; or 48;).
+fn half_blocks(img: &RgbImage, color: impl Fn(&Rgb<u8>) -> String) -> String {
+    let mut out = String::new();
+    for y in (0..img.height()).step_by(2) {
+        for x in 0..img.width() {
+            let top = color(img.get_pixel(x, y));
+            if y + 1 < img.height() {
+                let bottom = color(img.get_pixel(x, y + 1));
+                write!(out, "\x1b[38;{}m\x1b[48;{}m▀", top, bottom).unwrap();
+            } else {
+                // An odd last row only fills the top half of its cells.
+                write!(out, "\x1b[38;{}m\x1b[49m▀", top).unwrap();
+            }
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+// The levels of the xterm palette's 6x6x6 color cube (colors 16 to 231)
+const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+// The nearest color in the xterm 256-color palette, leaving out the first 16, which terminals
+// tend to redefine.
+fn xterm_color(c: &Rgb<u8>) -> u8 {
+    let nearest_level = |v: u8| {
+        (0..6)
+            .min_by_key(|&i| (CUBE[i] as i32 - v as i32).abs())
+            .unwrap()
+    };
+    let (r, g, b) = (
+        nearest_level(c[0]),
+        nearest_level(c[1]),
+        nearest_level(c[2]),
+    );
+    let cube = Rgb([CUBE[r], CUBE[g], CUBE[b]]);
+
+    // Grays 232 to 255 run from 8 to 238 in steps of 10.
+    let mean = (c[0] as i32 + c[1] as i32 + c[2] as i32) / 3;
+    let step = ((mean - 8 + 5) / 10).clamp(0, 23);
+    let level = (8 + step * 10) as u8;
+    let gray = Rgb([level, level, level]);
+
+    if distance(c, &gray) < distance(c, &cube) {
+        232 + step as u8
+    } else {
+        16 + (36 * r + 6 * g + b) as u8
+    }
+}
+
+// The color of xterm palette entry `index` (16 to 255).
+fn xterm_rgb(index: u8) -> Rgb<u8> {
+    if index >= 232 {
+        let level = 8 + (index - 232) * 10;
+        Rgb([level, level, level])
+    } else {
+        let i = (index - 16) as usize;
+        Rgb([CUBE[i / 36], CUBE[i / 6 % 6], CUBE[i % 6]])
+    }
+}
+
+fn distance(a: &Rgb<u8>, b: &Rgb<u8>) -> i32 {
+    (0..3).map(|c| (a[c] as i32 - b[c] as i32).pow(2)).sum()
+}
+
+// A sixel image: the palette, then bands six pixels high, each drawn once per color in it, where a
+// character sets the pixels of that color in one column of the band.
+fn sixel(img: &RgbImage) -> String {
+    let (width, height) = img.dimensions();
+    let indexes: Vec<u8> = img.pixels().map(xterm_color).collect();
+
+    let mut out = format!("\x1bPq\"1;1;{};{}", width, height);
+    for index in 16..=255u8 {
+        let c = xterm_rgb(index);
+        let percent = |v: u8| (v as u32 * 100 + 127) / 255;
+        write!(
+            out,
+            "#{};2;{};{};{}",
+            index,
+            percent(c[0]),
+            percent(c[1]),
+            percent(c[2])
+        )
+        .unwrap();
+    }
+    for band in (0..height).step_by(6) {
+        let rows = (height - band).min(6);
+        let mut used = [false; 256];
+        for y in band..band + rows {
+            for x in 0..width {
+                used[indexes[(y * width + x) as usize] as usize] = true;
+            }
+        }
+        for index in (0..256).filter(|&i| used[i]) {
+            let columns: Vec<u8> = (0..width)
+                .map(|x| {
+                    let bits = (0..rows)
+                        .filter(|&r| indexes[((band + r) * width + x) as usize] as usize == index)
+                        .fold(0, |bits, r| bits | 1 << r);
+                    63 + bits as u8
+                })
+                .collect();
+            write!(out, "#{}", index).unwrap();
+            run_length(&columns, &mut out);
+            // Back to the start of the band for the next color
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\\n");
+    out
+}
+
+// Sixel characters, with runs written as !COUNT followed by the character.
+fn run_length(chars: &[u8], out: &mut String) {
+    let mut i = 0;
+    while i < chars.len() {
+        let run = chars[i..].iter().take_while(|&&c| c == chars[i]).count();
+        if run > 3 {
+            write!(out, "!{}{}", run, chars[i] as char).unwrap();
+        } else {
+            for _ in 0..run {
+                out.push(chars[i] as char);
+            }
+        }
+        i += run;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_map_to_the_cube_and_the_grays() {
+        assert_eq!(xterm_color(&Rgb([0, 0, 0])), 16);
+        assert_eq!(xterm_color(&Rgb([255, 255, 255])), 231);
+        assert_eq!(xterm_color(&Rgb([255, 0, 0])), 196);
+        assert_eq!(xterm_color(&Rgb([100, 130, 180])), 16 + 36 + 2 * 6 + 3);
+        // Grays between the cube's levels go to the gray ramp
+        assert_eq!(xterm_color(&Rgb([128, 128, 128])), 244);
+        assert_eq!(xterm_color(&Rgb([10, 9, 8])), 232);
+        // Every palette color maps to itself (the ramp has no exact white or black, so those
+        // stay in the cube)
+        for index in 16..=255u8 {
+            assert_eq!(xterm_color(&xterm_rgb(index)), index);
+        }
+    }
+
+    #[test]
+    fn runs_longer_than_three_are_counted() {
+        let mut out = String::new();
+        run_length(b"????@@@AAAAAAB", &mut out);
+        assert_eq!(out, "!4?@@@!6AB");
+    }
+
+    #[test]
+    fn half_blocks_take_two_rows_per_line() {
+        let img = RgbImage::from_fn(3, 5, |x, _| Rgb([x as u8 * 100, 0, 0]));
+        let out = half_blocks(&img, |c| format!("2;{};{};{}", c[0], c[1], c[2]));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for line in &lines {
+            assert_eq!(line.matches('▀').count(), 3);
+            assert!(line.ends_with("\x1b[0m"));
+        }
+        assert!(lines[0].starts_with("\x1b[38;2;0;0;0m\x1b[48;2;0;0;0m▀\x1b[38;2;100;0;0m"));
+        // The odd row at the bottom leaves the lower halves alone
+        assert_eq!(lines[2].matches("\x1b[49m").count(), 3);
+    }
+
+    #[test]
+    fn render_fits_the_cells() {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(40, 20));
+        // 10 columns wide: 5 pixel rows, so 3 lines
+        let out = render(&img, Mode::TrueColor, (10, 10));
+        assert_eq!(out.lines().count(), 3);
+        assert!(out.lines().all(|line| line.matches('▀').count() == 10));
+        // Never scaled up
+        let out = render(&img, Mode::Color256, (100, 100));
+        assert_eq!(out.lines().count(), 10);
+    }
+
+    #[test]
+    fn sixel_is_wrapped_in_a_dcs_sequence() {
+        let img = RgbImage::from_fn(4, 8, |_, y| {
+            if y < 3 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+        let out = sixel(&img);
+        assert!(out.starts_with("\x1bPq\"1;1;4;8#16;2;0;0;0#17;2;0;0;37"));
+        assert!(out.ends_with("\x1b\\\n"));
+        // Two bands: blue and red in the first (in palette order), only blue in the second
+        let body = &out[out.find("#255;2;93;93;93").unwrap() + 15..out.len() - 3];
+        assert_eq!(body, "#21!4w$#196!4F$-#21!4B$-");
+    }
+}
@@ -0,0 +1,111 @@
+// Resizing with a triangle (bilinear) filter.
+//
+// Like the blur, a resize is a horizontal pass followed by a vertical one.  Each output pixel is a
+// weighted average of the input pixels under a triangle centered on it: one input pixel wide when
+// enlarging, and as wide as the pixels being merged when shrinking, so that every input pixel
+// counts.  Works on RGBA floats; callers take care of premultiplying alpha and of linear light.
+//
+// The tiled path in stream.rs uses the same weights and sums in the same order, a row at a time,
+// so both give identical results.
+
+use crate::parallel;
+use image::Rgba32FImage;
+
+// For each of `output` pixels: the first of `input` pixels it reads, and the weights.
+pub fn weights(input: u32, output: u32) -> Vec<(usize, Vec<f32>)> {
+    let scale = input as f32 / output as f32;
+    let support = scale.max(1.0);
+    (0..output)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * scale;
+            let start = (center - support).floor().max(0.0) as usize;
+            let end = ((center + support).ceil() as usize).min(input as usize);
+            let weights: Vec<f32> = (start..end)
+                .map(|j| (1.0 - ((j as f32 + 0.5 - center) / support).abs()).max(0.0))
+                .collect();
+            let sum: f32 = weights.iter().sum();
+            (start, weights.iter().map(|w| w / sum).collect())
+        })
+        .collect()
+}
+
+// Resize one row of RGBA floats horizontally.
+pub fn resize_row(row: &[f32], columns: &[(usize, Vec<f32>)]) -> Vec<f32> {
+    let mut out = vec![0.0; columns.len() * 4];
+    for (pixel, (start, weights)) in out.chunks_exact_mut(4).zip(columns) {
+        for (i, weight) in weights.iter().enumerate() {
+            let source = (start + i) * 4;
+            for c in 0..4 {
+                pixel[c] += weight * row[source + c];
+            }
+        }
+    }
+    out
+}
+
+pub fn resize(img: &Rgba32FImage, width: u32, height: u32) -> Rgba32FImage {
+    let columns = weights(img.width(), width);
+    let rows = weights(img.height(), height);
+    let (input_width, output_width) = (img.width() as usize, width as usize);
+    let source = img.as_raw();
+
+    let mut horizontal = vec![0.0; output_width * 4 * img.height() as usize];
+    parallel::for_each_row(&mut horizontal, output_width * 4, |y, row| {
+        let input = &source[y * input_width * 4..(y + 1) * input_width * 4];
+        row.copy_from_slice(&resize_row(input, &columns));
+    });
+
+    let mut result = vec![0.0; output_width * 4 * height as usize];
+    parallel::for_each_row(&mut result, output_width * 4, |y, row| {
+        let (start, weights) = &rows[y];
+        for (i, weight) in weights.iter().enumerate() {
+            let above =
+                &horizontal[(start + i) * output_width * 4..(start + i + 1) * output_width * 4];
+            for (o, s) in row.iter_mut().zip(above) {
+                *o += weight * s;
+            }
+        }
+    });
+    Rgba32FImage::from_raw(width, height, result).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn weights_cover_the_input() {
+        for (input, output) in [(10, 3), (3, 10), (7, 7), (1, 5)] {
+            for (start, weights) in weights(input, output) {
+                assert!(start + weights.len() <= input as usize);
+                assert!((weights.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn same_size_and_flat_images_are_unchanged() {
+        let img = Rgba32FImage::from_fn(5, 4, |x, y| {
+            Rgba([x as f32 / 4.0, y as f32 / 3.0, 0.5, 1.0])
+        });
+        assert_eq!(resize(&img, 5, 4), img);
+
+        let flat = Rgba32FImage::from_pixel(9, 6, Rgba([0.25, 0.5, 0.75, 1.0]));
+        for pixel in resize(&flat, 4, 13).pixels() {
+            for c in 0..4 {
+                assert!((pixel[c] - flat.get_pixel(0, 0)[c]).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn halving_averages_pairs() {
+        let img = Rgba32FImage::from_fn(4, 1, |x, _| Rgba([x as f32, 0.0, 0.0, 1.0]));
+        let half = resize(&img, 2, 1);
+        // Each output pixel sits between two inputs weighted 3/4, with the next ones out at 1/4
+        // (and nothing past the edge)
+        assert!((half.get_pixel(0, 0)[0] - 5.0 / 7.0).abs() < 1e-6);
+        assert!((half.get_pixel(1, 0)[0] - 16.0 / 7.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,454 @@
+// A small HTTP server that runs operation chains on uploaded images, for trying transforms from a
+// browser or frontend without a round trip through files.
+//
+//     GET  /health                                 {"status": "ok"}
+//     POST /process?ops=blur+2+invert&format=png   the image as the request body
+//     POST /process                                a JSON body: {"ops": "blur 2 invert",
+//                                                  "format": "png", "image": "<base64>"}
+//
+// "ops" is a chain as on the command line (see ops.rs); in JSON it can also be a list like
+// ["blur 2", "invert"].  The result comes back in the requested format, or the input's format.
+// Errors come back as {"error": "..."} with a 4xx or 5xx status.
+//
+// Requests are limited in body size, in the pixels of the input and of the result, and in time.
+// A request that runs out of time gets a 503, but its work can't be interrupted; it goes on in the
+// background and counts against the number of jobs that may run at once until it's done.
+
+use crate::{codec, ops};
+use base64::Engine;
+use image::io::Reader;
+use image::{DynamicImage, ImageFormat};
+use serde_json::{json, Value};
+use std::io::{Cursor, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+// Requests handled at once, which is also how many images can be processed at once
+const HANDLERS: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    // Bytes in a request body
+    pub max_bytes: usize,
+    // Pixels in the input image, and in the result
+    pub max_pixels: u64,
+    pub timeout: Duration,
+}
+
+struct Context {
+    limits: Limits,
+    linear: bool,
+    options: codec::SaveOptions,
+    // Images being processed, including ones whose requests have timed out
+    jobs: Arc<AtomicUsize>,
+}
+
+// An error response
+struct Failure(u16, String);
+
+type Reply = Result<Response<Cursor<Vec<u8>>>, Failure>;
+
+pub fn run(
+    address: &str,
+    limits: Limits,
+    linear: bool,
+    options: &codec::SaveOptions,
+) -> Result<(), String> {
+    let server = Server::http(address).map_err(|e| format!("{}: {}", address, e))?;
+    let context = Context {
+        limits,
+        linear,
+        options: options.clone(),
+        jobs: Arc::new(AtomicUsize::new(0)),
+    };
+    println!("Listening on http://{}", address);
+    serve(server, context)
+}
+
+// Handle requests until a handler crashes.
+fn serve(server: Server, context: Context) -> Result<(), String> {
+    let (server, context) = (Arc::new(server), Arc::new(context));
+    let handlers: Vec<_> = (0..HANDLERS)
+        .map(|_| {
+            let (server, context) = (server.clone(), context.clone());
+            std::thread::spawn(move || loop {
+                match server.recv() {
+                    Ok(request) => handle(request, &context),
+                    Err(e) => println!("{}", e),
+                }
+            })
+        })
+        .collect();
+    for handler in handlers {
+        handler
+            .join()
+            .map_err(|_| "A request handler crashed".to_string())?;
+    }
+    Ok(())
+}
+
+fn handle(mut request: Request, context: &Context) {
+    let (path, query) = match request.url().split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (request.url().to_string(), String::new()),
+    };
+    let reply = match (request.method(), path.as_str()) {
+        (Method::Get, "/health") => Ok(json_response(200, json!({"status": "ok"}))),
+        (Method::Post, "/process") => process(&mut request, &query, context),
+        (_, "/health") | (_, "/process") => Err(Failure(405, "Method not allowed".to_string())),
+        _ => Err(Failure(404, format!("No such endpoint: {}", path))),
+    };
+    let response = reply.unwrap_or_else(|Failure(status, message)| {
+        json_response(status, json!({ "error": message }))
+    });
+    println!(
+        "{} {} {}",
+        request.method(),
+        request.url(),
+        response.status_code().0
+    );
+    if let Err(e) = request.respond(response) {
+        println!("{}", e);
+    }
+}
+
+fn process(request: &mut Request, query: &str, context: &Context) -> Reply {
+    let limits = context.limits;
+    if request
+        .body_length()
+        .is_some_and(|length| length > limits.max_bytes)
+    {
+        return Err(too_large(limits));
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(limits.max_bytes as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| Failure(400, e.to_string()))?;
+    if body.len() > limits.max_bytes {
+        return Err(too_large(limits));
+    }
+
+    let is_json = request.headers().iter().any(|header| {
+        header.field.equiv("Content-Type") && header.value.as_str().starts_with("application/json")
+    });
+    let (image, chain, format) = if is_json {
+        json_request(&body)?
+    } else {
+        let parameter = |name| query_parameter(query, name);
+        (
+            body,
+            parameter("ops").unwrap_or_default(),
+            parameter("format"),
+        )
+    };
+
+    let words: Vec<&str> = chain.split_whitespace().collect();
+    let operations = ops::parse_chain(&words).map_err(|e| Failure(400, e))?;
+    let input_format =
+        image::guess_format(&image).map_err(|e| Failure(415, format!("Not an image: {}", e)))?;
+    let format = match format {
+        Some(name) => codec::parse_format(&name)
+            .ok_or_else(|| Failure(400, format!("Unknown format: {}", name)))?,
+        None => input_format,
+    };
+
+    // Check the sizes from the header, before decoding anything.
+    let size = Reader::with_format(Cursor::new(&image), input_format)
+        .into_dimensions()
+        .map_err(|e| Failure(400, e.to_string()))?;
+    let output = operations
+        .iter()
+        .fold(size, |size, op| op.output_size(size));
+    for (width, height) in [size, output] {
+        if width as u64 * height as u64 > limits.max_pixels {
+            return Err(Failure(
+                413,
+                format!(
+                    "{}x{} is more than the limit of {} pixels",
+                    width, height, limits.max_pixels
+                ),
+            ));
+        }
+    }
+
+    if context.jobs.fetch_add(1, Ordering::SeqCst) >= HANDLERS {
+        context.jobs.fetch_sub(1, Ordering::SeqCst);
+        return Err(Failure(503, "Too busy; try again later".to_string()));
+    }
+    let (sender, receiver) = mpsc::channel();
+    let (linear, mut options) = (context.linear, context.options.clone());
+    options.format = Some(format);
+    // The job has to own everything, since it may outlive this request.
+    let slot = JobSlot(context.jobs.clone());
+    std::thread::spawn(move || {
+        let _slot = slot;
+        let result = decode(&image, input_format).and_then(|mut img| {
+            for operation in &operations {
+                img = operation.apply(&img, linear);
+            }
+            codec::encode(&img, format, &options)
+        });
+        let _ = sender.send(result);
+    });
+
+    match receiver.recv_timeout(limits.timeout) {
+        Ok(Ok(bytes)) => {
+            Ok(Response::from_data(bytes)
+                .with_header(header("Content-Type", format.to_mime_type())))
+        }
+        Ok(Err(e)) => Err(Failure(422, e)),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(Failure(
+            503,
+            format!("Took longer than {} seconds", limits.timeout.as_secs_f32()),
+        )),
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(Failure(500, "Processing failed".to_string()))
+        }
+    }
+}
+
+// A place among the running jobs, given up when the job ends (even if it panics).
+struct JobSlot(Arc<AtomicUsize>);
+
+impl Drop for JobSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn too_large(limits: Limits) -> Failure {
+    Failure(
+        413,
+        format!(
+            "The request is bigger than the limit of {} bytes",
+            limits.max_bytes
+        ),
+    )
+}
+
+// The image, the chain and the format from a JSON body.
+fn json_request(body: &[u8]) -> Result<(Vec<u8>, String, Option<String>), Failure> {
+    let bad = |message: &str| Failure(400, message.to_string());
+    let value: Value = serde_json::from_slice(body).map_err(|e| Failure(400, e.to_string()))?;
+    let image = value["image"]
+        .as_str()
+        .ok_or_else(|| bad("\"image\" must be a base64 string"))?;
+    let image = base64::engine::general_purpose::STANDARD
+        .decode(image)
+        .map_err(|e| Failure(400, format!("\"image\": {}", e)))?;
+    let chain = match &value["ops"] {
+        Value::Null => String::new(),
+        Value::String(chain) => chain.clone(),
+        Value::Array(steps) => steps
+            .iter()
+            .map(|step| {
+                step.as_str()
+                    .ok_or_else(|| bad("\"ops\" must hold strings"))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .join(" "),
+        _ => return Err(bad("\"ops\" must be a string or a list of strings")),
+    };
+    let format = match &value["format"] {
+        Value::Null => None,
+        Value::String(format) => Some(format.clone()),
+        _ => return Err(bad("\"format\" must be a string")),
+    };
+    Ok((image, chain, format))
+}
+
+// The decoded value of `name` in a query string like "ops=blur+2&format=png".
+fn query_parameter(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (percent_decode(key) == name).then(|| percent_decode(value))
+    })
+}
+
+// Undo URL encoding: + is a space, and %XX is a byte.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn decode(bytes: &[u8], format: ImageFormat) -> Result<DynamicImage, String> {
+    Reader::with_format(Cursor::new(bytes), format)
+        .decode()
+        .map_err(|e| e.to_string())
+}
+
+fn json_response(status: u16, value: Value) -> Response<Cursor<Vec<u8>>> {
+    Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+
+    // Serve on a free port on the loopback interface, in the background.
+    fn start(max_bytes: usize, max_pixels: u64) -> SocketAddr {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        let context = Context {
+            limits: Limits {
+                max_bytes,
+                max_pixels,
+                timeout: Duration::from_secs(30),
+            },
+            linear: false,
+            options: codec::SaveOptions::default(),
+            jobs: Arc::new(AtomicUsize::new(0)),
+        };
+        std::thread::spawn(move || serve(server, context));
+        address
+    }
+
+    // The status and body of the response.
+    fn send(address: SocketAddr, head: &str, content_type: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut request = format!(
+            "{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Type: {}\r\n\
+             Content-Length: {}\r\n\r\n",
+            head,
+            content_type,
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(body);
+        stream.write_all(&request).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..end]).into_owned();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, response[end + 4..].to_vec())
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let img = RgbImage::from_fn(width, height, |x, y| Rgb([x as u8 * 10, y as u8 * 10, 0]));
+        let img = DynamicImage::ImageRgb8(img);
+        codec::encode(&img, ImageFormat::Png, &codec::SaveOptions::default()).unwrap()
+    }
+
+    fn error(body: &[u8]) -> String {
+        let value: Value = serde_json::from_slice(body).unwrap();
+        value["error"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn health() {
+        let address = start(1 << 20, 1 << 20);
+        let (status, body) = send(address, "GET /health", "text/plain", b"");
+        assert_eq!(status, 200);
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value, json!({"status": "ok"}));
+        assert_eq!(send(address, "GET /nowhere", "text/plain", b"").0, 404);
+        assert_eq!(send(address, "GET /process", "text/plain", b"").0, 405);
+    }
+
+    #[test]
+    fn chain_in_the_query_string() {
+        let address = start(1 << 20, 1 << 20);
+        let head = "POST /process?ops=invert+resize+4x3&format=bmp";
+        let (status, body) = send(address, head, "image/png", &png(8, 6));
+        assert_eq!(status, 200);
+        assert_eq!(image::guess_format(&body).unwrap(), ImageFormat::Bmp);
+        let img = image::load_from_memory(&body).unwrap();
+        assert_eq!((img.width(), img.height()), (4, 3));
+
+        let (status, body) = send(address, "POST /process?ops=wobble", "image/png", &png(8, 6));
+        assert_eq!(status, 400, "{}", error(&body));
+    }
+
+    #[test]
+    fn chain_in_a_json_body() {
+        let address = start(1 << 20, 1 << 20);
+        let image = base64::engine::general_purpose::STANDARD.encode(png(8, 6));
+        let body = json!({"ops": ["crop 0,0 5x5", "invert"], "image": image}).to_string();
+        let (status, body) = send(
+            address,
+            "POST /process",
+            "application/json",
+            body.as_bytes(),
+        );
+        assert_eq!(status, 200);
+        // The input's format, since none was asked for
+        assert_eq!(image::guess_format(&body).unwrap(), ImageFormat::Png);
+        let img = image::load_from_memory(&body).unwrap().into_rgb8();
+        assert_eq!(img.dimensions(), (5, 5));
+        assert_eq!(img.get_pixel(1, 2), &Rgb([245, 235, 255]));
+
+        let (status, body) = send(address, "POST /process", "application/json", b"{}");
+        assert_eq!(status, 400);
+        assert_eq!(error(&body), "\"image\" must be a base64 string");
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        let address = start(2000, 100);
+        let (status, body) = send(address, "POST /process", "image/png", &[0; 3000]);
+        assert_eq!(status, 413);
+        assert_eq!(
+            error(&body),
+            "The request is bigger than the limit of 2000 bytes"
+        );
+
+        // Too many pixels in the input, or in the result
+        let (status, body) = send(address, "POST /process", "image/png", &png(20, 20));
+        assert_eq!(status, 413);
+        assert_eq!(error(&body), "20x20 is more than the limit of 100 pixels");
+        let head = "POST /process?ops=resize+50x50";
+        let (status, body) = send(address, head, "image/png", &png(5, 5));
+        assert_eq!(status, 413);
+        assert_eq!(error(&body), "50x50 is more than the limit of 100 pixels");
+
+        let (status, _) = send(address, "POST /process", "image/png", b"not an image");
+        assert_eq!(status, 415);
+    }
+
+    #[test]
+    fn query_strings_are_decoded() {
+        assert_eq!(
+            query_parameter("format=png&ops=blur+2%2C5", "ops").as_deref(),
+            Some("blur 2,5")
+        );
+        assert_eq!(query_parameter("ops=blur", "format"), None);
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+}
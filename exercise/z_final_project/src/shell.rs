@@ -0,0 +1,279 @@
+// An interactive session on one image.
+//
+// The image is decoded once and each command works on the result of the last, so trying out
+// settings doesn't mean re-running (and re-decoding) the whole chain.  Every step's result is kept,
+// which makes undo and redo instant at the cost of memory.
+
+use crate::{codec, ops, preview, Settings};
+use image::DynamicImage;
+use std::io::{BufRead, Write};
+
+const COMMANDS: &str = "\
+Commands:
+    info            size, color type and number of steps
+    undo, redo      step back or forward
+    save FILE       write the current image
+    view            show the current image in the terminal
+    history         print the chained command line that gives the current image
+    recipe [FILE]   print the steps as a recipe, or write them to FILE
+    help, quit";
+
+struct Session {
+    infile: String,
+    // The original image, then the result of each step
+    images: Vec<DynamicImage>,
+    steps: Vec<ops::Operation>,
+    // How many steps are applied; the rest have been undone and can be redone
+    current: usize,
+    // Where the image was last saved, for the history
+    outfile: Option<String>,
+}
+
+pub fn run(infile: &str, settings: &Settings) -> Result<(), String> {
+    let (linear, options) = (settings.linear, &settings.save);
+    let img = codec::open(infile).map_err(|e| format!("Failed to open {}: {}", infile, e))?;
+    let mut session = Session {
+        infile: infile.to_string(),
+        images: vec![img],
+        steps: Vec::new(),
+        current: 0,
+        outfile: None,
+    };
+    println!("{}", session.info());
+    println!("Type help for a list of commands.");
+
+    let stdin = std::io::stdin();
+    loop {
+        print!("mirage> ");
+        std::io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        if stdin
+            .lock()
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?
+            == 0
+        {
+            println!();
+            return Ok(());
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["quit"] | ["exit"] => return Ok(()),
+            ["help"] => {
+                println!("Operations (several can go on one line):");
+                println!("    {}", ops::NAMES);
+                println!("{}", COMMANDS);
+            }
+            ["info"] => println!("{}", session.info()),
+            ["undo"] => match session.undo() {
+                Some(step) => println!("Undid {}", step),
+                None => println!("Nothing to undo"),
+            },
+            ["redo"] => match session.redo() {
+                Some(step) => println!("Redid {}", step),
+                None => println!("Nothing to redo"),
+            },
+            ["save", file] => match codec::save(session.image(), file, options) {
+                Ok(()) => {
+                    println!("Saved {}", file);
+                    session.outfile = Some(file.to_string());
+                }
+                Err(e) => println!("Failed writing {}: {}", file, e),
+            },
+            ["view"] => {
+                let mode = settings.preview.unwrap_or_else(preview::Mode::detect);
+                print!(
+                    "{}",
+                    preview::render(session.image(), mode, preview::available_size())
+                );
+            }
+            ["history"] => println!("{}", session.command_line(linear)),
+            ["recipe"] => print!("{}", ops::recipe(session.applied())),
+            ["recipe", file] => match std::fs::write(file, ops::recipe(session.applied())) {
+                Ok(()) => println!("Wrote {}", file),
+                Err(e) => println!("Failed writing {}: {}", file, e),
+            },
+            _ => match ops::parse_chain(&words) {
+                Ok(operations) => {
+                    for operation in operations {
+                        session.apply(operation, linear);
+                    }
+                    println!("{}", session.info());
+                    if let Some(mode) = settings.preview {
+                        let size = preview::available_size();
+                        print!("{}", preview::render(session.image(), mode, size));
+                    }
+                }
+                Err(e) => println!("{} (type help for a list of commands)", e),
+            },
+        }
+    }
+}
+
+impl Session {
+    fn image(&self) -> &DynamicImage {
+        &self.images[self.current]
+    }
+
+    fn applied(&self) -> &[ops::Operation] {
+        &self.steps[..self.current]
+    }
+
+    // Apply an operation to the current image, dropping anything that was undone.
+    fn apply(&mut self, operation: ops::Operation, linear: bool) {
+        let img = operation.apply(self.image(), linear);
+        self.images.truncate(self.current + 1);
+        self.steps.truncate(self.current);
+        self.images.push(img);
+        self.steps.push(operation);
+        self.current += 1;
+    }
+
+    // Step back, returning the step that was undone.
+    fn undo(&mut self) -> Option<&ops::Operation> {
+        if self.current == 0 {
+            return None;
+        }
+        self.current -= 1;
+        Some(&self.steps[self.current])
+    }
+
+    // Step forward again, returning the step that was redone.
+    fn redo(&mut self) -> Option<&ops::Operation> {
+        if self.current == self.steps.len() {
+            return None;
+        }
+        self.current += 1;
+        Some(&self.steps[self.current - 1])
+    }
+
+    fn info(&self) -> String {
+        let img = self.image();
+        format!(
+            "{}x{} {:?}, {} step(s){}",
+            img.width(),
+            img.height(),
+            img.color(),
+            self.current,
+            match self.steps.len() - self.current {
+                0 => String::new(),
+                undone => format!(" ({} to redo)", undone),
+            }
+        )
+    }
+
+    fn command_line(&self, linear: bool) -> String {
+        let mut words = vec!["mirage".to_string()];
+        if linear {
+            words.push("--linear".to_string());
+        }
+        words.push(quote(&self.infile));
+        words.push(quote(self.outfile.as_deref().unwrap_or("OUTFILE")));
+        words.extend(self.applied().iter().map(|op| op.to_string()));
+        words.join(" ")
+    }
+}
+
+// Quote a file name for the shell if it needs it.
+fn quote(text: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./,:+=@%".contains(c);
+    if !text.is_empty() && text.chars().all(plain) {
+        text.to_string()
+    } else {
+        format!("'{}'", text.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn session(infile: &str) -> Session {
+        let img = RgbImage::from_fn(6, 4, |x, y| Rgb([x as u8 * 40, y as u8 * 60, 90]));
+        Session {
+            infile: infile.to_string(),
+            images: vec![DynamicImage::ImageRgb8(img)],
+            steps: Vec::new(),
+            current: 0,
+            outfile: None,
+        }
+    }
+
+    fn apply(session: &mut Session, chain: &str) {
+        let words: Vec<&str> = chain.split_whitespace().collect();
+        for operation in ops::parse_chain(&words).unwrap() {
+            session.apply(operation, false);
+        }
+    }
+
+    #[test]
+    fn undo_and_redo_move_through_the_steps() {
+        let mut session = session("in.png");
+        let original = session.image().clone();
+        assert!(session.undo().is_none());
+        apply(&mut session, "invert resize 3x2");
+        let resized = session.image().clone();
+        assert_eq!(resized.width(), 3);
+        assert!(session.redo().is_none());
+
+        assert_eq!(
+            session.undo().map(|s| s.to_string()).as_deref(),
+            Some("resize 3x2")
+        );
+        assert_eq!(
+            session.undo().map(|s| s.to_string()).as_deref(),
+            Some("invert")
+        );
+        assert!(session.undo().is_none());
+        assert_eq!(session.image(), &original);
+        assert_eq!(session.info(), "6x4 Rgb8, 0 step(s) (2 to redo)");
+
+        session.redo();
+        session.redo();
+        assert_eq!(session.image(), &resized);
+
+        // A new step after an undo drops what could have been redone
+        session.undo();
+        apply(&mut session, "grayscale");
+        assert!(session.redo().is_none());
+        assert_eq!(
+            session.applied(),
+            &ops::parse_chain(&["invert", "grayscale"]).unwrap()[..]
+        );
+        assert_eq!(session.images.len(), 3);
+    }
+
+    #[test]
+    fn history_and_recipe_read_back() {
+        let mut session = session("my photo.png");
+        apply(
+            &mut session,
+            "blur 1.5 brighten -20 grain 10 --seed 3 crop 1,1 4x2 invert",
+        );
+        session.undo();
+        session.outfile = Some("out.png".to_string());
+
+        let line = session.command_line(true);
+        assert!(
+            line.starts_with("mirage --linear 'my photo.png' out.png "),
+            "{}",
+            line
+        );
+        let chain = line.split_once("out.png ").unwrap().1;
+        let words: Vec<&str> = chain.split_whitespace().collect();
+        assert_eq!(ops::parse_chain(&words).unwrap(), session.applied());
+
+        let recipe = ops::recipe(session.applied());
+        assert_eq!(ops::parse_recipe(&recipe).unwrap(), session.applied());
+    }
+
+    #[test]
+    fn names_are_quoted_for_the_shell() {
+        assert_eq!(quote("a/b-c.png"), "a/b-c.png");
+        assert_eq!(quote("my photo.png"), "'my photo.png'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        assert_eq!(quote(""), "''");
+    }
+}
@@ -0,0 +1,966 @@
+// Bounded-memory processing for images too big to decode in one piece.
+//
+// A 40000x40000 scan is 6.4 GB as 8-bit RGBA, and more once it's converted to floats for
+// processing.  With --tiled, the image is read, processed and written a few rows at a time, so
+// memory use depends on the width of the image (and the blur radius or resize factor), not on its
+// height.  Each operation is a stage that pulls rows from the one before it, keeping only the
+// rows it still needs: a blur holds on to the rows within its radius, a resize to the rows its
+// filter covers, and a per-pixel operation or a crop to nothing at all.
+//
+// PNG and TIFF (in strips or tiles) can be read and written in pieces.  Other formats can only be
+// read in one go, which is allowed when the whole image fits in the memory budget.  Before
+// anything is read, the stages add up how much memory they will need, and processing stops right
+// away if that's more than the budget.
+
+use crate::codec::{self, SaveOptions, TiffCompression};
+use crate::ops::{Operation, PixelFn};
+use crate::{alpha, blur, depth, gamma, resize};
+use image::io::Reader;
+use image::{ColorType, ImageFormat, Pixel, Rgba};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, Write};
+use tiff::decoder::{ChunkType, Decoder, DecodingResult};
+use tiff::encoder::compression::{Compression, Deflate, Lzw, Packbits, Uncompressed};
+use tiff::encoder::{TiffEncoder, TiffKind};
+use tiff::tags::{CompressionMethod, Tag};
+
+// One row of pixels, as RGBA floats.
+type Row = Vec<f32>;
+
+// Output TIFF strips hold about this many bytes.
+const STRIP_BYTES: usize = 1 << 20;
+
+// Parse a memory size like "512M", "2G" or "64KiB" into bytes.
+pub fn parse_memory(text: &str) -> Option<usize> {
+    let text = text.trim().to_ascii_lowercase();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let unit: usize = match unit.trim_end_matches("ib").trim_end_matches('b') {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        _ => return None,
+    };
+    let number: f64 = number.parse().ok()?;
+    Some((number * unit as f64) as usize)
+}
+
+// Run the operations on `infile` and write the result to `outfile` without using more than about
+// `budget` bytes.
+pub fn process(
+    infile: &str,
+    outfile: &str,
+    operations: &[Operation],
+    linear: bool,
+    budget: usize,
+    options: &SaveOptions,
+) -> Result<(), String> {
+    if infile == "-" || outfile == "-" {
+        return Err("--tiled works on files, not stdin or stdout".to_string());
+    }
+    let format = match options.format {
+        Some(format) => format,
+        None => ImageFormat::from_path(outfile).map_err(|e| e.to_string())?,
+    };
+    if format != ImageFormat::Png && format != ImageFormat::Tiff {
+        return Err("--tiled can only write PNG and TIFF".to_string());
+    }
+    if let Some(operation) = operations.iter().find(|operation| !operation.streams()) {
+        return Err(format!(
+            "\"{}\" needs the whole image at once, so it can't run with --tiled",
+            operation
+        ));
+    }
+
+    let (mut rows, mut color) = open(infile, budget)?;
+    for operation in operations {
+        rows = stage(rows, operation, linear, color.has_alpha());
+        color = operation.output_color(color);
+    }
+    if let Some(bits) = options.depth {
+        color = depth::color_type(bits, color.has_color(), color.has_alpha());
+    }
+
+    let needed = rows.memory() + strip_rows(rows.width()) * rows.width() as usize * 16;
+    if needed > budget {
+        return Err(format!(
+            "This needs about {} MiB, more than the memory budget of {} MiB",
+            needed.div_ceil(1 << 20),
+            budget >> 20
+        ));
+    }
+    match format {
+        ImageFormat::Png => write_png(rows.as_mut(), outfile, color, options),
+        _ => write_tiff(rows.as_mut(), outfile, color, options.tiff_compression),
+    }
+}
+
+// The rows of an image, and its color type.
+type Source = (Box<dyn Rows>, ColorType);
+
+// A source of rows, top to bottom.
+trait Rows {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    fn next_row(&mut self) -> Result<Row, String>;
+    // The most memory this stage and the ones before it hold on to, in bytes
+    fn memory(&self) -> usize;
+}
+
+fn open(path: &str, budget: usize) -> Result<Source, String> {
+    let error = |e: &dyn std::fmt::Display| format!("{}: {}", path, e);
+    let reader = Reader::open(path)
+        .and_then(Reader::with_guessed_format)
+        .map_err(|e| error(&e))?;
+    let rows = match reader.format() {
+        Some(ImageFormat::Png) => PngRows::open(path).map_err(|e| error(&e))?,
+        Some(ImageFormat::Tiff) => TiffRows::open(path).map_err(|e| error(&e))?,
+        _ => None,
+    };
+    if let Some(rows) = rows {
+        return Ok(rows);
+    }
+
+    // Everything else has to be decoded in one go.
+    let (width, height) = reader.into_dimensions().map_err(|e| error(&e))?;
+    let needed = width as usize * height as usize * 16;
+    if needed > budget {
+        return Err(error(&format!(
+            "can't be read in pieces, and the whole image needs {} MiB; convert it to PNG or TIFF",
+            needed.div_ceil(1 << 20)
+        )));
+    }
+    let img = codec::open(path)?;
+    let color = img.color();
+    let rows = MemoryRows {
+        pixels: img.into_rgba32f(),
+        next: 0,
+    };
+    Ok((Box::new(rows), color))
+}
+
+// Expand gray, gray+alpha, RGB or RGBA samples to RGBA.
+fn to_rgba(samples: &[f32], channels: usize) -> Row {
+    let mut row = Vec::with_capacity(samples.len() / channels * 4);
+    for pixel in samples.chunks_exact(channels) {
+        match *pixel {
+            [l] => row.extend([l, l, l, 1.0]),
+            [l, a] => row.extend([l, l, l, a]),
+            [r, g, b] => row.extend([r, g, b, 1.0]),
+            [r, g, b, a] => row.extend([r, g, b, a]),
+            _ => unreachable!(),
+        }
+    }
+    row
+}
+
+// The samples that an output with `channels` channels keeps of each RGBA pixel.
+fn pick(row: &[f32], channels: usize) -> impl Iterator<Item = f32> + '_ {
+    let keep: &'static [usize] = match channels {
+        1 => &[0],
+        2 => &[0, 3],
+        3 => &[0, 1, 2],
+        _ => &[0, 1, 2, 3],
+    };
+    row.chunks_exact(4)
+        .flat_map(move |pixel| keep.iter().map(move |&c| pixel[c]))
+}
+
+fn channels(color: ColorType) -> usize {
+    color.channel_count() as usize
+}
+
+struct PngRows {
+    reader: png::Reader<BufReader<File>>,
+    channels: usize,
+    sixteen: bool,
+}
+
+impl PngRows {
+    // Interlaced PNGs store the image in seven passes over the whole thing, so they can't be
+    // streamed; that gives None.
+    fn open(path: &str) -> Result<Option<Source>, png::DecodingError> {
+        let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+        // Palettes and low bit depths become 8-bit gray or RGB, and transparent colors alpha.
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let reader = decoder.read_info()?;
+        if reader.info().interlaced {
+            return Ok(None);
+        }
+        let (color, bit_depth) = reader.output_color_type();
+        let channels = color.samples();
+        let sixteen = bit_depth == png::BitDepth::Sixteen;
+        let color = depth::color_type(
+            if sixteen { 16 } else { 8 },
+            channels >= 3,
+            channels % 2 == 0,
+        );
+        let rows = PngRows {
+            reader,
+            channels,
+            sixteen,
+        };
+        Ok(Some((Box::new(rows), color)))
+    }
+}
+
+impl Rows for PngRows {
+    fn width(&self) -> u32 {
+        self.reader.info().width
+    }
+
+    fn height(&self) -> u32 {
+        self.reader.info().height
+    }
+
+    fn next_row(&mut self) -> Result<Row, String> {
+        let row = self
+            .reader
+            .next_row()
+            .map_err(|e| e.to_string())?
+            .ok_or("The PNG ended early")?;
+        let samples: Vec<f32> = if self.sixteen {
+            row.data()
+                .chunks_exact(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]) as f32 / 65535.0)
+                .collect()
+        } else {
+            row.data().iter().map(|&b| b as f32 / 255.0).collect()
+        };
+        Ok(to_rgba(&samples, self.channels))
+    }
+
+    fn memory(&self) -> usize {
+        self.width() as usize * 16
+    }
+}
+
+struct TiffRows {
+    decoder: Decoder<BufReader<File>>,
+    width: u32,
+    height: u32,
+    channels: usize,
+    // Strip or tile size
+    chunk: (u32, u32),
+    tiled: bool,
+    // What's left of the current band of strips or tiles
+    band: VecDeque<Row>,
+    next_band: u32,
+}
+
+impl TiffRows {
+    // Layouts we can't stream (separate planes for each channel, CMYK, palettes, odd bit depths)
+    // give None, and are decoded in one go by the image crate instead.
+    fn open(path: &str) -> tiff::TiffResult<Option<Source>> {
+        let file = BufReader::new(File::open(path)?);
+        // The memory budget is checked separately, against the strip or tile size.
+        let mut decoder = Decoder::new(file)?.with_limits(tiff::decoder::Limits::unlimited());
+        let (width, height) = decoder.dimensions()?;
+        if decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)? == Some(2) {
+            return Ok(None);
+        }
+        let (channels, bits) = match decoder.colortype()? {
+            tiff::ColorType::Gray(bits) => (1, bits),
+            tiff::ColorType::GrayA(bits) => (2, bits),
+            tiff::ColorType::RGB(bits) => (3, bits),
+            tiff::ColorType::RGBA(bits) => (4, bits),
+            _ => return Ok(None),
+        };
+        let float = decoder
+            .find_tag_unsigned_vec::<u16>(Tag::SampleFormat)?
+            .is_some_and(|formats| formats.first() == Some(&3));
+        if !matches!((bits, float), (8, false) | (16, false) | (32, true)) {
+            return Ok(None);
+        }
+        let rows = TiffRows {
+            width,
+            height,
+            channels,
+            chunk: decoder.chunk_dimensions(),
+            tiled: decoder.get_chunk_type() == ChunkType::Tile,
+            band: VecDeque::new(),
+            next_band: 0,
+            decoder,
+        };
+        let color = depth::color_type(bits, channels >= 3, channels % 2 == 0);
+        Ok(Some((Box::new(rows), color)))
+    }
+
+    fn read_band(&mut self) -> Result<(), String> {
+        let (chunk_width, chunk_height) = self.chunk;
+        let across = if self.tiled {
+            self.width.div_ceil(chunk_width)
+        } else {
+            1
+        };
+        let rows = chunk_height.min(self.height - self.next_band * chunk_height);
+        let mut band = vec![Vec::with_capacity(self.width as usize * 4); rows as usize];
+        for column in 0..across {
+            let index = self.next_band * across + column;
+            let (data_width, _) = self.decoder.chunk_data_dimensions(index);
+            let samples: Vec<f32> = match self.decoder.read_chunk(index) {
+                Ok(DecodingResult::U8(data)) => data.iter().map(|&s| s as f32 / 255.0).collect(),
+                Ok(DecodingResult::U16(data)) => data.iter().map(|&s| s as f32 / 65535.0).collect(),
+                Ok(DecodingResult::F32(data)) => data,
+                Ok(_) => return Err("Unsupported TIFF sample format".to_string()),
+                Err(e) => return Err(e.to_string()),
+            };
+            let line = data_width as usize * self.channels;
+            for (row, samples) in band.iter_mut().zip(samples.chunks(line)) {
+                row.extend(to_rgba(samples, self.channels));
+            }
+        }
+        self.band = band.into();
+        self.next_band += 1;
+        Ok(())
+    }
+}
+
+impl Rows for TiffRows {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn next_row(&mut self) -> Result<Row, String> {
+        if self.band.is_empty() {
+            self.read_band()?;
+        }
+        self.band
+            .pop_front()
+            .ok_or_else(|| "The TIFF ended early".to_string())
+    }
+
+    fn memory(&self) -> usize {
+        // A decoded band, plus the raw samples of one strip or tile
+        let (chunk_width, chunk_height) = self.chunk;
+        let chunk = chunk_width as usize * chunk_height as usize * self.channels * 4;
+        self.width as usize * chunk_height as usize * 16 + chunk
+    }
+}
+
+struct MemoryRows {
+    pixels: image::Rgba32FImage,
+    next: u32,
+}
+
+impl Rows for MemoryRows {
+    fn width(&self) -> u32 {
+        self.pixels.width()
+    }
+
+    fn height(&self) -> u32 {
+        self.pixels.height()
+    }
+
+    fn next_row(&mut self) -> Result<Row, String> {
+        let length = self.pixels.width() as usize * 4;
+        let start = self.next as usize * length;
+        self.next += 1;
+        Ok(self.pixels.as_raw()[start..start + length].to_vec())
+    }
+
+    fn memory(&self) -> usize {
+        self.pixels.as_raw().len() * 4
+    }
+}
+
+// Wrap `input` in the stages that carry out `operation`.  Filters that mix neighboring pixels work
+// on premultiplied alpha (see alpha.rs), and everything but cropping honors --linear.
+fn stage(input: Box<dyn Rows>, operation: &Operation, linear: bool, alpha: bool) -> Box<dyn Rows> {
+    if let Operation::Crop { .. } = operation {
+        // Cropping doesn't touch the pixel values at all.
+        let (x, y, width, height) = operation.crop_rect((input.width(), input.height()));
+        return Box::new(CropRows {
+            input,
+            x,
+            y,
+            width,
+            height,
+            next: 0,
+        });
+    }
+
+    let decode = move |pixel: &mut Rgba<f32>| {
+        if linear {
+            *pixel = gamma::decode_pixel(*pixel);
+        }
+    };
+    let encode = move |pixel: &mut Rgba<f32>| {
+        if linear {
+            *pixel = gamma::encode_pixel(*pixel);
+        }
+    };
+    if let Some(adjust) = operation.per_pixel() {
+        return map(input, move |pixel| {
+            decode(pixel);
+            adjust(pixel);
+            encode(pixel);
+        });
+    }
+
+    let before = map(input, move |pixel| {
+        decode(pixel);
+        if alpha {
+            alpha::premultiply_pixel(pixel);
+        }
+    });
+    let filtered: Box<dyn Rows> = match *operation {
+        Operation::Blur(sigma, blur::Method::Gaussian) => Box::new(BlurRows {
+            input: before,
+            kernel: blur::kernel(sigma),
+            window: VecDeque::new(),
+            first: 0,
+            next: 0,
+        }),
+        Operation::Blur(sigma, blur::Method::Box(passes)) => blur::box_radii(sigma, passes)
+            .into_iter()
+            .fold(before, |input, radius| {
+                Box::new(BoxRows {
+                    input,
+                    radius,
+                    window: VecDeque::new(),
+                    sums: Vec::new(),
+                    first: 0,
+                    next: 0,
+                })
+            }),
+        Operation::Resize(..) => {
+            let (width, height) = operation.output_size((before.width(), before.height()));
+            Box::new(ResizeRows {
+                columns: resize::weights(before.width(), width),
+                rows: resize::weights(before.height(), height),
+                input: before,
+                window: VecDeque::new(),
+                first: 0,
+                next: 0,
+            })
+        }
+        _ => unreachable!("{} doesn't stream", operation),
+    };
+    map(filtered, move |pixel| {
+        if alpha {
+            alpha::unpremultiply_pixel(pixel);
+        }
+        encode(pixel);
+    })
+}
+
+fn map(input: Box<dyn Rows>, f: impl Fn(&mut Rgba<f32>) + Send + Sync + 'static) -> Box<dyn Rows> {
+    Box::new(MapRows {
+        input,
+        f: Box::new(f),
+    })
+}
+
+struct MapRows {
+    input: Box<dyn Rows>,
+    f: PixelFn,
+}
+
+impl Rows for MapRows {
+    fn width(&self) -> u32 {
+        self.input.width()
+    }
+
+    fn height(&self) -> u32 {
+        self.input.height()
+    }
+
+    fn next_row(&mut self) -> Result<Row, String> {
+        let mut row = self.input.next_row()?;
+        for pixel in row.chunks_exact_mut(4) {
+            (self.f)(Rgba::from_slice_mut(pixel));
+        }
+        Ok(row)
+    }
+
+    fn memory(&self) -> usize {
+        self.input.memory()
+    }
+}
+
+struct CropRows {
+    input: Box<dyn Rows>,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    next: u32,
+}
+
+impl Rows for CropRows {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn next_row(&mut self) -> Result<Row, String> {
+        if self.next == 0 {
+            for _ in 0..self.y {
+                self.input.next_row()?;
+            }
+        }
+        self.next += 1;
+        let row = self.input.next_row()?;
+        let start = self.x as usize * 4;
+        Ok(row[start..start + self.width as usize * 4].to_vec())
+    }
+
+    fn memory(&self) -> usize {
+        self.input.memory()
+    }
+}
+
+// One box blur pass of blur.rs, one row at a time.  Each output row is the one above plus the row
+// coming into the box minus the row leaving it, so the cost doesn't depend on the radius.
+struct BoxRows {
+    input: Box<dyn Rows>,
+    radius: usize,
+    // Horizontally blurred input rows, starting with row `first`
+    window: VecDeque<Row>,
+    // The running sum of the rows in the box
+    sums: Vec<f64>,
+    first: u32,
+    next: u32,
+}
+
+impl Rows for BoxRows {
+    fn width(&self) -> u32 {
+        self.input.width()
+    }
+
+    fn height(&self) -> u32 {
+        self.input.height()
+    }
+
+    fn next_row(&mut self) -> Result<Row, String> {
+        let radius = self.radius as i64;
+        let last = self.height() as i64 - 1;
+        let y = self.next as i64;
+        self.next += 1;
+
+        let needed = (y + radius).min(last);
+        while self.first as i64 + self.window.len() as i64 <= needed {
+            let row = self.input.next_row()?;
+            let mut blurred = vec![0.0; row.len()];
+            blur::box_row(&row, self.radius, &mut blurred);
+            self.window.push_back(blurred);
+        }
+
+        let first = self.first as i64;
+        let window = &self.window;
+        let row = |y: i64| &window[(y.clamp(0, last) - first) as usize];
+        if y == 0 {
+            self.sums = vec![0.0; self.width() as usize * 4];
+            for k in -radius..=radius {
+                for (sum, s) in self.sums.iter_mut().zip(row(k)) {
+                    *sum += *s as f64;
+                }
+            }
+        } else {
+            let (entering, leaving) = (row(y + radius), row(y - radius - 1));
+            for ((sum, e), l) in self.sums.iter_mut().zip(entering).zip(leaving) {
+                *sum += *e as f64 - *l as f64;
+            }
+        }
+        let scale = 1.0 / (2 * radius + 1) as f64;
+        let out = self.sums.iter().map(|sum| (sum * scale) as f32).collect();
+
+        // The row leaving the box next time is y - radius.
+        while (self.first as i64) < y - radius {
+            self.window.pop_front();
+            self.first += 1;
+        }
+        Ok(out)
+    }
+
+    fn memory(&self) -> usize {
+        (2 * self.radius + 3) * self.width() as usize * 16 + self.input.memory()
+    }
+}
+
+// The same separable Gaussian blur as blur.rs, one row at a time.
+struct BlurRows {
+    input: Box<dyn Rows>,
+    kernel: Vec<f32>,
+    // Horizontally blurred input rows, starting with row `first`
+    window: VecDeque<Row>,
+    first: u32,
+    next: u32,
+}
+
+impl Rows for BlurRows {
+    fn width(&self) -> u32 {
+        self.input.width()
+    }
+
+    fn height(&self) -> u32 {
+        self.input.height()
+    }
+
+    fn next_row(&mut self) -> Result<Row, String> {
+        let radius = (self.kernel.len() / 2) as i64;
+        let last = self.height() as i64 - 1;
+        let y = self.next as i64;
+        self.next += 1;
+
+        let needed = (y + radius).min(last);
+        while self.first as i64 + self.window.len() as i64 <= needed {
+            let row = self.input.next_row()?;
+            let mut blurred = vec![0.0; row.len()];
+            blur::blur_row(&row, &self.kernel, &mut blurred);
+            self.window.push_back(blurred);
+        }
+        while (self.first as i64) < y - radius {
+            self.window.pop_front();
+            self.first += 1;
+        }
+
+        let mut out = vec![0.0; self.width() as usize * 4];
+        for (k, weight) in self.kernel.iter().enumerate() {
+            let source = (y + k as i64 - radius).clamp(0, last) as u32;
+            let row = &self.window[(source - self.first) as usize];
+            for (o, s) in out.iter_mut().zip(row) {
+                *o += weight * s;
+            }
+        }
+        Ok(out)
+    }
+
+    fn memory(&self) -> usize {
+        (self.kernel.len() + 1) * self.width() as usize * 16 + self.input.memory()
+    }
+}
+
+// The resize in resize.rs, a row at a time.
+struct ResizeRows {
+    input: Box<dyn Rows>,
+    // For each output column and row: the first input pixel it reads, and the weights
+    columns: Vec<(usize, Vec<f32>)>,
+    rows: Vec<(usize, Vec<f32>)>,
+    // Horizontally resized input rows, starting with row `first`
+    window: VecDeque<Row>,
+    first: usize,
+    next: usize,
+}
+
+impl Rows for ResizeRows {
+    fn width(&self) -> u32 {
+        self.columns.len() as u32
+    }
+
+    fn height(&self) -> u32 {
+        self.rows.len() as u32
+    }
+
+    fn next_row(&mut self) -> Result<Row, String> {
+        let (start, weights) = &self.rows[self.next];
+        self.next += 1;
+        while self.first + self.window.len() < start + weights.len() {
+            let row = self.input.next_row()?;
+            self.window
+                .push_back(resize::resize_row(&row, &self.columns));
+        }
+        while self.first < *start {
+            self.window.pop_front();
+            self.first += 1;
+        }
+
+        let mut out = vec![0.0; self.columns.len() * 4];
+        for (row, weight) in self.window.iter().zip(weights) {
+            for (o, s) in out.iter_mut().zip(row) {
+                *o += weight * s;
+            }
+        }
+        Ok(out)
+    }
+
+    fn memory(&self) -> usize {
+        // The window can also hold the rows skipped between two output rows.
+        let taps = self.rows.iter().map(|(_, w)| w.len()).max().unwrap_or(0);
+        let step = self.input.height() as usize / self.rows.len().max(1) + 1;
+        (taps + step) * self.columns.len() * 16 + self.input.memory()
+    }
+}
+
+fn strip_rows(width: u32) -> usize {
+    (STRIP_BYTES / (width as usize * 16).max(1)).max(1)
+}
+
+fn write_png(
+    rows: &mut dyn Rows,
+    path: &str,
+    color: ColorType,
+    options: &SaveOptions,
+) -> Result<(), String> {
+    let error = |e: &dyn std::fmt::Display| format!("{}: {}", path, e);
+    let file = File::create(path).map_err(|e| error(&e))?;
+    let (width, height) = (rows.width(), rows.height());
+    let encoder = codec::png_encoder(BufWriter::new(file), width, height, color, options);
+    // Floats are written as 16-bit, big-endian like all PNG samples.
+    let sixteen = depth::bits(color) > 8;
+    let mut writer = encoder.write_header().map_err(|e| error(&e))?;
+    let mut stream = writer.stream_writer().map_err(|e| error(&e))?;
+    let channels = channels(color);
+    for _ in 0..height {
+        let row = rows.next_row()?;
+        let bytes: Vec<u8> = if sixteen {
+            pick(&row, channels)
+                .flat_map(|s| u16::from_f32(s).to_be_bytes())
+                .collect()
+        } else {
+            pick(&row, channels).map(u8::from_f32).collect()
+        };
+        stream.write_all(&bytes).map_err(|e| error(&e))?;
+    }
+    stream.finish().map_err(|e| error(&e))
+}
+
+fn write_tiff(
+    rows: &mut dyn Rows,
+    path: &str,
+    color: ColorType,
+    method: TiffCompression,
+) -> Result<(), String> {
+    let error = |e: &dyn std::fmt::Display| format!("{}: {}", path, e);
+    let file = File::create(path).map_err(|e| error(&e))?;
+    let out = BufWriter::new(file);
+    // TIFF has no gray+alpha type that readers agree on, so that becomes RGBA (as in codec.rs).
+    let channels = match channels(color) {
+        2 => 4,
+        channels => channels,
+    };
+    let bits = depth::bits(color);
+    // Plain TIFF can't point past 4 GB, so bigger images are written as BigTIFF.
+    let size = rows.width() as u64 * rows.height() as u64 * channels as u64 * bits as u64 / 8;
+    if size > 1 << 31 {
+        let mut encoder = TiffEncoder::new_big(out).map_err(|e| error(&e))?;
+        tiff_compressed(&mut encoder, rows, channels, bits, method).map_err(|e| error(&e))
+    } else {
+        let mut encoder = TiffEncoder::new(out).map_err(|e| error(&e))?;
+        tiff_compressed(&mut encoder, rows, channels, bits, method).map_err(|e| error(&e))
+    }
+}
+
+fn tiff_compressed<W: Write + Seek, K: TiffKind>(
+    encoder: &mut TiffEncoder<W, K>,
+    rows: &mut dyn Rows,
+    channels: usize,
+    bits: u8,
+    method: TiffCompression,
+) -> Result<(), String> {
+    match method {
+        TiffCompression::None => tiff_strips(encoder, rows, channels, bits, Uncompressed),
+        TiffCompression::Lzw => tiff_strips(encoder, rows, channels, bits, Lzw),
+        TiffCompression::Deflate => tiff_strips(encoder, rows, channels, bits, Deflate::default()),
+        TiffCompression::Packbits => tiff_strips(encoder, rows, channels, bits, Packbits),
+    }
+}
+
+// The tiff crate's strip-by-strip encoder doesn't compress the strips, so this writes the image
+// directory and compresses each strip itself.
+fn tiff_strips<W: Write + Seek, K: TiffKind, D: Compression>(
+    encoder: &mut TiffEncoder<W, K>,
+    rows: &mut dyn Rows,
+    channels: usize,
+    bits: u8,
+    mut method: D,
+) -> Result<(), String> {
+    let tiff_error = |e: tiff::TiffError| e.to_string();
+    let (width, height) = (rows.width(), rows.height());
+    // PackBits runs must not cross rows.
+    let strip_rows = match D::COMPRESSION_METHOD {
+        CompressionMethod::PackBits => 1,
+        _ => strip_rows(width) as u32,
+    };
+
+    let mut directory = encoder.new_directory().map_err(tiff_error)?;
+    let mut offsets = Vec::new();
+    let mut byte_counts = Vec::new();
+    let mut strip = Vec::new();
+    for y in 0..height {
+        let row = rows.next_row()?;
+        for sample in pick(&row, channels) {
+            match bits {
+                8 => strip.push(u8::from_f32(sample)),
+                16 => strip.extend(u16::from_f32(sample).to_ne_bytes()),
+                _ => strip.extend(sample.to_ne_bytes()),
+            }
+        }
+        if (y + 1) % strip_rows == 0 || y + 1 == height {
+            let mut compressed = Vec::new();
+            method
+                .write_to(&mut compressed, &strip)
+                .map_err(|e| e.to_string())?;
+            let offset = directory.write_data(&compressed[..]).map_err(tiff_error)?;
+            offsets.push(K::convert_offset(offset).map_err(tiff_error)?);
+            byte_counts.push(K::convert_offset(compressed.len() as u64).map_err(tiff_error)?);
+            strip.clear();
+        }
+    }
+
+    let sample_format = if bits == 32 { 3u16 } else { 1 };
+    let tags = [
+        (Tag::ImageWidth, width),
+        (Tag::ImageLength, height),
+        (Tag::RowsPerStrip, strip_rows),
+    ];
+    for (tag, value) in tags {
+        directory.write_tag(tag, value).map_err(tiff_error)?;
+    }
+    let short_tags = [
+        (Tag::Compression, D::COMPRESSION_METHOD.to_u16()),
+        // 1 is "black is zero" grayscale, 2 is RGB
+        (
+            Tag::PhotometricInterpretation,
+            if channels < 3 { 1 } else { 2 },
+        ),
+        (Tag::SamplesPerPixel, channels as u16),
+    ];
+    for (tag, value) in short_tags {
+        directory.write_tag(tag, value).map_err(tiff_error)?;
+    }
+    directory
+        .write_tag(Tag::BitsPerSample, &vec![bits as u16; channels][..])
+        .map_err(tiff_error)?;
+    directory
+        .write_tag(Tag::SampleFormat, &vec![sample_format; channels][..])
+        .map_err(tiff_error)?;
+    if channels == 4 {
+        // The fourth sample is (unassociated) alpha
+        directory
+            .write_tag(Tag::ExtraSamples, 2u16)
+            .map_err(tiff_error)?;
+    }
+    directory
+        .write_tag(Tag::StripOffsets, K::convert_slice(&offsets))
+        .map_err(tiff_error)?;
+    directory
+        .write_tag(Tag::StripByteCounts, K::convert_slice(&byte_counts))
+        .map_err(tiff_error)?;
+    directory.finish().map_err(tiff_error)
+}
+
+// Converting channel values in 0.0..=1.0 to what the encoders store.
+trait Sample: Copy {
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Sample for u8 {
+    fn from_f32(value: f32) -> Self {
+        (value.clamp(0.0, 1.0) * 255.0).round() as u8
+    }
+}
+
+impl Sample for u16 {
+    fn from_f32(value: f32) -> Self {
+        (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops;
+    use image::{DynamicImage, GenericImageView, RgbImage, RgbaImage};
+
+    fn pattern(alpha: bool) -> DynamicImage {
+        let img = RgbaImage::from_fn(23, 17, |x, y| {
+            let edge = if (x / 5 + y / 4) % 2 == 0 { 230 } else { 20 };
+            Rgba([edge, (x * 11) as u8, (y * 15) as u8, 255 - (x * y) as u8])
+        });
+        if alpha {
+            DynamicImage::ImageRgba8(img)
+        } else {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img).into_rgb8())
+        }
+    }
+
+    // Run the chain tiled and in memory, and check they agree to within one 8-bit level.
+    fn check(img: &DynamicImage, chain: &str, linear: bool) {
+        let words: Vec<&str> = chain.split_whitespace().collect();
+        let operations = ops::parse_chain(&words).unwrap();
+        let base = format!(
+            "mirage-stream-{}-{}",
+            std::process::id(),
+            chain.replace(' ', "_")
+        );
+        let infile = std::env::temp_dir().join(format!("{}-{}-in.png", base, linear));
+        let outfile = std::env::temp_dir().join(format!("{}-{}-out.png", base, linear));
+        img.save(&infile).unwrap();
+        process(
+            infile.to_str().unwrap(),
+            outfile.to_str().unwrap(),
+            &operations,
+            linear,
+            1 << 30,
+            &SaveOptions::default(),
+        )
+        .unwrap();
+        let tiled = image::open(&outfile).unwrap();
+        std::fs::remove_file(&infile).unwrap();
+        std::fs::remove_file(&outfile).unwrap();
+
+        let memory = operations
+            .iter()
+            .fold(img.clone(), |img, operation| operation.apply(&img, linear));
+        assert_eq!(tiled.color(), memory.color(), "{}", chain);
+        assert_eq!(tiled.dimensions(), memory.dimensions(), "{}", chain);
+        let (tiled, memory) = (tiled.into_rgba8(), memory.into_rgba8());
+        for (a, b) in tiled.as_raw().iter().zip(memory.as_raw()) {
+            assert!(
+                a.abs_diff(*b) <= 1,
+                "{} (linear {}): {} vs {}",
+                chain,
+                linear,
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn tiled_matches_in_memory() {
+        let chains = [
+            "blur 1.5",
+            "blur 2 --passes 0",
+            "blur 5",
+            "blur 3 --passes 2",
+            "resize 10x7",
+            "resize 40x30",
+            "resize 31x0",
+            "crop 3,2 12x9",
+            "invert grayscale brighten 20",
+            "crop 1,1 20x14 blur 1 resize 15x11 invert",
+        ];
+        for alpha in [false, true] {
+            let img = pattern(alpha);
+            for chain in chains {
+                for linear in [false, true] {
+                    check(&img, chain, linear);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gray_input_stays_gray() {
+        let img = DynamicImage::ImageLuma8(pattern(false).into_luma8());
+        check(&img, "blur 2 resize 11x8", false);
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(9, 9, |x, y| {
+            image::Rgb([(x * 28) as u8, (y * 28) as u8, 100])
+        }));
+        check(&img, "grayscale", false);
+    }
+}
@@ -0,0 +1,478 @@
+// Text rendering: captions and labels burned into images.
+//
+// Text is first rasterized into a coverage mask (0.0 = empty, 1.0 = fully inside a glyph), which
+// makes outlines and drop shadows easy: they are just the same mask grown or moved and painted in
+// another color underneath.  The finished label is then placed with the compositing code, so it
+// supports the same anchors and offsets as `composite`.
+//
+// Without a font file we fall back to the ISO-8859-1 bitmap fonts that ship with embedded-graphics,
+// scaled up by whole pixels.  Those cover ASCII and Western European accents; everything else is
+// drawn as '?'.  For full UTF-8 coverage, pass a TrueType/OpenType font with --font.
+
+use crate::composite::{self, Anchor, CompositeOptions};
+use ab_glyph::{Font as _, FontVec, PxScale, ScaleFont};
+use embedded_graphics::image::GetPixel;
+use embedded_graphics::mono_font::{iso_8859_1, MonoFont};
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::{OriginDimensions, Point};
+use image::{DynamicImage, ImageBuffer, Luma, Rgba, RgbaImage};
+
+type Mask = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+pub enum Font {
+    Bitmap,
+    Truetype(FontVec),
+}
+
+impl Font {
+    pub fn load(path: &str) -> Result<Font, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let font = FontVec::try_from_vec(data).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Font::Truetype(font))
+    }
+
+    fn sized(&self, size: f32) -> SizedFont<'_> {
+        match self {
+            Font::Bitmap => {
+                // Pick the bitmap font that gets closest to the requested size when scaled by a
+                // whole number, preferring the bigger (more detailed) font on ties.
+                let fonts = [
+                    &iso_8859_1::FONT_10X20,
+                    &iso_8859_1::FONT_8X13,
+                    &iso_8859_1::FONT_6X10,
+                ];
+                let (font, scale) = fonts
+                    .iter()
+                    .map(|font| {
+                        let height = font.character_size.height as f32;
+                        (*font, (size / height).round().max(1.0) as u32)
+                    })
+                    .min_by_key(|(font, scale)| {
+                        (font.character_size.height as f32 * *scale as f32 - size).abs() as u32
+                    })
+                    .unwrap();
+                SizedFont::Bitmap(font, scale)
+            }
+            Font::Truetype(font) => SizedFont::Truetype(font.as_scaled(PxScale::from(size))),
+        }
+    }
+}
+
+enum SizedFont<'a> {
+    Bitmap(&'static MonoFont<'static>, u32),
+    Truetype(ab_glyph::PxScaleFont<&'a FontVec>),
+}
+
+impl SizedFont<'_> {
+    fn line_height(&self) -> f32 {
+        match self {
+            SizedFont::Bitmap(font, scale) => (font.character_size.height * scale) as f32,
+            SizedFont::Truetype(font) => font.height() + font.line_gap(),
+        }
+    }
+
+    fn width(&self, line: &str) -> f32 {
+        match self {
+            SizedFont::Bitmap(font, scale) => {
+                let advance = font.character_size.width + font.character_spacing;
+                (line.chars().count() as u32 * advance * scale) as f32
+            }
+            SizedFont::Truetype(font) => {
+                let mut width = 0.0;
+                let mut previous = None;
+                for c in line.chars() {
+                    let id = font.glyph_id(c);
+                    if let Some(previous) = previous {
+                        width += font.kern(previous, id);
+                    }
+                    width += font.h_advance(id);
+                    previous = Some(id);
+                }
+                width
+            }
+        }
+    }
+
+    // Add the coverage of one line of text to the mask, with its top-left corner at (left, top).
+    fn draw_line(&self, mask: &mut Mask, line: &str, left: f32, top: f32) {
+        match self {
+            SizedFont::Bitmap(font, scale) => {
+                let (w, h) = (font.character_size.width, font.character_size.height);
+                let glyphs_per_row = font.image.size().width / w;
+                let advance = (w + font.character_spacing) * scale;
+                for (i, c) in line.chars().enumerate() {
+                    let index = font.glyph_mapping.index(c) as u32;
+                    let glyph_x = index % glyphs_per_row * w;
+                    let glyph_y = index / glyphs_per_row * h;
+                    let x0 = left as i64 + (i as u32 * advance) as i64;
+                    for gy in 0..h {
+                        for gx in 0..w {
+                            let p = Point::new((glyph_x + gx) as i32, (glyph_y + gy) as i32);
+                            if font.image.pixel(p) != Some(BinaryColor::On) {
+                                continue;
+                            }
+                            for sy in 0..*scale {
+                                for sx in 0..*scale {
+                                    let x = x0 + (gx * scale + sx) as i64;
+                                    let y = top as i64 + (gy * scale + sy) as i64;
+                                    add_coverage(mask, x, y, 1.0);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            SizedFont::Truetype(font) => {
+                let baseline = top + font.ascent();
+                let mut x = left;
+                let mut previous = None;
+                for c in line.chars() {
+                    let id = font.glyph_id(c);
+                    if let Some(previous) = previous {
+                        x += font.kern(previous, id);
+                    }
+                    let glyph =
+                        id.with_scale_and_position(font.scale(), ab_glyph::point(x, baseline));
+                    if let Some(outlined) = font.outline_glyph(glyph) {
+                        let bounds = outlined.px_bounds();
+                        outlined.draw(|gx, gy, coverage| {
+                            let x = bounds.min.x as i64 + gx as i64;
+                            let y = bounds.min.y as i64 + gy as i64;
+                            add_coverage(mask, x, y, coverage);
+                        });
+                    }
+                    x += font.h_advance(id);
+                    previous = Some(id);
+                }
+            }
+        }
+    }
+}
+
+fn add_coverage(mask: &mut Mask, x: i64, y: i64, coverage: f32) {
+    if x >= 0 && y >= 0 && x < mask.width() as i64 && y < mask.height() as i64 {
+        let pixel = mask.get_pixel_mut(x as u32, y as u32);
+        pixel[0] = (pixel[0] + coverage).min(1.0);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+impl Align {
+    pub fn from_name(name: &str) -> Option<Align> {
+        match name {
+            "left" => Some(Align::Left),
+            "center" => Some(Align::Center),
+            "right" => Some(Align::Right),
+            _ => None,
+        }
+    }
+}
+
+pub struct TextOptions {
+    pub size: f32,
+    pub color: Rgba<u8>,
+    pub align: Align,
+    // Wrap lines at word boundaries so that they fit in this many pixels
+    pub width: Option<u32>,
+    pub outline: u32,
+    pub outline_color: Rgba<u8>,
+    pub shadow: Option<(i64, i64)>,
+    pub shadow_color: Rgba<u8>,
+    pub anchor: Anchor,
+    pub offset: (i64, i64),
+    // Blend the text onto the image in linear light
+    pub linear: bool,
+}
+
+impl Default for TextOptions {
+    fn default() -> Self {
+        TextOptions {
+            size: 20.0,
+            color: Rgba([255, 255, 255, 255]),
+            align: Align::Left,
+            width: None,
+            outline: 0,
+            outline_color: Rgba([0, 0, 0, 255]),
+            shadow: None,
+            shadow_color: Rgba([0, 0, 0, 160]),
+            anchor: Anchor::TopLeft,
+            offset: (0, 0),
+            linear: false,
+        }
+    }
+}
+
+// Draw `text` onto a copy of `img`.
+pub fn draw_text(
+    img: &DynamicImage,
+    text: &str,
+    font: &Font,
+    options: &TextOptions,
+) -> DynamicImage {
+    let label = render_text(text, font, options);
+    let placement = CompositeOptions {
+        anchor: options.anchor,
+        offset: options.offset,
+        linear: options.linear,
+        ..CompositeOptions::default()
+    };
+    composite::composite(img, &DynamicImage::ImageRgba8(label), &placement)
+}
+
+// Render `text` as a label on a transparent background, just big enough to hold it along with its
+// outline and shadow.
+pub fn render_text(text: &str, font: &Font, options: &TextOptions) -> RgbaImage {
+    let font = font.sized(options.size);
+    let lines = wrap(text, &font, options.width);
+    let widest = lines
+        .iter()
+        .map(|line| font.width(line))
+        .fold(0.0, f32::max);
+    let block_width = options.width.map_or(widest, |w| w as f32).ceil();
+    let line_height = font.line_height();
+
+    let margin = options.outline as i64;
+    let (shadow_x, shadow_y) = options.shadow.unwrap_or((0, 0));
+    let width = block_width as i64 + 2 * margin + shadow_x.abs();
+    let height = (line_height * lines.len() as f32).ceil() as i64 + 2 * margin + shadow_y.abs();
+
+    // Where the text itself starts within the label, leaving room for a shadow going up or left.
+    let origin_x = (margin + (-shadow_x).max(0)) as f32;
+    let origin_y = (margin + (-shadow_y).max(0)) as f32;
+    let mut fill = Mask::new(width as u32, height as u32);
+    for (i, line) in lines.iter().enumerate() {
+        let free = block_width - font.width(line);
+        let x = match options.align {
+            Align::Left => 0.0,
+            Align::Center => (free / 2.0).round(),
+            Align::Right => free,
+        };
+        font.draw_line(
+            &mut fill,
+            line,
+            origin_x + x,
+            origin_y + i as f32 * line_height,
+        );
+    }
+    let outline = grow(&fill, options.outline);
+
+    let mut label = RgbaImage::new(width as u32, height as u32);
+    for (x, y, pixel) in label.enumerate_pixels_mut() {
+        let mut layers = Vec::with_capacity(3);
+        if options.shadow.is_some() {
+            let sx = x as i64 - shadow_x;
+            let sy = y as i64 - shadow_y;
+            if sx >= 0 && sy >= 0 && sx < width && sy < height {
+                layers.push((
+                    options.shadow_color,
+                    outline.get_pixel(sx as u32, sy as u32)[0],
+                ));
+            }
+        }
+        if options.outline > 0 {
+            layers.push((options.outline_color, outline.get_pixel(x, y)[0]));
+        }
+        layers.push((options.color, fill.get_pixel(x, y)[0]));
+        *pixel = flatten_layers(&layers);
+    }
+    label
+}
+
+// Break text into lines: at every '\n', and between words wherever a line would get wider than
+// `width`.  A single word that is too wide on its own gets a line to itself.
+fn wrap(text: &str, font: &SizedFont, width: Option<u32>) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let width = match width {
+            Some(width) => width as f32,
+            None => {
+                lines.push(paragraph.to_string());
+                continue;
+            }
+        };
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let candidate = if line.is_empty() {
+                word.to_string()
+            } else {
+                format!("{} {}", line, word)
+            };
+            if font.width(&candidate) <= width || line.is_empty() {
+                line = candidate;
+            } else {
+                lines.push(std::mem::replace(&mut line, word.to_string()));
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+// Grow the mask by `radius` pixels in every direction (a round dilation), for outlines.
+fn grow(mask: &Mask, radius: u32) -> Mask {
+    if radius == 0 {
+        return mask.clone();
+    }
+    let r = radius as i64;
+    let (w, h) = (mask.width() as i64, mask.height() as i64);
+    Mask::from_fn(mask.width(), mask.height(), |x, y| {
+        let mut best: f32 = 0.0;
+        for dy in -r..=r {
+            for dx in -r..=r {
+                if dx * dx + dy * dy > r * r {
+                    continue;
+                }
+                let (sx, sy) = (x as i64 + dx, y as i64 + dy);
+                if sx >= 0 && sy >= 0 && sx < w && sy < h {
+                    best = best.max(mask.get_pixel(sx as u32, sy as u32)[0]);
+                }
+            }
+        }
+        Luma([best])
+    })
+}
+
+// Paint (color, coverage) layers over each other, bottom first.
+fn flatten_layers(layers: &[(Rgba<u8>, f32)]) -> Rgba<u8> {
+    let mut color = [0.0f32; 3];
+    let mut alpha = 0.0f32;
+    for (layer, coverage) in layers {
+        let a = layer[3] as f32 / 255.0 * coverage;
+        let out_alpha = a + alpha * (1.0 - a);
+        if out_alpha > 0.0 {
+            for (c, value) in color.iter_mut().enumerate() {
+                let src = layer[c] as f32 / 255.0;
+                *value = (src * a + *value * alpha * (1.0 - a)) / out_alpha;
+            }
+        }
+        alpha = out_alpha;
+    }
+    Rgba([
+        (color[0] * 255.0).round() as u8,
+        (color[1] * 255.0).round() as u8,
+        (color[2] * 255.0).round() as u8,
+        (alpha * 255.0).round() as u8,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The leftmost and rightmost columns with any ink
+    fn ink_columns(label: &RgbaImage) -> (u32, u32) {
+        let columns: Vec<u32> = label
+            .enumerate_pixels()
+            .filter(|(_, _, p)| p[3] > 0)
+            .map(|(x, _, _)| x)
+            .collect();
+        (
+            *columns.iter().min().unwrap(),
+            *columns.iter().max().unwrap(),
+        )
+    }
+
+    #[test]
+    fn wraps_between_words() {
+        let font = Font::Bitmap.sized(20.0);
+        let width = font.width("one two") as u32;
+        assert_eq!(
+            wrap("one two three", &font, Some(width)),
+            vec!["one two", "three"]
+        );
+        // Newlines always break, and a word too long for the width gets its own line
+        assert_eq!(wrap("a\nb c", &font, None), vec!["a", "b c"]);
+        assert_eq!(
+            wrap("a enormous b", &font, Some(width)),
+            vec!["a", "enormous", "b"]
+        );
+    }
+
+    #[test]
+    fn label_size_follows_lines_outline_and_shadow() {
+        let font = Font::Bitmap;
+        let sized = font.sized(20.0);
+        let (line_width, line_height) = (sized.width("hi") as u32, sized.line_height() as u32);
+
+        let plain = render_text("hi\nhi", &font, &TextOptions::default());
+        assert_eq!(plain.dimensions(), (line_width, 2 * line_height));
+
+        let options = TextOptions {
+            outline: 3,
+            shadow: Some((4, -2)),
+            ..TextOptions::default()
+        };
+        let decorated = render_text("hi\nhi", &font, &options);
+        assert_eq!(
+            decorated.dimensions(),
+            (line_width + 6 + 4, 2 * line_height + 6 + 2)
+        );
+    }
+
+    #[test]
+    fn bitmap_text_draws_in_its_color() {
+        let options = TextOptions {
+            color: Rgba([255, 0, 0, 255]),
+            ..TextOptions::default()
+        };
+        let label = render_text("Mirage", &Font::Bitmap, &options);
+        let inked = label.pixels().filter(|p| p[3] > 0).count();
+        assert!(inked > 20, "{}", inked);
+        assert!(label
+            .pixels()
+            .all(|p| p[3] == 0 || *p == Rgba([255, 0, 0, 255])));
+
+        // Onto an image, at the same size
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(80, 30, Rgba([0, 0, 0, 255])));
+        let drawn = draw_text(&img, "Mirage", &Font::Bitmap, &options).into_rgba8();
+        assert_eq!(drawn.dimensions(), (80, 30));
+        assert!(drawn.pixels().any(|p| *p == Rgba([255, 0, 0, 255])));
+    }
+
+    #[test]
+    fn alignment_moves_short_lines() {
+        let ink = |align| {
+            let options = TextOptions {
+                align,
+                width: Some(200),
+                ..TextOptions::default()
+            };
+            ink_columns(&render_text("hi", &Font::Bitmap, &options))
+        };
+        let (left, center, right) = (ink(Align::Left), ink(Align::Center), ink(Align::Right));
+        assert!(left.0 < 10);
+        assert!(center.0 > 80 && center.1 < 120, "{:?}", center);
+        assert!(right.1 > 190);
+    }
+
+    #[test]
+    fn layers_paint_over_each_other() {
+        assert_eq!(flatten_layers(&[]), Rgba([0, 0, 0, 0]));
+        let black = (Rgba([0, 0, 0, 255]), 1.0);
+        let white = Rgba([255, 255, 255, 255]);
+        assert_eq!(flatten_layers(&[black, (white, 1.0)]), white);
+        assert_eq!(
+            flatten_layers(&[black, (white, 0.5)]),
+            Rgba([128, 128, 128, 255])
+        );
+        // Half-covered on nothing: the color stays, at half alpha
+        assert_eq!(flatten_layers(&[(white, 0.5)]), Rgba([255, 255, 255, 128]));
+    }
+
+    #[test]
+    fn outlines_grow_round() {
+        let mut mask = Mask::new(7, 7);
+        mask.put_pixel(3, 3, Luma([1.0]));
+        let grown = grow(&mask, 2);
+        let covered = grown.pixels().filter(|p| p[0] > 0.0).count();
+        // A disk of radius 2: rows of 1, 3, 5, 3 and 1 pixels
+        assert_eq!(covered, 13);
+        assert_eq!(grown.get_pixel(1, 1)[0], 0.0);
+    }
+}
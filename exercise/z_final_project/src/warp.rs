@@ -0,0 +1,504 @@
+// Geometric distortions.
+//
+// Every warp works backwards: for each pixel of the result, a transform gives the point of the
+// input it comes from, and the input is sampled there.  That way every output pixel gets exactly
+// one value, with no holes, whatever the transform does.  Points between pixels are filled in by
+// the chosen interpolation:
+// - nearest: the pixel the point falls in.  Blocky, but keeps exact colors (good for pixel art).
+// - bilinear: a weighted mean of the four nearest pixels.
+// - bicubic: Catmull-Rom over the sixteen nearest, which keeps more detail when enlarging.
+//
+// Transforms, in pixel coordinates with 0,0 at the top left corner of the image:
+// - affine A,B,C,D,E,F: moves each input point x,y to A*x + B*y + C, D*x + E*y + F.
+// - rotate DEGREES: clockwise about the center, with the canvas grown to fit the corners.
+// - perspective X,Y X,Y X,Y X,Y: stretches the four-sided area with these corners (top left, top
+//   right, bottom right, bottom left) into a rectangle, e.g. to straighten a photographed
+//   document.  The rectangle is as big as the area's longest sides, unless a size is given.
+// - swirl DEGREES: twists the middle round by up to DEGREES, less and less out to `radius`
+//   (half the smaller side by default).
+// - ripple AMPLITUDE WAVELENGTH: shifts rows sideways and columns up and down in sine waves.
+// - polar / unpolar: from a circle to a rectangle whose columns go round the center clockwise from
+//   the right, and whose rows go out from the center to the corners; and back again.
+//
+// Anything that comes from outside the input is transparent (black in images without alpha).
+
+use crate::parallel;
+use image::Rgba32FImage;
+use std::f64::consts::PI;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Nearest,
+    Bilinear,
+    Bicubic,
+}
+
+impl Interpolation {
+    pub const NAMES: &'static str = "nearest, bilinear, bicubic";
+
+    pub fn from_name(name: &str) -> Option<Interpolation> {
+        match name {
+            "nearest" => Some(Interpolation::Nearest),
+            "bilinear" => Some(Interpolation::Bilinear),
+            "bicubic" => Some(Interpolation::Bicubic),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Interpolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Interpolation::Nearest => "nearest",
+            Interpolation::Bilinear => "bilinear",
+            Interpolation::Bicubic => "bicubic",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transform {
+    Affine([f32; 6]),
+    Rotate(f32),
+    Perspective {
+        corners: [(f32, f32); 4],
+        // Of the result; 0,0 fits the corners
+        size: (u32, u32),
+    },
+    Swirl {
+        degrees: f32,
+        // 0 for half the smaller side
+        radius: f32,
+    },
+    Ripple {
+        amplitude: f32,
+        wavelength: f32,
+    },
+    Polar,
+    Unpolar,
+}
+
+impl Transform {
+    // Check what can't work whatever the image: matrices that squash everything onto a line, and
+    // corners that cross over.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Transform::Affine(m) if m[0] * m[4] - m[1] * m[3] == 0.0 => {
+                Err("The affine matrix flattens the image, so it can't be undone".to_string())
+            }
+            Transform::Perspective { corners, .. } if Homography::to_quad(corners).is_none() => {
+                Err("The perspective corners must make a four-sided shape".to_string())
+            }
+            Transform::Ripple { wavelength, .. } if *wavelength <= 0.0 => {
+                Err("The ripple wavelength must be more than 0".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn output_size(&self, (width, height): (u32, u32)) -> (u32, u32) {
+        match *self {
+            Transform::Rotate(degrees) => {
+                let (sin, cos) = (degrees as f64 * PI / 180.0).sin_cos();
+                let (w, h) = (width as f64, height as f64);
+                // Rounded first so that right angles don't gain a pixel from rounding errors
+                let fit = |v: f64| ((v * 1e6).round() / 1e6).ceil().max(1.0) as u32;
+                (
+                    fit(w * cos.abs() + h * sin.abs()),
+                    fit(w * sin.abs() + h * cos.abs()),
+                )
+            }
+            Transform::Perspective { corners, size } => {
+                let length = |a: usize, b: usize| {
+                    let (ax, ay) = corners[a];
+                    let (bx, by) = corners[b];
+                    ((ax - bx) as f64).hypot((ay - by) as f64)
+                };
+                let w = length(0, 1).max(length(3, 2)).round().max(1.0) as u32;
+                let h = length(0, 3).max(length(1, 2)).round().max(1.0) as u32;
+                match size {
+                    (0, 0) => (w, h),
+                    (0, sh) => (
+                        ((w as f64 * sh as f64 / h as f64).round() as u32).max(1),
+                        sh,
+                    ),
+                    (sw, 0) => (
+                        sw,
+                        ((h as f64 * sw as f64 / w as f64).round() as u32).max(1),
+                    ),
+                    size => size,
+                }
+            }
+            _ => (width, height),
+        }
+    }
+
+    // The function from points of the result to points of the input, for an input of this size.
+    fn source(&self, (width, height): (u32, u32)) -> Box<dyn Fn(f64, f64) -> (f64, f64) + Sync> {
+        let (w, h) = (width as f64, height as f64);
+        let (cx, cy) = (w / 2.0, h / 2.0);
+        let (out_w, out_h) = self.output_size((width, height));
+        match *self {
+            Transform::Affine(m) => {
+                let [a, b, c, d, e, f] = m.map(|v| v as f64);
+                let det = a * e - b * d;
+                Box::new(move |x, y| {
+                    let (x, y) = (x - c, y - f);
+                    ((e * x - b * y) / det, (a * y - d * x) / det)
+                })
+            }
+            Transform::Rotate(degrees) => {
+                let (sin, cos) = (degrees as f64 * PI / 180.0).sin_cos();
+                let (ox, oy) = (out_w as f64 / 2.0, out_h as f64 / 2.0);
+                Box::new(move |x, y| {
+                    let (x, y) = (x - ox, y - oy);
+                    (cx + x * cos + y * sin, cy - x * sin + y * cos)
+                })
+            }
+            Transform::Perspective { corners, .. } => {
+                let homography = Homography::to_quad(&corners).unwrap();
+                let (sx, sy) = (1.0 / out_w as f64, 1.0 / out_h as f64);
+                Box::new(move |x, y| homography.apply(x * sx, y * sy))
+            }
+            Transform::Swirl { degrees, radius } => {
+                let radius = if radius > 0.0 {
+                    radius as f64
+                } else {
+                    w.min(h) / 2.0
+                };
+                let most = degrees as f64 * PI / 180.0;
+                Box::new(move |x, y| {
+                    let (dx, dy) = (x - cx, y - cy);
+                    let distance = dx.hypot(dy);
+                    if distance >= radius {
+                        return (x, y);
+                    }
+                    let turn = most * (1.0 - distance / radius).powi(2);
+                    let (sin, cos) = turn.sin_cos();
+                    (cx + dx * cos - dy * sin, cy + dx * sin + dy * cos)
+                })
+            }
+            Transform::Ripple {
+                amplitude,
+                wavelength,
+            } => {
+                let (amplitude, k) = (amplitude as f64, 2.0 * PI / wavelength as f64);
+                Box::new(move |x, y| (x + amplitude * (y * k).sin(), y + amplitude * (x * k).sin()))
+            }
+            Transform::Polar => {
+                let reach = cx.hypot(cy);
+                Box::new(move |x, y| {
+                    let angle = x / w * 2.0 * PI;
+                    let distance = y / h * reach;
+                    (cx + distance * angle.cos(), cy + distance * angle.sin())
+                })
+            }
+            Transform::Unpolar => {
+                let reach = cx.hypot(cy);
+                Box::new(move |x, y| {
+                    let (dx, dy) = (x - cx, y - cy);
+                    let angle = dy.atan2(dx).rem_euclid(2.0 * PI);
+                    (angle / (2.0 * PI) * w, dx.hypot(dy) / reach * h)
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for Transform {
+    // The transform's words in a chain
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Transform::Affine(m) => write!(
+                f,
+                "affine {},{},{},{},{},{}",
+                m[0], m[1], m[2], m[3], m[4], m[5]
+            ),
+            Transform::Rotate(degrees) => write!(f, "rotate {}", degrees),
+            Transform::Perspective { corners, size } => {
+                write!(f, "perspective")?;
+                for (x, y) in corners {
+                    write!(f, " {},{}", x, y)?;
+                }
+                if size != (0, 0) {
+                    write!(f, " --size {}x{}", size.0, size.1)?;
+                }
+                Ok(())
+            }
+            Transform::Swirl { degrees, radius } if radius > 0.0 => {
+                write!(f, "swirl {} --radius {}", degrees, radius)
+            }
+            Transform::Swirl { degrees, .. } => write!(f, "swirl {}", degrees),
+            Transform::Ripple {
+                amplitude,
+                wavelength,
+            } => write!(f, "ripple {} {}", amplitude, wavelength),
+            Transform::Polar => write!(f, "polar"),
+            Transform::Unpolar => write!(f, "unpolar"),
+        }
+    }
+}
+
+// A projective map from the unit square onto a four-sided shape (Heckbert, 1989):
+// x = (a*u + b*v + c) / (g*u + h*v + 1), y = (d*u + e*v + f) / (g*u + h*v + 1).
+#[derive(Clone, Copy, Debug)]
+struct Homography([f64; 8]);
+
+impl Homography {
+    // Corners 0,0 1,0 1,1 0,1 go to `corners`, in that order.
+    fn to_quad(corners: &[(f32, f32); 4]) -> Option<Homography> {
+        let [(x0, y0), (x1, y1), (x2, y2), (x3, y3)] = corners.map(|(x, y)| (x as f64, y as f64));
+        let (sx, sy) = (x0 - x1 + x2 - x3, y0 - y1 + y2 - y3);
+        let (dx1, dx2, dy1, dy2) = (x1 - x2, x3 - x2, y1 - y2, y3 - y2);
+        let den = dx1 * dy2 - dx2 * dy1;
+        if den.abs() < 1e-9 {
+            return None;
+        }
+        let g = (sx * dy2 - dx2 * sy) / den;
+        let h = (dx1 * sy - sx * dy1) / den;
+        let m = [
+            x1 - x0 + g * x1,
+            x3 - x0 + h * x3,
+            x0,
+            y1 - y0 + g * y1,
+            y3 - y0 + h * y3,
+            y0,
+            g,
+            h,
+        ];
+        // The shape has to be convex, or points of the square end up behind the viewer.
+        let corners_ok = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]
+            .iter()
+            .all(|&(u, v)| g * u + h * v + 1.0 > 0.0);
+        let area = m[0] * m[4] - m[1] * m[3];
+        (corners_ok && area.abs() > 1e-9).then_some(Homography(m))
+    }
+
+    fn apply(&self, u: f64, v: f64) -> (f64, f64) {
+        let [a, b, c, d, e, f, g, h] = self.0;
+        let w = g * u + h * v + 1.0;
+        ((a * u + b * v + c) / w, (d * u + e * v + f) / w)
+    }
+}
+
+// Warp an image; takes premultiplied alpha, so that edges fade out rather than darken.
+pub fn warp(
+    img: &Rgba32FImage,
+    transform: &Transform,
+    interpolation: Interpolation,
+) -> Rgba32FImage {
+    let (out_w, out_h) = transform.output_size(img.dimensions());
+    let source = transform.source(img.dimensions());
+    // The columns of a polar image go all the way round, so the last one is next to the first.
+    let wrap = *transform == Transform::Unpolar;
+    let mut out = Rgba32FImage::new(out_w, out_h);
+    parallel::for_each_row(&mut out, out_w as usize * 4, |y, row| {
+        for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+            // From pixel centers to pixel centers
+            let (sx, sy) = source(x as f64 + 0.5, y as f64 + 0.5);
+            pixel.copy_from_slice(&sample(img, sx - 0.5, sy - 0.5, interpolation, wrap));
+        }
+    });
+    out
+}
+
+// The value at x, y, where whole numbers are pixel centers.  Pixels beyond the edges are
+// transparent, except that with `wrap` the left and right edges join up.
+fn sample(
+    img: &Rgba32FImage,
+    x: f64,
+    y: f64,
+    interpolation: Interpolation,
+    wrap: bool,
+) -> [f32; 4] {
+    let (width, height) = (img.width() as i64, img.height() as i64);
+    let pixel = |px: i64, py: i64| {
+        let px = if wrap { px.rem_euclid(width) } else { px };
+        if px < 0 || py < 0 || px >= width || py >= height {
+            [0.0; 4]
+        } else {
+            img.get_pixel(px as u32, py as u32).0
+        }
+    };
+    if !x.is_finite() || !y.is_finite() {
+        return [0.0; 4];
+    }
+    let (fx, fy) = (x.floor(), y.floor());
+    let (tx, ty) = ((x - fx) as f32, (y - fy) as f32);
+    let (ix, iy) = (fx as i64, fy as i64);
+    match interpolation {
+        Interpolation::Nearest => pixel(x.round() as i64, y.round() as i64),
+        Interpolation::Bilinear => {
+            let weights = [
+                (0, 0, (1.0 - tx) * (1.0 - ty)),
+                (1, 0, tx * (1.0 - ty)),
+                (0, 1, (1.0 - tx) * ty),
+                (1, 1, tx * ty),
+            ];
+            let mut sum = [0.0; 4];
+            for (dx, dy, weight) in weights {
+                let p = pixel(ix + dx, iy + dy);
+                for c in 0..4 {
+                    sum[c] += weight * p[c];
+                }
+            }
+            sum
+        }
+        Interpolation::Bicubic => {
+            let (wx, wy) = (catmull_rom(tx), catmull_rom(ty));
+            let mut sum = [0.0; 4];
+            for (j, wy) in wy.iter().enumerate() {
+                for (i, wx) in wx.iter().enumerate() {
+                    let p = pixel(ix + i as i64 - 1, iy + j as i64 - 1);
+                    for c in 0..4 {
+                        sum[c] += wx * wy * p[c];
+                    }
+                }
+            }
+            // Catmull-Rom overshoots a little at sharp edges, and premultiplied colors can't be
+            // brighter than their alpha.  Alpha past 1 (next to transparency) is scaled back
+            // along with the colors, which keeps their hue and brightness.
+            let scale = if sum[3] > 1.0 { 1.0 / sum[3] } else { 1.0 };
+            let alpha = (sum[3] * scale).max(0.0);
+            [
+                (sum[0] * scale).clamp(0.0, alpha),
+                (sum[1] * scale).clamp(0.0, alpha),
+                (sum[2] * scale).clamp(0.0, alpha),
+                alpha,
+            ]
+        }
+    }
+}
+
+// The weights of the four pixels around a point `t` of the way from the second to the third.
+fn catmull_rom(t: f32) -> [f32; 4] {
+    let (t2, t3) = (t * t, t * t * t);
+    [
+        (-t3 + 2.0 * t2 - t) / 2.0,
+        (3.0 * t3 - 5.0 * t2 + 2.0) / 2.0,
+        (-3.0 * t3 + 4.0 * t2 + t) / 2.0,
+        (t3 - t2) / 2.0,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn pattern(width: u32, height: u32) -> Rgba32FImage {
+        Rgba32FImage::from_fn(width, height, |x, y| {
+            Rgba([x as f32 / width as f32, y as f32 / height as f32, 0.5, 1.0])
+        })
+    }
+
+    fn largest_difference(a: &Rgba32FImage, b: &Rgba32FImage) -> f32 {
+        assert_eq!(a.dimensions(), b.dimensions());
+        a.as_raw()
+            .iter()
+            .zip(b.as_raw())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn quarter_turns() {
+        let img = pattern(5, 3);
+        for interpolation in [
+            Interpolation::Nearest,
+            Interpolation::Bilinear,
+            Interpolation::Bicubic,
+        ] {
+            let turned = warp(&img, &Transform::Rotate(90.0), interpolation);
+            assert_eq!(turned.dimensions(), (3, 5));
+            // Clockwise: the top left corner ends up top right
+            let corner = turned.get_pixel(2, 0);
+            assert!(
+                largest_difference(
+                    &Rgba32FImage::from_pixel(1, 1, *corner),
+                    &Rgba32FImage::from_pixel(1, 1, *img.get_pixel(0, 0))
+                ) < 1e-5
+            );
+
+            let around = (0..4).fold(img.clone(), |img, _| {
+                warp(&img, &Transform::Rotate(90.0), interpolation)
+            });
+            assert!(
+                largest_difference(&around, &img) < 1e-5,
+                "{}",
+                interpolation
+            );
+        }
+    }
+
+    #[test]
+    fn rotation_grows_the_canvas() {
+        assert_eq!(Transform::Rotate(180.0).output_size((5, 3)), (5, 3));
+        assert_eq!(Transform::Rotate(45.0).output_size((10, 10)), (15, 15));
+        // The corners come from outside the image, so they're transparent
+        let turned = warp(
+            &pattern(10, 10),
+            &Transform::Rotate(45.0),
+            Interpolation::Bilinear,
+        );
+        assert_eq!(turned.get_pixel(0, 0)[3], 0.0);
+        assert_eq!(turned.get_pixel(7, 7)[3], 1.0);
+    }
+
+    #[test]
+    fn polar_and_back() {
+        // Smooth, so that resampling twice doesn't lose much
+        let img = Rgba32FImage::from_fn(32, 32, |x, y| {
+            let (dx, dy) = (x as f32 - 15.5, y as f32 - 15.5);
+            Rgba([0.5 + dx / 40.0, 0.5 + dy / 40.0, 0.5, 1.0])
+        });
+        for interpolation in [Interpolation::Bilinear, Interpolation::Bicubic] {
+            let polar = warp(&img, &Transform::Polar, interpolation);
+            let back = warp(&polar, &Transform::Unpolar, interpolation);
+            assert_eq!(back.dimensions(), img.dimensions());
+            // Compare inside the inscribed circle, away from the corners the polar image doesn't
+            // keep.  That includes both sides of the seam where the polar image's ends meet.
+            let mut largest = 0.0f32;
+            for (x, y, pixel) in back.enumerate_pixels() {
+                let (dx, dy) = (x as f32 - 15.5, y as f32 - 15.5);
+                if (dx * dx + dy * dy).sqrt() < 14.0 {
+                    let original = img.get_pixel(x, y);
+                    for c in 0..4 {
+                        largest = largest.max((pixel[c] - original[c]).abs());
+                    }
+                }
+            }
+            assert!(largest < 0.002, "{}: {}", interpolation, largest);
+        }
+    }
+
+    #[test]
+    fn impossible_transforms_are_rejected() {
+        assert!(Transform::Affine([1.0, 2.0, 0.0, 2.0, 4.0, 0.0])
+            .validate()
+            .is_err());
+        assert!(Transform::Affine([1.0, 0.0, 5.0, 0.0, 1.0, 5.0])
+            .validate()
+            .is_ok());
+        let crossed = Transform::Perspective {
+            corners: [(0.0, 0.0), (10.0, 10.0), (10.0, 0.0), (0.0, 10.0)],
+            size: (0, 0),
+        };
+        assert!(crossed.validate().is_err());
+        let ripple = Transform::Ripple {
+            amplitude: 3.0,
+            wavelength: 0.0,
+        };
+        assert!(ripple.validate().is_err());
+    }
+
+    #[test]
+    fn interpolation_names() {
+        for name in ["nearest", "bilinear", "bicubic"] {
+            assert_eq!(Interpolation::from_name(name).unwrap().to_string(), name);
+        }
+        assert_eq!(Interpolation::from_name("lanczos"), None);
+    }
+}
@@ -0,0 +1,224 @@
+// Re-running a recipe whenever its inputs or the recipe itself change.
+//
+// Everything is processed once at the start.  After that, a changed input image is processed again
+// on its own, and a changed recipe reprocesses every input.  Editors and exporters often write a
+// file in several steps (or write a temporary file and rename it), so changes are collected until
+// nothing has happened for the debounce time, and each file is processed once per burst.
+//
+// The directories holding the inputs are watched rather than the files themselves, which keeps
+// working when a file is replaced by a rename.  Directory inputs cover the images directly in them,
+// including ones added later.  Changes in the output directory are ignored, so an input in there is
+// processed once at the start (overwriting it, unless --format changes the extension) and then left
+// alone.
+
+use crate::{codec, ops, Settings};
+use image::ImageFormat;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+struct Job<'a> {
+    files: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+    recipe: PathBuf,
+    outdir: PathBuf,
+    operations: Vec<ops::Operation>,
+    settings: &'a Settings,
+}
+
+pub fn run(
+    inputs: &[String],
+    recipe: &str,
+    outdir: &str,
+    debounce: Duration,
+    settings: &Settings,
+) -> Result<(), String> {
+    let absolute = |path: &str| std::fs::canonicalize(path).map_err(|e| format!("{}: {}", path, e));
+    std::fs::create_dir_all(outdir).map_err(|e| format!("{}: {}", outdir, e))?;
+    let mut job = Job {
+        files: Vec::new(),
+        dirs: Vec::new(),
+        recipe: absolute(recipe)?,
+        outdir: absolute(outdir)?,
+        operations: Vec::new(),
+        settings,
+    };
+    for input in inputs {
+        let path = absolute(input)?;
+        if path.is_dir() {
+            job.dirs.push(path);
+        } else {
+            job.files.push(path);
+        }
+    }
+    job.operations = read_recipe(&job.recipe)?;
+
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(|e| e.to_string())?;
+    let mut watched = BTreeSet::new();
+    for file in job.files.iter().chain([&job.recipe]) {
+        watched.insert(file.parent().unwrap().to_path_buf());
+    }
+    watched.extend(job.dirs.iter().cloned());
+    for dir in &watched {
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+
+    for input in job.all_inputs() {
+        job.process(&input);
+    }
+    println!("Watching for changes (Ctrl-C to stop)");
+
+    loop {
+        // Wait for something to happen, then for things to calm down.
+        let mut changed = BTreeSet::new();
+        let mut event = events.recv().map_err(|e| e.to_string())?;
+        loop {
+            match event {
+                Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                    changed.extend(event.paths)
+                }
+                Ok(_) => {}
+                Err(e) => println!("Watch error: {}", e),
+            }
+            event = match events.recv_timeout(debounce) {
+                Ok(event) => event,
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(e) => return Err(e.to_string()),
+            };
+        }
+
+        if changed.contains(&job.recipe) {
+            match read_recipe(&job.recipe) {
+                Ok(operations) => {
+                    println!("Recipe changed");
+                    job.operations = operations;
+                    for input in job.all_inputs() {
+                        job.process(&input);
+                    }
+                }
+                // Keep the old recipe until the new one makes sense.
+                Err(e) => println!("{}", e),
+            }
+            continue;
+        }
+        for path in changed {
+            if job.is_input(&path) && path.is_file() {
+                job.process(&path);
+            }
+        }
+    }
+}
+
+fn read_recipe(path: &Path) -> Result<Vec<ops::Operation>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    ops::parse_recipe(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+impl Job<'_> {
+    // The input files, and the images currently in the input directories.
+    fn all_inputs(&self) -> Vec<PathBuf> {
+        let mut inputs = self.files.clone();
+        for dir in &self.dirs {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            let mut images: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file() && self.is_input(path))
+                .collect();
+            images.sort();
+            inputs.extend(images);
+        }
+        inputs
+    }
+
+    fn is_input(&self, path: &Path) -> bool {
+        // Leave out our own output, whatever is being watched: writing it would set off another
+        // round, and another after that.
+        let parent = path.parent().unwrap_or(Path::new(""));
+        if parent == self.outdir {
+            return false;
+        }
+        self.files.iter().any(|file| file == path)
+            || (self.dirs.iter().any(|dir| dir == parent) && ImageFormat::from_path(path).is_ok())
+    }
+
+    // OUTDIR/NAME, with the extension changed to match --format if it's given.
+    fn output(&self, input: &Path) -> PathBuf {
+        let out = self.outdir.join(input.file_name().unwrap());
+        match self.settings.save.format {
+            Some(format) => out.with_extension(format.extensions_str()[0]),
+            None => out,
+        }
+    }
+
+    // Run the recipe on one input.  Failures are reported and skipped: the file may be half
+    // written, and will come round again when it's finished.
+    fn process(&self, input: &Path) {
+        let start = Instant::now();
+        let output = self.output(input);
+        let result = codec::open(input).and_then(|mut img| {
+            for operation in &self.operations {
+                img = operation.apply(&img, self.settings.linear);
+            }
+            codec::save(&img, &output.to_string_lossy(), &self.settings.save)
+        });
+        match result {
+            Ok(()) => println!(
+                "{} -> {} ({} ms)",
+                input.display(),
+                output.display(),
+                start.elapsed().as_millis()
+            ),
+            Err(e) => println!("{}: {}", input.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job<'a>(files: &[&str], dirs: &[&str], outdir: &str, settings: &'a Settings) -> Job<'a> {
+        Job {
+            files: files.iter().map(PathBuf::from).collect(),
+            dirs: dirs.iter().map(PathBuf::from).collect(),
+            recipe: PathBuf::from("/photos/recipe.txt"),
+            outdir: PathBuf::from(outdir),
+            operations: Vec::new(),
+            settings,
+        }
+    }
+
+    #[test]
+    fn output_never_counts_as_input() {
+        let settings = Settings {
+            save: codec::SaveOptions::default(),
+            linear: false,
+            tiled: None,
+            preview: None,
+        };
+        let path = Path::new;
+
+        // A single file, written next to itself
+        let single = job(&["/photos/a.png"], &[], "/photos", &settings);
+        assert!(!single.is_input(path("/photos/a.png")));
+        let single = job(&["/photos/a.png"], &[], "/out", &settings);
+        assert!(single.is_input(path("/photos/a.png")));
+        assert!(!single.is_input(path("/photos/b.png")));
+        assert!(!single.is_input(path("/out/a.png")));
+
+        // A directory, with the output inside it or the same
+        let dir = job(&[], &["/photos"], "/photos", &settings);
+        assert!(!dir.is_input(path("/photos/a.png")));
+        let dir = job(&[], &["/photos"], "/photos/out", &settings);
+        assert!(dir.is_input(path("/photos/a.png")));
+        assert!(!dir.is_input(path("/photos/notes.txt")));
+        assert!(!dir.is_input(path("/photos/out/a.png")));
+    }
+}